@@ -0,0 +1,233 @@
+//! The actions berth performs on environments, shared by every container engine
+
+use crate::{
+    cli::{Action, OutputFormat},
+    configuration::{Configuration, Environment},
+    docker::{
+        self, ContainerEngine, ContainerStatus, DockerHandler, Drift, EnvironmentStatus,
+        GarbageCollector,
+    },
+    util::{format_age, format_table, to_json, Spinner},
+};
+use miette::Result;
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+/// Creates the environment's container, removing it again if setting it up
+/// fails so a half set up container is never reused
+async fn create(docker: &DockerHandler) -> Result<()> {
+    let result = docker.create_new_environment().await;
+    if result.is_err() {
+        docker.discard_container().await?;
+    }
+    result
+}
+
+pub async fn build(docker: &DockerHandler) -> Result<()> {
+    docker.migrate_legacy_container().await?;
+    create(docker).await?;
+    docker.stop_container_if_running().await?;
+
+    Ok(())
+}
+
+pub fn format_diff(container: &str, diff: &Option<Vec<String>>) -> String {
+    match diff {
+        Some(lines) if lines.is_empty() => format!(
+            "Container {container} was created from a different configuration, \
+            but none of the fields shown by `berth view` differ. \
+            The dockerfile's contents may have changed."
+        ),
+        Some(lines) => format!(
+            "Container {container} was created from a different configuration:\n{}",
+            lines.join("\n")
+        ),
+        None => format!(
+            "Container {container} was created from a different configuration, \
+            but was created before berth recorded definitions so can't be compared"
+        ),
+    }
+}
+
+pub async fn diff(docker: &DockerHandler) -> Result<()> {
+    match docker.drift().await? {
+        Drift::Absent => println!("No container has been created for this environment"),
+        Drift::UpToDate { container } => {
+            println!("Container {container} matches the current configuration")
+        }
+        Drift::Changed { container, diff } => println!("{}", format_diff(&container, &diff)),
+    }
+
+    Ok(())
+}
+
+pub async fn up(docker: &DockerHandler) -> Result<()> {
+    docker.migrate_legacy_container().await?;
+    if !docker.does_environment_exist().await? {
+        if let Drift::Changed { container, diff } = docker.drift().await? {
+            eprintln!("{}", format_diff(&container, &diff));
+            eprintln!("Creating a new container, run `berth gc` to remove the old one");
+        }
+        create(docker).await?;
+    } else {
+        let spinner = Spinner::new("Starting Container");
+
+        docker.start_container().await?;
+
+        spinner.finish_and_clear();
+    }
+    docker.enter_environment().await?;
+
+    Ok(())
+}
+
+pub async fn rm(docker: &DockerHandler, image: bool) -> Result<()> {
+    // Removed even if an `on_stop` hook fails, which is reported afterwards
+    let stopped = docker.stop_container_if_running().await;
+    docker.delete_container_if_exists().await?;
+
+    if image {
+        docker.delete_image_if_built().await?;
+    }
+
+    stopped
+}
+
+fn status_table(statuses: &[EnvironmentStatus]) -> String {
+    let mut rows = vec![vec![
+        "NAME".to_string(),
+        "IMAGE".to_string(),
+        "STATUS".to_string(),
+        "CREATED".to_string(),
+    ]];
+
+    for status in statuses {
+        rows.push(vec![
+            status.environment.clone(),
+            status.image.clone(),
+            status.status.to_string(),
+            status.created.map(format_age).unwrap_or("-".to_string()),
+        ]);
+    }
+
+    format_table(&rows)
+}
+
+pub async fn list(
+    environments: Vec<Environment>,
+    engine: Arc<dyn ContainerEngine>,
+    format: OutputFormat,
+) -> Result<()> {
+    let mut statuses = Vec::new();
+
+    for environment in environments {
+        let docker = DockerHandler::new(environment, engine.clone())?;
+        statuses.push(docker.status().await?);
+    }
+
+    match format {
+        OutputFormat::Text => print!("{}", status_table(&statuses)),
+        OutputFormat::Json => println!("{}", to_json(&statuses)?),
+    }
+
+    Ok(())
+}
+
+pub async fn status(docker: &DockerHandler, format: OutputFormat) -> Result<()> {
+    let status = docker.status().await?;
+
+    match format {
+        OutputFormat::Text => {
+            print!("{}", status_table(std::slice::from_ref(&status)));
+            if matches!(
+                status.status,
+                ContainerStatus::Running | ContainerStatus::Stopped
+            ) {
+                println!("\nContainer: {}", status.container);
+            }
+        }
+        OutputFormat::Json => println!("{}", to_json(&status)?),
+    }
+
+    Ok(())
+}
+
+pub async fn gc(
+    environments: &[Environment],
+    config_paths: &[PathBuf],
+    engine: Arc<dyn ContainerEngine>,
+    dry_run: bool,
+    all: bool,
+    older_than: Option<Duration>,
+) -> Result<()> {
+    let collector = GarbageCollector::new(config_paths, engine)?;
+    let garbage = collector.find(environments, all, older_than).await?;
+
+    if garbage.is_empty() {
+        println!("Nothing to remove");
+        return Ok(());
+    }
+
+    if !dry_run {
+        let spinner = Spinner::new("Removing Containers and Images");
+        collector.remove(&garbage).await?;
+        spinner.finish_and_clear();
+    }
+
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    for container in &garbage.containers {
+        println!("{verb} container {}", container.name);
+    }
+    for image in &garbage.images {
+        println!("{verb} image {}", image.name);
+    }
+
+    Ok(())
+}
+
+/// Validates every environment in the config files without contacting the
+/// container engine, reporting every problem found at once
+pub fn check(configuration: &mut Configuration) -> Result<()> {
+    let (environments, mut problems) = configuration.check_all_environments()?;
+
+    for environment in &environments {
+        problems.record(docker::check_options(environment).map_err(|error| {
+            error.wrap_err(format!(
+                "Environment '{}' has an invalid option",
+                environment.original_name
+            ))
+        }));
+    }
+
+    problems.finish(())?;
+
+    let count = environments.len();
+    let plural = if count == 1 { "" } else { "s" };
+    println!("No problems found in {count} environment{plural}");
+    Ok(())
+}
+
+/// Runs an action on a single environment. Only a container that fails to be
+/// created is removed, errors from an existing container leave it in place
+pub async fn run(docker: &DockerHandler, action: &Action, cleanup: bool) -> Result<()> {
+    match action {
+        Action::Up => up(docker).await?,
+        Action::Build => build(docker).await?,
+        Action::Stop => return docker.stop_container_if_running().await,
+        Action::Diff => return diff(docker).await,
+        Action::Status { format } => return status(docker, *format).await,
+        Action::Rm { image } => return rm(docker, *image).await,
+        Action::View { .. }
+        | Action::List { .. }
+        | Action::Gc { .. }
+        | Action::Check
+        | Action::Schema => {
+            unreachable!("Not an action on a single environment")
+        }
+    }
+
+    if cleanup {
+        docker.delete_container_if_exists().await?;
+    }
+
+    Ok(())
+}
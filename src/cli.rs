@@ -1,8 +1,11 @@
-use clap::Parser;
+use crate::docker::Engine;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use miette::{Diagnostic, Result};
 use std::{
     ffi::OsString,
+    fs,
     path::{Path, PathBuf},
+    time::Duration,
 };
 use thiserror::Error;
 
@@ -14,49 +17,209 @@ pub enum CliError {
     #[error("Could not find file at 'config-path': {0:?}")]
     NoConfigAtProvidedPath(OsString),
 
-    #[error("Could not find config file in $XDG_CONFIG_HOME or $HOME")]
+    #[error("Could not find a .berth.toml or berth.toml in the current directory or its parents, or a config file in $XDG_CONFIG_HOME or $HOME")]
     NoConfigInStandardLocation,
 }
 
+/// Names of the project config files searched for in the current directory
+/// and its parents, in order of preference
+const PROJECT_CONFIG_NAMES: [&str; 2] = [".berth.toml", "berth.toml"];
+
 #[derive(Parser, Debug)]
 #[command(
     about = "berth, A CLI to help create development environments without touching repository code",
-    trailing_var_arg = false
+    trailing_var_arg = false,
+    subcommand_negates_reqs = true,
+    override_usage = "berth [OPTIONS] <ENVIRONMENT>\n       berth [OPTIONS] <COMMAND>"
 )]
 struct Cli {
     /// Path to config file
-    #[arg(long, value_name = "FILE")]
+    #[arg(long, value_name = "FILE", global = true)]
     pub config_path: Option<PathBuf>,
 
+    /// Container engine to use, overriding the engine set in the config file
+    #[arg(long, value_enum, global = true)]
+    pub engine: Option<Engine>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Deletes container on exit
     #[arg(long, default_value_t = false)]
     pub cleanup: bool,
 
-    /// Build/rebuild the environment instead of starting it
-    #[arg(long, default_value_t = false, group = "action")]
-    pub build: bool,
+    /// The environment to be used, shorthand for `berth up <ENVIRONMENT>`
+    #[arg(required = true)]
+    pub environment: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Start and enter an environment, building it first if required
+    Up {
+        /// Deletes container on exit
+        #[arg(long, default_value_t = false)]
+        cleanup: bool,
+
+        /// The environment to be used
+        environment: String,
+    },
+
+    /// Build/rebuild an environment without entering it
+    Build {
+        /// The environment to be used
+        environment: String,
+    },
 
     /// View environment definition after it has been parsed by berth
-    #[arg(long, default_value_t = false, group = "action")]
-    pub view: bool,
+    View {
+        /// Show which environment or preset each field and list element came from
+        #[arg(long, default_value_t = false, conflicts_with_all = ["resolved", "format"])]
+        explain: bool,
 
-    /// The environment to be used
-    pub environment: String,
+        /// Include the values berth computes, such as the container and image names
+        #[arg(long, default_value_t = false)]
+        resolved: bool,
+
+        /// Format to print the environment in, JSON always includes the computed values
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+
+        /// The environment to be used
+        environment: String,
+    },
+
+    /// Show what has changed in an environment's configuration since its container was created
+    Diff {
+        /// The environment to be used
+        environment: String,
+    },
+
+    /// List the environments defined in the config file and the state of their containers
+    List {
+        /// Format to print the environments in
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+
+    /// Show the state of an environment's container
+    Status {
+        /// Format to print the status in
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+
+        /// The environment to be used
+        environment: String,
+    },
+
+    /// Stop an environment's container if it is running
+    Stop {
+        /// The environment to be used
+        environment: String,
+    },
+
+    /// Remove an environment's container
+    Rm {
+        /// Also remove the image built from the environment's dockerfile
+        #[arg(long, default_value_t = false)]
+        image: bool,
+
+        /// The environment to be used
+        environment: String,
+    },
+
+    /// Remove containers and images left behind by changed or deleted environments
+    #[command(visible_alias = "prune")]
+    Gc {
+        /// Show what would be removed without removing anything
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+
+        /// Also remove the containers and images of the current environments
+        #[arg(long, default_value_t = false)]
+        all: bool,
+
+        /// Only remove containers and images created more than AGE ago, e.g. 30m, 12h, 7d or 2w
+        #[arg(long, value_name = "AGE", value_parser = parse_age)]
+        older_than: Option<Duration>,
+    },
+
+    /// Check every environment in the config files for problems without creating anything
+    Check,
+
+    /// Print a JSON Schema of the config file format for editors to use
+    Schema,
 }
 
-#[derive(Clone)]
+fn parse_age(age: &str) -> Result<Duration, String> {
+    let error = || format!("'{age}' is not an age such as 30s, 30m, 12h, 7d or 2w");
+
+    let split = age.find(|c: char| !c.is_ascii_digit()).ok_or_else(error)?;
+    let (amount, unit) = age.split_at(split);
+    let amount: u64 = amount.parse().map_err(|_| error())?;
+
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => return Err(error()),
+    };
+
+    amount
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(error)
+}
+
+/// How commands that report on environments print their output
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable text
+    #[default]
+    Text,
+    /// JSON, for scripts and editors
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Action {
     Up,
     Build,
-    View,
+    View {
+        explain: bool,
+        resolved: bool,
+        format: OutputFormat,
+    },
+    Diff,
+    List {
+        format: OutputFormat,
+    },
+    Status {
+        format: OutputFormat,
+    },
+    Stop,
+    Rm {
+        image: bool,
+    },
+    Gc {
+        dry_run: bool,
+        all: bool,
+        older_than: Option<Duration>,
+    },
+    Check,
+    Schema,
 }
 
 #[derive(Clone)]
 pub struct AppConfig {
-    pub config_path: PathBuf,
+    /// Config files to read environments from, from lowest to highest priority
+    pub config_paths: Vec<PathBuf>,
     pub action: Action,
     pub cleanup: bool,
     pub environment: String,
+    pub engine: Option<Engine>,
 }
 
 impl AppConfig {
@@ -79,37 +242,117 @@ impl AppConfig {
             }
         };
 
-        let action = match (cli.view, cli.build) {
-            (true, false) => Action::View,
-            (false, true) => Action::Build,
-            (false, false) => Action::Up,
-            (true, true) => panic!("Parsing should catch this"),
+        if cli.command.is_some() && (cli.environment.is_some() || cli.cleanup) {
+            let error = Cli::command().error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "'<ENVIRONMENT>' and '--cleanup' cannot be used with a subcommand",
+            );
+            return Err(CliError::BadInput(error.to_string()).into());
+        }
+
+        let (action, cleanup, environment) = match cli.command {
+            Some(Command::Up {
+                cleanup,
+                environment,
+            }) => (Action::Up, cleanup, environment),
+            Some(Command::Build { environment }) => (Action::Build, false, environment),
+            Some(Command::View {
+                explain,
+                resolved,
+                format,
+                environment,
+            }) => (
+                Action::View {
+                    explain,
+                    resolved,
+                    format,
+                },
+                false,
+                environment,
+            ),
+            Some(Command::Diff { environment }) => (Action::Diff, false, environment),
+            Some(Command::List { format }) => (Action::List { format }, false, String::new()),
+            Some(Command::Status {
+                format,
+                environment,
+            }) => (Action::Status { format }, false, environment),
+            Some(Command::Stop { environment }) => (Action::Stop, false, environment),
+            Some(Command::Rm { image, environment }) => (Action::Rm { image }, false, environment),
+            Some(Command::Gc {
+                dry_run,
+                all,
+                older_than,
+            }) => (
+                Action::Gc {
+                    dry_run,
+                    all,
+                    older_than,
+                },
+                false,
+                String::new(),
+            ),
+            Some(Command::Check) => (Action::Check, false, String::new()),
+            Some(Command::Schema) => (Action::Schema, false, String::new()),
+            None => (
+                Action::Up,
+                cli.cleanup,
+                cli.environment
+                    .expect("Parsing should require an environment"),
+            ),
+        };
+
+        // The schema doesn't depend on any config file
+        let config_paths = match action {
+            Action::Schema => Vec::new(),
+            _ => Self::set_config_paths(cli.config_path)?,
         };
 
         Ok(AppConfig {
-            config_path: Self::set_config_path(cli.config_path)?,
+            config_paths,
             action,
-            cleanup: cli.cleanup,
-            environment: cli.environment,
+            cleanup,
+            environment,
+            engine: cli.engine,
         })
     }
 
-    fn set_config_path(config_path: Option<PathBuf>) -> Result<PathBuf> {
+    /// The provided config file, otherwise the global config file with the
+    /// project's config file layered on top of it
+    fn set_config_paths(config_path: Option<PathBuf>) -> Result<Vec<PathBuf>> {
         if let Some(path) = config_path {
             return if path.exists() && path.is_file() {
-                Ok(path)
+                Ok(vec![path])
             } else {
                 Err(CliError::NoConfigAtProvidedPath(path.as_os_str().into()).into())
             };
         }
 
+        let mut paths: Vec<PathBuf> = Self::global_config_path().into_iter().collect();
+
+        if let Some(project_path) = Self::project_config_path() {
+            let is_global = paths.first().is_some_and(|global| {
+                fs::canonicalize(global).ok() == fs::canonicalize(&project_path).ok()
+            });
+            if !is_global {
+                paths.push(project_path);
+            }
+        }
+
+        if paths.is_empty() {
+            return Err(CliError::NoConfigInStandardLocation.into());
+        }
+
+        Ok(paths)
+    }
+
+    fn global_config_path() -> Option<PathBuf> {
         if let Ok(xdg_config) = std::env::var("XDG_CONFIG_HOME") {
             let xdg_path = Path::new(&xdg_config)
                 .join(".config")
                 .join("berth")
                 .join("config.toml");
             if xdg_path.exists() {
-                return Ok(xdg_path);
+                return Some(xdg_path);
             }
         }
 
@@ -119,10 +362,32 @@ impl AppConfig {
                 .join("berth")
                 .join("config.toml");
             if home_path.exists() {
-                return Ok(home_path);
+                return Some(home_path);
+            }
+        }
+
+        None
+    }
+
+    /// Searches the current directory and its parents for a project config
+    /// file, stopping at the root of the git repository if in one
+    fn project_config_path() -> Option<PathBuf> {
+        let current_dir = std::env::current_dir().ok()?;
+
+        for dir in current_dir.ancestors() {
+            let found = PROJECT_CONFIG_NAMES
+                .iter()
+                .map(|name| dir.join(name))
+                .find(|path| path.is_file());
+            if found.is_some() {
+                return found;
+            }
+
+            if dir.join(".git").exists() {
+                break;
             }
         }
 
-        Err(CliError::NoConfigInStandardLocation.into())
+        None
     }
 }
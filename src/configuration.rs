@@ -1,9 +1,15 @@
 use envmnt::{ExpandOptions, ExpansionType};
-use miette::{Diagnostic, LabeledSpan, NamedSource, Result, SourceSpan};
-use serde::Deserialize;
+use miette::{Diagnostic, LabeledSpan, NamedSource, Report, Result, SourceSpan};
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use serde::{
+    de::{self, value::SeqAccessDeserializer, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
 use sha2::{Digest, Sha256};
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt,
     fs::{self, File},
     hash::{DefaultHasher, Hash, Hasher},
     io::Read,
@@ -12,7 +18,11 @@ use std::{
 };
 use thiserror::Error;
 
-use crate::{cli::AppConfig, util::UnexpectedExt};
+use crate::{
+    cli::AppConfig,
+    docker::{Engine, COPY_CONTAINER_PREFIX},
+    util::{to_json, UnexpectedExt},
+};
 
 #[derive(Debug, Error, PartialEq, Diagnostic)]
 pub enum ConfigError {
@@ -66,6 +76,86 @@ pub enum ConfigError {
         span: SourceSpan,
     },
 
+    #[error("Unknown Environment")]
+    #[diagnostic(code(configuration::environment::extends))]
+    UnknownExtends {
+        msg: String,
+        #[source_code]
+        input: NamedSource<String>,
+        #[label("{msg}")]
+        span: SourceSpan,
+    },
+
+    #[error("Circular Extends")]
+    #[diagnostic(code(configuration::environment::cycle))]
+    CircularExtends {
+        msg: String,
+        #[source_code]
+        input: NamedSource<String>,
+        #[label("{msg}")]
+        span: SourceSpan,
+    },
+
+    #[error("Removed Values Not Inherited")]
+    #[diagnostic(
+        code(configuration::list::remove),
+        help("'remove' can only remove values inherited from presets or an extended environment")
+    )]
+    UnmatchedRemoval {
+        #[source_code]
+        input: NamedSource<String>,
+        #[label(collection)]
+        spans: Vec<LabeledSpan>,
+    },
+
+    #[error("Circular Preset")]
+    #[diagnostic(code(configuration::preset::cycle))]
+    CircularPreset {
+        msg: String,
+        #[source_code]
+        input: NamedSource<String>,
+        #[label("{msg}")]
+        span: SourceSpan,
+    },
+
+    #[error("Malformed Command")]
+    #[diagnostic(
+        code(configuration::environment::command),
+        help("Commands and options are split into words like a shell, so quotes must be closed")
+    )]
+    InvalidCommand {
+        msg: String,
+        #[source_code]
+        input: NamedSource<String>,
+        #[label("{msg}")]
+        span: SourceSpan,
+    },
+
+    #[error("Invalid Mount")]
+    #[diagnostic(code(configuration::environment::mount))]
+    InvalidMount {
+        msg: String,
+        #[source_code]
+        input: NamedSource<String>,
+        #[label("{msg}")]
+        span: SourceSpan,
+    },
+
+    #[error("Duplicate Mount Target")]
+    #[diagnostic(
+        code(configuration::mount::duplication),
+        help("Each mount of an environment and its presets needs a different target")
+    )]
+    DuplicateMountTarget {
+        #[source_code]
+        input: NamedSource<String>,
+        #[label(collection)]
+        spans: Vec<LabeledSpan>,
+        /// The duplicates found in other config files
+        #[related]
+        others: Vec<ConfigError>,
+    },
+
     #[error("Duplicate Fields From Presets")]
     #[diagnostic(code(configuration::preset::duplication))]
     DuplicateFieldsFromPresets {
@@ -73,109 +163,645 @@ pub enum ConfigError {
         input: NamedSource<String>,
         #[label(collection)]
         spans: Vec<LabeledSpan>,
+        /// The duplicates found in other config files
+        #[related]
+        others: Vec<ConfigError>,
+    },
+
+    #[error("Unknown Include")]
+    #[diagnostic(
+        code(configuration::include::unknown),
+        help("Included paths are relative to the file including them")
+    )]
+    UnknownInclude {
+        msg: String,
+        #[source_code]
+        input: NamedSource<String>,
+        #[label("{msg}")]
+        span: SourceSpan,
+    },
+
+    #[error("Circular Include")]
+    #[diagnostic(code(configuration::include::cycle))]
+    CircularInclude {
+        msg: String,
+        #[source_code]
+        input: NamedSource<String>,
+        #[label("{msg}")]
+        span: SourceSpan,
     },
 
     #[error("Couldn't read provided dockerfile, '{0}', for hashing")]
     FailedToInteractWithDockerfile(String),
 }
 
+/// Prefix of the configuration hash, to be bumped whenever the way an
+/// environment is hashed changes
+pub const CONFIG_HASH_VERSION: &str = "v1";
+
+/// Several problems found in the config files, reported together
+#[derive(Debug, Error, Diagnostic)]
+#[error("Found {} problems in the config", problems.len())]
+#[diagnostic(code(configuration::problems))]
+pub struct ConfigProblems {
+    #[related]
+    pub problems: Vec<Report>,
+}
+
+/// Collects the problems found while reading the config files, so every
+/// problem can be reported at once rather than stopping at the first
+#[derive(Debug, Default)]
+pub struct Problems {
+    reports: Vec<Report>,
+}
+
+impl Problems {
+    /// Records the error of `result`, unless the same error has already been
+    /// recorded, such as one repeated by every environment extending a
+    /// broken environment. Several problems found together are recorded
+    /// one by one
+    pub fn record<T>(&mut self, result: Result<T>) -> Option<T> {
+        let error = match result {
+            Ok(value) => return Some(value),
+            Err(error) => error,
+        };
+
+        let error = match error.downcast::<ConfigProblems>() {
+            Ok(problems) => {
+                for problem in problems.problems {
+                    self.record::<()>(Err(problem));
+                }
+                return None;
+            }
+            Err(error) => error,
+        };
+
+        let is_duplicate = error.downcast_ref::<ConfigError>().is_some_and(|error| {
+            self.reports
+                .iter()
+                .any(|report| report.downcast_ref::<ConfigError>() == Some(error))
+        });
+        if !is_duplicate {
+            self.reports.push(error);
+        }
+        None
+    }
+
+    /// `value` if no problems were recorded, otherwise the problem or every
+    /// problem together
+    pub fn finish<T>(mut self, value: T) -> Result<T> {
+        match self.reports.len() {
+            0 => Ok(value),
+            1 => Err(self.reports.remove(0)),
+            _ => Err(ConfigProblems {
+                problems: self.reports,
+            }
+            .into()),
+        }
+    }
+}
+
 macro_rules! labeled_error {
-    ($self:expr, $type: ident, $span:expr, $msg:expr) => {
+    ($file:expr, $type: ident, $span:expr, $msg:expr) => {
         ConfigError::$type {
-            input: NamedSource::new(
-                $self.app.config_path.to_str().unwrap(),
-                $self.content.to_string(),
-            ),
+            input: $file.source(),
             span: $span.into(),
             msg: $msg.to_string(),
         }
     };
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(deny_unknown_fields)]
-pub struct TomlEnvironment {
-    #[serde(default)]
-    entry_cmd: String,
+/// The fields of an environment or preset that are lists
+const LIST_FIELDS: [&str; 13] = [
+    "entry_options",
+    "cp_cmds",
+    "exec_cmds",
+    "exec_options",
+    "create_options",
+    "on_create",
+    "on_start",
+    "on_enter",
+    "on_stop",
+    "host_pre_create",
+    "host_post_create",
+    "host_post_exit",
+    "pass_env",
+];
+
+/// How a list field is combined with the values it inherits from presets and
+/// an extended environment
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum ListStrategy {
+    /// A plain array, placed after the extended environment's values and
+    /// before the presets'
+    #[default]
+    Merge,
+    /// Placed after all the inherited values
+    Append,
+    /// Placed before all the inherited values
+    Prepend,
+    /// Used in place of the inherited values
+    Replace,
+    /// Removed from the inherited values
+    Remove,
+}
 
-    #[serde(default)]
-    #[serde(rename = "image")]
-    provided_image: String,
+/// A list field, written either as an array or as a table naming how it's
+/// combined with the inherited values, e.g. `{ replace = [...] }`
+#[derive(Debug, Clone, Default)]
+struct TomlList {
+    values: Vec<String>,
+    strategy: ListStrategy,
+    /// Where each value came from, only known once the list has been merged
+    origins: Vec<ValueOrigin>,
+}
 
-    #[serde(default)]
-    dockerfile: String,
+/// The fields of an environment or preset that hold a single value
+const SINGLE_FIELDS: [&str; 7] = [
+    "entry_cmd",
+    "image",
+    "dockerfile",
+    "build_context",
+    "workspace",
+    "workspace_root",
+    "env_file",
+];
+
+/// The single value fields holding paths, which are relative to the file
+/// they're defined in
+const PATH_FIELDS: [&str; 3] = ["dockerfile", "build_context", "env_file"];
+
+/// The image an environment uses, and for environments with a dockerfile how
+/// it's built
+struct ResolvedImage {
+    image: String,
+    dockerfile: Option<PathBuf>,
+    build_context: Option<PathBuf>,
+    dockerfile_hash: Option<String>,
+}
 
-    #[serde(default)]
-    build_context: String,
+/// The environment or preset a value was defined in
+#[derive(Debug, Clone, PartialEq)]
+struct ValueOrigin {
+    /// Either `environment` or `preset`
+    table: &'static str,
+    name: String,
+    /// Index of the config file
+    file: usize,
+    /// Index of the value in its list, for list fields
+    index: Option<usize>,
+}
 
-    #[serde(default)]
-    entry_options: Vec<String>,
+impl<'de> Deserialize<'de> for TomlList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ListVisitor;
 
-    #[serde(default)]
-    cp_cmds: Vec<String>,
+        impl<'de> Visitor<'de> for ListVisitor {
+            type Value = TomlList;
 
-    #[serde(default)]
-    exec_cmds: Vec<String>,
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("an array of strings or a table such as `{ replace = [...] }`")
+            }
 
-    #[serde(default)]
-    exec_options: Vec<String>,
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<TomlList, A::Error> {
+                Ok(TomlList {
+                    values: Deserialize::deserialize(SeqAccessDeserializer::new(seq))?,
+                    strategy: ListStrategy::Merge,
+                    origins: Vec::new(),
+                })
+            }
 
-    #[serde(default)]
-    create_options: Vec<String>,
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<TomlList, A::Error> {
+                let single_strategy = || de::Error::custom("Expected a single merge strategy");
+
+                let key = map.next_key::<String>()?.ok_or_else(single_strategy)?;
+                let strategy = match key.as_str() {
+                    "append" => ListStrategy::Append,
+                    "prepend" => ListStrategy::Prepend,
+                    "replace" => ListStrategy::Replace,
+                    "remove" => ListStrategy::Remove,
+                    _ => {
+                        return Err(de::Error::custom(format!(
+                            "Unknown merge strategy '{key}', expected one of \
+                            'append', 'prepend', 'replace' or 'remove'"
+                        )))
+                    }
+                };
+                let values = map.next_value()?;
 
-    #[serde(default)]
-    presets: Vec<String>,
+                if map.next_key::<String>()?.is_some() {
+                    return Err(single_strategy());
+                }
+
+                Ok(TomlList {
+                    values,
+                    strategy,
+                    origins: Vec::new(),
+                })
+            }
+        }
+
+        deserializer.deserialize_any(ListVisitor)
+    }
+}
+
+impl JsonSchema for TomlList {
+    fn schema_name() -> Cow<'static, str> {
+        "List".into()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        let values = generator.subschema_for::<Vec<String>>();
+
+        json_schema!({
+            "oneOf": [
+                values,
+                {
+                    "type": "object",
+                    "description": "How the values are combined with the inherited values",
+                    "properties": {
+                        "append": values,
+                        "prepend": values,
+                        "replace": values,
+                        "remove": values,
+                    },
+                    "minProperties": 1,
+                    "maxProperties": 1,
+                    "additionalProperties": false,
+                },
+            ],
+        })
+    }
+}
+
+/// What is mounted into the container
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MountType {
+    /// A file or directory on the host
+    #[default]
+    Bind,
+    /// A volume managed by the container engine
+    Volume,
+    /// A temporary filesystem held in memory
+    Tmpfs,
+}
+
+impl MountType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MountType::Bind => "bind",
+            MountType::Volume => "volume",
+            MountType::Tmpfs => "tmpfs",
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Default)]
+/// A mount into the container
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
-pub struct TomlPreset {
+pub struct Mount {
+    /// The host path of a bind mount, relative to the config file, or the name
+    /// of a volume
     #[serde(default)]
-    entry_cmd: String,
-
+    pub source: Option<String>,
+    /// The absolute path the mount is placed at in the container
+    pub target: String,
+    /// What is mounted, a bind mount if not given
+    #[serde(rename = "type", default)]
+    pub kind: MountType,
+    /// Whether the container can only read from the mount
     #[serde(default)]
-    #[serde(rename = "image")]
-    provided_image: String,
+    pub readonly: bool,
+}
 
-    #[serde(default)]
-    dockerfile: String,
+impl Mount {
+    /// Whether both mounts are placed at the same path in the container
+    fn same_target(&self, other: &Mount) -> bool {
+        self.target.trim_end_matches('/') == other.target.trim_end_matches('/')
+    }
 
-    #[serde(default)]
-    build_context: String,
+    fn to_toml(&self) -> toml_edit::InlineTable {
+        let mut table = toml_edit::InlineTable::new();
+        if let Some(source) = &self.source {
+            table.insert("source", source.into());
+        }
+        table.insert("target", self.target.as_str().into());
+        if self.kind != MountType::Bind {
+            table.insert("type", self.kind.as_str().into());
+        }
+        if self.readonly {
+            table.insert("readonly", true.into());
+        }
+        table
+    }
+}
 
-    #[serde(default)]
-    entry_options: Vec<String>,
+/// Written the way `docker create --mount` takes it
+impl fmt::Display for Mount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "type={}", self.kind.as_str())?;
+        if let Some(source) = &self.source {
+            write!(f, ",source={source}")?;
+        }
+        write!(f, ",target={}", self.target)?;
+        if self.readonly {
+            write!(f, ",readonly")?;
+        }
+        Ok(())
+    }
+}
 
-    #[serde(default)]
-    cp_cmds: Vec<String>,
+/// Which host directory is mounted at an environment's `workspace`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum WorkspaceRoot {
+    /// The directory berth is run from
+    #[default]
+    Cwd,
+    /// The root of the git repository berth is run from, or the directory
+    /// berth is run from outside of one
+    Git,
+}
 
-    #[serde(default)]
-    exec_cmds: Vec<String>,
+/// The host directory mounted into the container by the `workspace` field
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Workspace {
+    /// Path of the mount in the container
+    pub target: String,
+    pub root: WorkspaceRoot,
+    /// The host directory mounted at the `target`
+    pub source: PathBuf,
+    /// The directory commands are run from in the container, the place of
+    /// the directory berth is run from in the mount
+    pub workdir: String,
+}
 
-    #[serde(default)]
-    exec_options: Vec<String>,
+/// Declares a table of the config file with the fields environments and
+/// presets share, followed by its own fields, and ways to get those shared
+/// fields by name
+macro_rules! toml_table {
+    (
+        $(#[$attr:meta])*
+        pub struct $name:ident {
+            $($fields:tt)*
+        }
+    ) => {
+        $(#[$attr])*
+        pub struct $name {
+            /// The command run in the container when the environment is entered
+            #[serde(default)]
+            entry_cmd: String,
+
+            /// The container image to use, pulled if it isn't available locally
+            #[serde(default)]
+            #[serde(rename = "image")]
+            provided_image: String,
+
+            /// Path to a dockerfile to build the container's image from
+            #[serde(default)]
+            dockerfile: String,
+
+            /// Path to the directory used as the build context of the `dockerfile`
+            #[serde(default)]
+            build_context: String,
+
+            /// Path in the container the directory berth is run from is mounted at,
+            /// which commands are run from
+            #[serde(default)]
+            workspace: String,
+
+            /// Which directory is mounted at the `workspace`, the current directory
+            /// by default
+            #[serde(default)]
+            #[schemars(with = "WorkspaceRoot")]
+            workspace_root: String,
+
+            /// Options passed to `docker exec` for the `entry_cmd`
+            #[serde(default)]
+            entry_options: TomlList,
+
+            /// Commands copying files to or from the container, following `docker cp`
+            #[serde(default)]
+            cp_cmds: TomlList,
+
+            /// Commands run in the container when it is created
+            #[serde(default)]
+            exec_cmds: TomlList,
+
+            /// `docker exec` options used for all `exec_cmds`
+            #[serde(default)]
+            exec_options: TomlList,
+
+            /// `docker create` options used when creating the container
+            #[serde(default)]
+            create_options: TomlList,
+
+            /// Commands run in the container once it has been created, after `exec_cmds`
+            #[serde(default)]
+            on_create: TomlList,
+
+            /// Commands run in the container each time it is started
+            #[serde(default)]
+            on_start: TomlList,
+
+            /// Commands run in the container each time the environment is entered
+            #[serde(default)]
+            on_enter: TomlList,
+
+            /// Commands run in the container before berth stops it
+            #[serde(default)]
+            on_stop: TomlList,
+
+            /// Commands run on the host, from the config file's directory, before
+            /// the container is created
+            #[serde(default)]
+            host_pre_create: TomlList,
+
+            /// Commands run on the host, from the config file's directory, after the
+            /// container is created
+            #[serde(default)]
+            host_post_create: TomlList,
+
+            /// Commands run on the host, from the config file's directory, after the
+            /// `entry_cmd` exits
+            #[serde(default)]
+            host_post_exit: TomlList,
+
+            /// Bind mounts, volumes and tmpfs mounts added to the container
+            #[serde(default)]
+            mounts: Vec<Mount>,
+
+            /// Environment variables set in the container and for every command run
+            /// in it
+            #[serde(default)]
+            env: BTreeMap<String, String>,
+
+            /// Path to a file of environment variables, one `KEY=value` per line
+            #[serde(default)]
+            env_file: String,
+
+            /// Names of the host's environment variables passed to the container
+            #[serde(default)]
+            pass_env: TomlList,
+
+            /// Names of presets to merge in
+            #[serde(default)]
+            presets: Vec<String>,
+
+            $($fields)*
+        }
 
-    #[serde(default)]
-    create_options: Vec<String>,
+        impl $name {
+            fn single(&self, field: &str) -> &str {
+                single_field!(self, field)
+            }
+
+            fn list(&self, field: &str) -> &TomlList {
+                list_field!(self, field)
+            }
+        }
+    };
+}
+
+/// The single value field of `$table` named `$field`, borrowed mutably when
+/// followed by `mut`
+macro_rules! single_field {
+    ($table:expr, $field:expr $(, $mut:tt)?) => {
+        match $field {
+            "entry_cmd" => &$($mut)? $table.entry_cmd,
+            "image" => &$($mut)? $table.provided_image,
+            "dockerfile" => &$($mut)? $table.dockerfile,
+            "build_context" => &$($mut)? $table.build_context,
+            "workspace" => &$($mut)? $table.workspace,
+            "workspace_root" => &$($mut)? $table.workspace_root,
+            "env_file" => &$($mut)? $table.env_file,
+            field => unreachable!("Unknown field {field}"),
+        }
+    };
+}
+
+/// The list field of `$table` named `$field`, borrowed mutably when followed
+/// by `mut`
+macro_rules! list_field {
+    ($table:expr, $field:expr $(, $mut:tt)?) => {
+        match $field {
+            "entry_options" => &$($mut)? $table.entry_options,
+            "cp_cmds" => &$($mut)? $table.cp_cmds,
+            "exec_cmds" => &$($mut)? $table.exec_cmds,
+            "exec_options" => &$($mut)? $table.exec_options,
+            "create_options" => &$($mut)? $table.create_options,
+            "on_create" => &$($mut)? $table.on_create,
+            "on_start" => &$($mut)? $table.on_start,
+            "on_enter" => &$($mut)? $table.on_enter,
+            "on_stop" => &$($mut)? $table.on_stop,
+            "host_pre_create" => &$($mut)? $table.host_pre_create,
+            "host_post_create" => &$($mut)? $table.host_post_create,
+            "host_post_exit" => &$($mut)? $table.host_post_exit,
+            "pass_env" => &$($mut)? $table.pass_env,
+            field => unreachable!("Unknown list field {field}"),
+        }
+    };
+}
+
+toml_table! {
+    /// An environment, defined in an `environment` sub-table named after it
+    #[derive(Debug, Clone, Deserialize, JsonSchema)]
+    #[serde(deny_unknown_fields)]
+    #[schemars(rename = "Environment")]
+    pub struct TomlEnvironment {
+        /// The environment this environment is a variant of
+        #[serde(default)]
+        #[schemars(with = "String")]
+        extends: Option<String>,
+
+        /// Every preset merged into the environment, including those listed by
+        /// other presets, in the order they are merged
+        #[serde(skip)]
+        resolved_presets: Vec<String>,
+
+        /// Where each of the `mounts` came from, only known once they have been
+        /// merged
+        #[serde(skip)]
+        mount_origins: Vec<ValueOrigin>,
+
+        /// Where each variable of `env` came from, only known once they have been
+        /// merged
+        #[serde(skip)]
+        env_origins: BTreeMap<String, ValueOrigin>,
+
+        /// Index of the config file the environment was defined in
+        #[serde(skip)]
+        file: usize,
+
+        /// Where each single value field came from, once presets and the
+        /// extended environment have been merged
+        #[serde(skip)]
+        origins: HashMap<&'static str, ValueOrigin>,
+    }
+}
+
+toml_table! {
+    /// Fields shared by environments, defined in a `preset` sub-table named after
+    /// it and merged into the environments listing it
+    #[derive(Debug, Deserialize, Default, JsonSchema)]
+    #[serde(deny_unknown_fields)]
+    #[schemars(rename = "Preset")]
+    pub struct TomlPreset {
+        /// Index of the config file the preset was defined in
+        #[serde(skip)]
+        file: usize,
+    }
+}
+
+impl TomlEnvironment {
+    fn single_mut(&mut self, field: &str) -> &mut String {
+        single_field!(self, field, mut)
+    }
+
+    fn list_mut(&mut self, field: &str) -> &mut TomlList {
+        list_field!(self, field, mut)
+    }
 }
 
 type TomlEnvs = HashMap<String, TomlEnvironment>;
 type TomlPresets = HashMap<String, TomlPreset>;
 
-#[derive(Debug, Deserialize)]
+/// A berth config file
+#[derive(Debug, Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
+#[schemars(title = "berth configuration")]
 pub struct TomlConfiguration {
-    #[serde(rename = "environment")]
+    /// Other config files to read, relative to this one, which may use `~`,
+    /// environment variables and glob patterns
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// The container engine used for every environment
+    #[serde(default)]
+    #[schemars(with = "Engine")]
+    pub engine: Option<Engine>,
+    /// The environments, by name
+    #[serde(rename = "environment", default)]
     pub environments: TomlEnvs,
+    /// The presets, by name
     #[serde(rename = "preset", default)]
     pub presets: TomlPresets,
 }
 
-#[derive(Hash, Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Environment {
     pub name: String,
+    /// The config file the environment was defined in, which relative paths
+    /// are resolved against
+    pub config_path: PathBuf,
+    #[serde(rename = "environment")]
     pub original_name: String,
+    /// Hash of the environment's configuration, used as the last part of `name`
+    pub hash: String,
     pub image: String,
+    /// Hash of the dockerfile's content, used as the last part of `image` when
+    /// the image is built by berth
+    pub dockerfile_hash: Option<String>,
     pub dockerfile: Option<PathBuf>,
     pub build_context: Option<PathBuf>,
     pub entry_cmd: String,
@@ -184,37 +810,362 @@ pub struct Environment {
     pub exec_options: Vec<String>,
     pub create_options: Vec<String>,
     pub cp_cmds: Vec<String>,
+    pub on_create: Vec<String>,
+    pub on_start: Vec<String>,
+    pub on_enter: Vec<String>,
+    pub on_stop: Vec<String>,
+    pub host_pre_create: Vec<String>,
+    pub host_post_create: Vec<String>,
+    pub host_post_exit: Vec<String>,
+    /// The mounts, with the sources of bind mounts resolved
+    pub mounts: Vec<Mount>,
+    pub workspace: Option<Workspace>,
+    /// Environment variables set in the container, after variables are expanded
+    pub env: BTreeMap<String, String>,
+    pub env_file: Option<PathBuf>,
+    /// Hash of the env file's content, so editing the file recreates the
+    /// container
+    pub env_file_hash: Option<String>,
+    /// Names of the host's environment variables passed to the container
+    pub pass_env: Vec<String>,
+    /// Where each field came from, with an origin per element for lists
+    #[serde(skip)]
+    pub origins: HashMap<String, Vec<Origin>>,
 }
 
-pub struct Configuration {
+/// Where a field, or an element of a list field, of an environment was defined
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    /// The environment or preset, e.g. `preset 'interactive'`
+    pub source: String,
+    pub path: PathBuf,
+    pub line: usize,
+}
+
+/// A config file environments and presets are read from
+struct ConfigFile {
+    path: PathBuf,
     content: String,
-    app: AppConfig,
     doc: Option<toml_edit::ImDocument<String>>,
 }
 
+impl ConfigFile {
+    fn read(path: &Path) -> Result<Self> {
+        Ok(ConfigFile {
+            path: path.to_path_buf(),
+            content: fs::read_to_string(path).unexpected()?,
+            doc: None,
+        })
+    }
+
+    fn source(&self) -> NamedSource<String> {
+        NamedSource::new(self.path.to_str().unwrap(), self.content.to_string())
+    }
+
+    fn doc(&self) -> Result<&toml_edit::ImDocument<String>> {
+        self.doc.as_ref().unexpected()
+    }
+
+    /// The item defining the environment or preset `name`, where `table` is
+    /// either `environment` or `preset`
+    fn item(&self, table: &str, name: &str) -> Result<Option<&toml_edit::Item>> {
+        Ok(self
+            .doc()?
+            .get(table)
+            .and_then(|item| item.as_table())
+            .and_then(|table| table.get(name)))
+    }
+
+    /// The span of `preset` in the `presets` array of the environment or
+    /// preset `name`
+    fn preset_entry_span(&self, table: &str, name: &str, preset: &str) -> Result<Range<usize>> {
+        self.item(table, name)?
+            .and_then(|item| item.get("presets"))
+            .and_then(|item| item.as_array())
+            .and_then(|array| {
+                array
+                    .iter()
+                    .find(|value| value.as_str() == Some(preset))
+                    .and_then(|value| value.span())
+            })
+            .unexpected()
+    }
+
+    /// Resolves a path from the file, expanding `~` and environment variables
+    /// and making relative paths relative to the file's directory
+    fn resolve_path(&self, path: &str) -> PathBuf {
+        let mut options = ExpandOptions::new();
+        options.expansion_type = Some(ExpansionType::Unix);
+
+        let path = match path.strip_prefix('~') {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("$HOME{rest}"),
+            _ => path.to_string(),
+        };
+        let path = PathBuf::from(envmnt::expand(&path, Some(options)));
+        match self.path.parent() {
+            Some(dir) if path.is_relative() => dir.join(path),
+            _ => path,
+        }
+    }
+
+    /// Resolves a path like `resolve_path`, then makes it absolute from the
+    /// current directory when the file was given by a relative path
+    fn absolute_path(&self, path: &str) -> Result<PathBuf> {
+        let path = self.resolve_path(path);
+        if path.is_absolute() {
+            return Ok(path);
+        }
+        Ok(std::env::current_dir().unexpected()?.join(path))
+    }
+
+    /// The span of each entry of the `include` array
+    fn include_spans(&self) -> Result<Vec<Range<usize>>> {
+        self.doc()?
+            .get("include")
+            .and_then(|item| item.as_array())
+            .map(|array| array.iter().map(|value| value.span()).collect())
+            .unwrap_or(Some(Vec::new()))
+            .unexpected()
+    }
+}
+
+pub struct Configuration {
+    app: AppConfig,
+    files: Vec<ConfigFile>,
+    engine: Option<Engine>,
+}
+
 impl Configuration {
+    /// JSON Schema of the config file format, for editor completion and
+    /// validation
+    pub fn schema() -> Result<String> {
+        to_json(&schemars::schema_for!(TomlConfiguration))
+    }
+
     pub fn new(app: &AppConfig) -> Result<Self> {
-        let content = fs::read_to_string(&app.config_path).unexpected()?;
+        // Fail early if a config file can't be read, they are read again
+        // along with any files they include when environments are found
+        for path in &app.config_paths {
+            ConfigFile::read(path)?;
+        }
+
         Ok(Self {
-            content,
             app: app.clone(),
-            doc: None,
+            files: Vec::new(),
+            engine: None,
         })
     }
 
-    pub fn find_environment_from_configuration(mut self) -> Result<Environment> {
-        let config = self.parse_toml()?;
+    /// The config files environments are read from, including any included
+    /// files, from lowest to highest priority. Only known once environments
+    /// have been found
+    pub fn config_paths(&self) -> Vec<PathBuf> {
+        self.files.iter().map(|file| file.path.clone()).collect()
+    }
+
+    /// The engine given on the command line, otherwise the one set in the
+    /// config files, only known once environments have been found
+    pub fn engine(&self) -> Engine {
+        self.app.engine.or(self.engine).unwrap_or_default()
+    }
+
+    pub fn find_environment_from_configuration(&mut self) -> Result<Environment> {
+        let mut envs = self.resolve_environments()?;
+        let name = self.app.environment.clone();
+
+        match envs.remove(&name) {
+            Some(env) => self.create_environment(&name, env),
+            None => {
+                let file = self.files.last().unexpected()?;
+                Err(labeled_error!(
+                    file,
+                    EnvironmentSearch,
+                    (0, file.content.len()),
+                    format!("Failed to find provided environment '{}' in config", &name)
+                )
+                .into())
+            }
+        }
+    }
+
+    pub fn find_all_environments(&mut self) -> Result<Vec<Environment>> {
+        let (environments, problems) = self.check_all_environments()?;
+        problems.finish(environments)
+    }
+
+    /// Creates every environment, collecting the problems of those that are
+    /// invalid rather than stopping at the first
+    pub fn check_all_environments(&mut self) -> Result<(Vec<Environment>, Problems)> {
+        let envs = self.resolve_environments()?;
+
+        let mut sorted_envs: Vec<_> = envs.into_iter().collect();
+        sorted_envs.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut environments = Vec::new();
+        let mut problems = Problems::default();
+        for (name, env) in sorted_envs {
+            if let Some(env) = problems.record(self.create_environment(&name, env)) {
+                environments.push(env);
+            }
+        }
+
+        Ok((environments, problems))
+    }
+
+    fn resolve_environments(&mut self) -> Result<TomlEnvs> {
+        let config = self.parse_files()?;
         let config = self.check_presets_exist(config)?;
+        let config = self.resolve_presets(config)?;
         let config = self.valid_unique_fields(config)?;
-        let envs = self.merge_presets(config)?;
-        let envs = self.validate_environments(envs)?;
-        self.create_environment(envs)
+        let config = self.merge_presets(config)?;
+        let envs = self.extend_environments(config)?;
+        self.validate_environments(envs)
+    }
+
+    /// Parses every config file, with the environments, presets and engine
+    /// of later files replacing those of the same name in earlier ones
+    fn parse_files(&mut self) -> Result<TomlConfiguration> {
+        let mut merged = TomlConfiguration {
+            include: Vec::new(),
+            engine: None,
+            environments: TomlEnvs::new(),
+            presets: TomlPresets::new(),
+        };
+
+        self.files.clear();
+        let mut problems = Problems::default();
+        for path in self.app.config_paths.clone() {
+            let parsed = ConfigFile::read(&path)
+                .and_then(|file| self.parse_file(file, &mut merged, &mut Vec::new()));
+            problems.record(parsed);
+        }
+
+        self.engine = merged.engine;
+        problems.finish(merged)
+    }
+
+    /// Parses a config file into `merged`, after the files it includes so
+    /// that its own environments and presets take priority over theirs.
+    /// `including` holds the canonical paths of the files that led to this
+    /// one being included, to detect cycles
+    fn parse_file(
+        &mut self,
+        mut file: ConfigFile,
+        merged: &mut TomlConfiguration,
+        including: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        let mut config = Self::parse_toml(&mut file)?;
+        let canonical_path = fs::canonicalize(&file.path).unexpected()?;
+
+        // A file included by several others, or also given as a config file,
+        // only needs reading once
+        if self.is_loaded(&canonical_path)? {
+            return Ok(());
+        }
+
+        including.push(canonical_path);
+        for (path, span) in Self::find_includes(&file, &config.include)? {
+            if let Some(start) = including.iter().position(|included| *included == path) {
+                let cycle: Vec<String> = including[start..]
+                    .iter()
+                    .chain(std::iter::once(&path))
+                    .map(|path| path.display().to_string())
+                    .collect();
+                return Err(labeled_error!(
+                    file,
+                    CircularInclude,
+                    span,
+                    format!(
+                        "Including this file creates a cycle: {}",
+                        cycle.join(" -> ")
+                    )
+                )
+                .into());
+            }
+
+            let included = ConfigFile::read(&path)?;
+            self.parse_file(included, merged, including)?;
+        }
+        including.pop();
+
+        let index = self.files.len();
+        for env in config.environments.values_mut() {
+            env.file = index;
+        }
+        for preset in config.presets.values_mut() {
+            preset.file = index;
+        }
+
+        merged.engine = config.engine.or(merged.engine);
+        merged.environments.extend(config.environments);
+        merged.presets.extend(config.presets);
+
+        self.files.push(file);
+        Ok(())
     }
 
-    fn parse_toml(&mut self) -> Result<TomlConfiguration> {
-        match toml_edit::de::from_str::<TomlConfiguration>(&self.content) {
+    fn is_loaded(&self, canonical_path: &Path) -> Result<bool> {
+        for file in &self.files {
+            if fs::canonicalize(&file.path).unexpected()? == canonical_path {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// The canonical paths of the files included by `file`, along with the
+    /// span of the include entry matching them. Glob patterns are expanded in
+    /// alphabetical order and may match nothing, while plain paths must exist
+    fn find_includes(
+        file: &ConfigFile,
+        includes: &[String],
+    ) -> Result<Vec<(PathBuf, Range<usize>)>> {
+        let spans = file.include_spans()?;
+        let mut paths = Vec::new();
+
+        for (include, span) in includes.iter().zip(spans) {
+            let pattern = file.resolve_path(include);
+            let pattern = pattern.to_str().unexpected()?;
+
+            let matches: Vec<PathBuf> = if include.contains(['*', '?', '[']) {
+                let entries = glob::glob(pattern).map_err(|error| {
+                    labeled_error!(
+                        file,
+                        UnknownInclude,
+                        span.clone(),
+                        format!("Invalid glob pattern: {}", error.msg)
+                    )
+                })?;
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .filter(|path| path.is_file())
+                    .collect()
+            } else {
+                let path = PathBuf::from(pattern);
+                if !path.is_file() {
+                    return Err(labeled_error!(
+                        file,
+                        UnknownInclude,
+                        span,
+                        format!("Failed to find included file at {:?}", path)
+                    )
+                    .into());
+                }
+                vec![path]
+            };
+
+            for path in matches {
+                paths.push((fs::canonicalize(&path).unexpected()?, span.clone()));
+            }
+        }
+
+        Ok(paths)
+    }
+
+    fn parse_toml(file: &mut ConfigFile) -> Result<TomlConfiguration> {
+        match toml_edit::de::from_str::<TomlConfiguration>(&file.content) {
             Ok(config) => {
-                self.doc = Some(self.content.parse().unexpected()?);
+                file.doc = Some(file.content.parse().unexpected()?);
                 Ok(config)
             }
             Err(error) => {
@@ -224,333 +1175,1203 @@ impl Configuration {
                     s if s.contains("missing field") => error.message(),
                     s if s.contains("unknown field") => "Unknown field",
                     s if s.contains("invalid type") => error.message(),
+                    s if s.contains("unknown variant") => error.message(),
                     s if s.contains("duplicate key") => error.message(),
+                    s if s.contains("merge strategy") => error.message(),
                     _ => &format!("Unexpected TOML Error {:?}", error.message()),
                 };
 
-                Err(labeled_error!(self, TomlParse, span, label_message).into())
+                Err(labeled_error!(file, TomlParse, span, label_message).into())
             }
         }
     }
 
     fn check_presets_exist(&self, config: TomlConfiguration) -> Result<TomlConfiguration> {
-        for (env_name, env) in &config.environments {
-            for preset_name in &env.presets {
+        let envs = config
+            .environments
+            .iter()
+            .map(|(name, env)| ("environment", name, env.file, &env.presets));
+        let presets = config
+            .presets
+            .iter()
+            .map(|(name, preset)| ("preset", name, preset.file, &preset.presets));
+
+        let mut lists: Vec<_> = envs.chain(presets).collect();
+        lists.sort();
+
+        let mut problems = Problems::default();
+        for (table, name, file, presets) in lists {
+            let file = &self.files[file];
+            for preset_name in presets {
                 if !config.presets.contains_key(preset_name) {
-                    let span = self
-                        .doc
-                        .as_ref()
-                        .unexpected()?
-                        .get("environment")
-                        .and_then(|env| env.as_table())
-                        .and_then(|table| table.get(env_name))
-                        .and_then(|item| item.get("presets"))
-                        .and_then(|item| item.as_array())
-                        .and_then(|array| {
-                            array
-                                .iter()
-                                .find(|v| v.as_str() == Some(preset_name))
-                                .and_then(|value| value.span())
-                        })
-                        .unexpected()?;
-                    return Err(labeled_error!(
-                        self,
+                    let span = file.preset_entry_span(table, name, preset_name)?;
+                    problems.record::<()>(Err(labeled_error!(
+                        file,
                         UnknownPreset,
                         span,
                         "Failed to find provided preset"
                     )
-                    .into());
+                    .into()));
                 }
             }
         }
+        problems.finish(config)
+    }
+
+    /// Finds every preset merged into each environment, erroring if presets
+    /// list each other in a cycle
+    fn resolve_presets(&self, mut config: TomlConfiguration) -> Result<TomlConfiguration> {
+        // Presets not used by any environment are checked too, so a cycle is
+        // reported as soon as it's written
+        let mut preset_names: Vec<&String> = config.presets.keys().collect();
+        preset_names.sort();
+        let mut problems = Problems::default();
+        // Presets that led to a problem, so a cycle is only reported from the
+        // first preset in it
+        let mut failed: Vec<String> = Vec::new();
+        for name in preset_names {
+            if failed.contains(name) {
+                continue;
+            }
+
+            let mut stack = Vec::new();
+            let result = self.preset_tree(&config.presets, name, &mut stack, &mut Vec::new());
+            if problems.record(result).is_none() {
+                failed.extend(stack);
+            }
+        }
+        problems.finish(())?;
+
+        let mut resolved = HashMap::new();
+        for (env_name, env) in &config.environments {
+            let mut tree = Vec::new();
+            for preset_name in &env.presets {
+                self.preset_tree(&config.presets, preset_name, &mut Vec::new(), &mut tree)?;
+            }
+            resolved.insert(env_name.clone(), tree);
+        }
+
+        for (env_name, env) in config.environments.iter_mut() {
+            env.resolved_presets = resolved.remove(env_name).unexpected()?;
+        }
         Ok(config)
     }
 
+    /// Adds `name` and the presets it lists to `tree`, depth first, skipping
+    /// presets already in the tree. `stack` holds the presets that led to
+    /// `name`, to detect cycles
+    fn preset_tree(
+        &self,
+        presets: &TomlPresets,
+        name: &str,
+        stack: &mut Vec<String>,
+        tree: &mut Vec<String>,
+    ) -> Result<()> {
+        if tree.iter().any(|preset| preset == name) {
+            return Ok(());
+        }
+
+        stack.push(name.to_string());
+        tree.push(name.to_string());
+
+        let preset = presets.get(name).unexpected()?;
+        for child in &preset.presets {
+            if let Some(start) = stack.iter().position(|preset| preset == child) {
+                let file = &self.files[preset.file];
+                let cycle: Vec<&str> = stack[start..]
+                    .iter()
+                    .map(String::as_str)
+                    .chain(std::iter::once(child.as_str()))
+                    .collect();
+                return Err(labeled_error!(
+                    file,
+                    CircularPreset,
+                    file.preset_entry_span("preset", name, child)?,
+                    format!("Presets form a cycle: {}", cycle.join(" -> "))
+                )
+                .into());
+            }
+
+            self.preset_tree(presets, child, stack, tree)?;
+        }
+
+        stack.pop();
+        Ok(())
+    }
+
     fn valid_unique_fields(&self, config: TomlConfiguration) -> Result<TomlConfiguration> {
-        let find_fields_span = |table: &str, name: &str, field: &str| -> Result<SourceSpan> {
-            let span = self
-                .doc
-                .as_ref()
-                .unexpected()?
-                .get(table)
-                .and_then(|envs_item| envs_item.as_table())
-                .and_then(|envs_table| envs_table.get(name))
-                .and_then(|env_item| env_item.as_table())
-                .and_then(|env_table| env_table.get_key_value(field))
-                .map(|(key, value)| {
-                    let key_span = key.span().unwrap();
-                    let value_span = value.span().unwrap();
-                    key_span.start..value_span.end
+        let find_fields_span =
+            |file: &ConfigFile, table: &str, name: &str, field: &str| -> Result<SourceSpan> {
+                let span = file
+                    .item(table, name)?
+                    .and_then(|env_item| env_item.as_table())
+                    .and_then(|env_table| env_table.get_key_value(field))
+                    .map(|(key, value)| {
+                        let key_span = key.span().unwrap();
+                        let value_span = value.span().unwrap();
+                        key_span.start..value_span.end
+                    })
+                    .unexpected()?;
+                Ok(span.into())
+            };
+
+        let check_unique = |field: &str, env: &TomlEnvironment, env_name: &str| -> Result<()> {
+            // The file and span of each instance of the field
+            let mut instances: Vec<(usize, SourceSpan)> = Vec::new();
+
+            let is_env_field_preset = !env.single(field).is_empty();
+
+            if is_env_field_preset {
+                let file = &self.files[env.file];
+                let span = find_fields_span(file, "environment", env_name, field)?;
+                instances.push((env.file, span));
+            }
+
+            for preset_name in &env.resolved_presets {
+                let preset = &config.presets[preset_name];
+                let is_preset_field_preset = !preset.single(field).is_empty();
+
+                if is_preset_field_preset {
+                    let file = &self.files[preset.file];
+                    let span = find_fields_span(file, "preset", preset_name, field)?;
+                    instances.push((preset.file, span));
+                }
+            }
+
+            // If we more than 1 instance, then we have duplicate fields
+            // if zero, then non are present which is fine for some fields
+            // and is handled later.
+            if instances.len() <= 1 {
+                return Ok(());
+            }
+
+            let presets_span = self.files[env.file]
+                .item("environment", env_name)?
+                .and_then(|item| item.get("presets"))
+                .and_then(|item| item.span())
+                .unexpected()?;
+
+            // Instances in other files than the environment's are reported
+            // alongside it, as a diagnostic can only show a single file
+            let mut files = vec![env.file];
+            for (file, _) in &instances {
+                if !files.contains(file) {
+                    files.push(*file);
+                }
+            }
+
+            let mut errors: Vec<ConfigError> = files
+                .into_iter()
+                .map(|index| {
+                    let mut spans: Vec<LabeledSpan> = instances
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, (file, _))| *file == index)
+                        .map(|(instance, (_, span))| {
+                            let text = format!("instance {}", instance + 1);
+                            LabeledSpan::new_with_span(Some(text), *span)
+                        })
+                        .collect();
+
+                    if index == env.file {
+                        spans.push(LabeledSpan::new_with_span(
+                            Some(format!("Preset(s) causing duplicate '{field}' field")),
+                            presets_span.clone(),
+                        ));
+                    }
+
+                    ConfigError::DuplicateFieldsFromPresets {
+                        input: self.files[index].source(),
+                        spans,
+                        others: Vec::new(),
+                    }
                 })
+                .collect();
+
+            let mut error = errors.remove(0);
+            if let ConfigError::DuplicateFieldsFromPresets { others, .. } = &mut error {
+                *others = errors;
+            }
+            Err(error.into())
+        };
+
+        let mut env_names: Vec<&String> = config.environments.keys().collect();
+        env_names.sort();
+
+        let mut problems = Problems::default();
+        for env_name in env_names {
+            for field in SINGLE_FIELDS {
+                problems.record(check_unique(
+                    field,
+                    &config.environments[env_name],
+                    env_name,
+                ));
+            }
+        }
+        problems.finish(config)
+    }
+
+    /// Merges the single value fields of each environment's presets, their
+    /// lists are merged along with the extended environment's
+    fn merge_presets(&self, mut config: TomlConfiguration) -> Result<TomlConfiguration> {
+        for (env_name, env) in config.environments.iter_mut() {
+            for field in SINGLE_FIELDS {
+                if !env.single(field).is_empty() {
+                    let origin = ValueOrigin {
+                        table: "environment",
+                        name: env_name.clone(),
+                        file: env.file,
+                        index: None,
+                    };
+                    env.origins.insert(field, origin);
+                }
+            }
+
+            for preset_name in env.resolved_presets.clone() {
+                let preset = config.presets.get(&preset_name).unexpected()?;
+                let origin = ValueOrigin {
+                    table: "preset",
+                    name: preset_name,
+                    file: preset.file,
+                    index: None,
+                };
+
+                for field in SINGLE_FIELDS {
+                    let value = preset.single(field);
+                    if value.is_empty() {
+                        continue;
+                    }
+
+                    env.origins.insert(field, origin.clone());
+                    *env.single_mut(field) =
+                        if PATH_FIELDS.contains(&field) && preset.file != env.file {
+                            // Relative to the preset's file rather than the environment's
+                            let file = &self.files[preset.file];
+                            file.resolve_path(value).display().to_string()
+                        } else {
+                            value.to_string()
+                        };
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Applies each environment on top of the environment it extends, after
+    /// both have had their presets merged, and merges their lists
+    fn extend_environments(&self, config: TomlConfiguration) -> Result<TomlEnvs> {
+        let envs = &config.environments;
+        let mut names: Vec<&String> = envs.keys().collect();
+        names.sort();
+
+        let mut extended = TomlEnvs::new();
+        let mut problems = Problems::default();
+        // Environments that led to a problem, so a cycle is only reported
+        // from the first environment in it
+        let mut failed: Vec<String> = Vec::new();
+        for name in names {
+            if failed.contains(name) {
+                continue;
+            }
+
+            let mut stack = Vec::new();
+            let result = self.extend_environment(name, &config, &mut extended, &mut stack);
+            if problems.record(result).is_none() {
+                failed.extend(stack);
+            }
+        }
+        problems.finish(extended)
+    }
+
+    /// Adds `name` to `extended` once the environment it extends has been.
+    /// `stack` holds the environments extending `name`, to detect cycles
+    fn extend_environment(
+        &self,
+        name: &str,
+        config: &TomlConfiguration,
+        extended: &mut TomlEnvs,
+        stack: &mut Vec<String>,
+    ) -> Result<()> {
+        let envs = &config.environments;
+        if extended.contains_key(name) {
+            return Ok(());
+        }
+
+        let mut env = envs.get(name).unexpected()?.clone();
+
+        if let Some(base_name) = env.extends.clone() {
+            let file = &self.files[env.file];
+            let span = file
+                .item("environment", name)?
+                .and_then(|item| item.get("extends"))
+                .and_then(|item| item.span())
                 .unexpected()?;
-            Ok(span.into())
+
+            if !envs.contains_key(&base_name) {
+                return Err(labeled_error!(
+                    file,
+                    UnknownExtends,
+                    span,
+                    "Failed to find environment to extend"
+                )
+                .into());
+            }
+
+            stack.push(name.to_string());
+            if let Some(start) = stack.iter().position(|env| *env == base_name) {
+                let cycle: Vec<&str> = stack[start..]
+                    .iter()
+                    .map(String::as_str)
+                    .chain(std::iter::once(base_name.as_str()))
+                    .collect();
+                return Err(labeled_error!(
+                    file,
+                    CircularExtends,
+                    span,
+                    format!("Environments form a cycle: {}", cycle.join(" -> "))
+                )
+                .into());
+            }
+
+            self.extend_environment(&base_name, config, extended, stack)?;
+            stack.pop();
+
+            self.inherit(&mut env, &extended[&base_name]);
+        }
+
+        let base = env.extends.as_ref().map(|base_name| &extended[base_name]);
+        for field in LIST_FIELDS {
+            let mut visited = Vec::new();
+            let mut from_presets = Vec::new();
+            for preset_name in &env.presets {
+                from_presets.extend(self.preset_list(
+                    &config.presets,
+                    preset_name,
+                    field,
+                    &mut visited,
+                )?);
+            }
+            let from_base: Vec<_> = base
+                .map(|base| {
+                    let list = base.list(field);
+                    list.values
+                        .iter()
+                        .cloned()
+                        .zip(list.origins.clone())
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            let merged = self.merge_list(
+                ("environment", name, env.file),
+                field,
+                env.list(field),
+                from_base,
+                from_presets,
+            )?;
+            let (values, origins) = merged.into_iter().unzip();
+            *env.list_mut(field) = TomlList {
+                values,
+                strategy: ListStrategy::Merge,
+                origins,
+            };
+        }
+
+        let (mounts, origins) = self.merge_mounts(name, &env, base, &config.presets)?;
+        env.mounts = mounts;
+        env.mount_origins = origins;
+
+        let (variables, origins) = self.merge_env(name, &env, base, &config.presets)?;
+        env.env = variables;
+        env.env_origins = origins;
+
+        extended.insert(name.to_string(), env);
+        Ok(())
+    }
+
+    /// The mounts of the environment `name` and its presets, which must each
+    /// have a different target, after those of the extended environment that
+    /// aren't replaced by a mount at the same target
+    fn merge_mounts(
+        &self,
+        name: &str,
+        env: &TomlEnvironment,
+        base: Option<&TomlEnvironment>,
+        presets: &TomlPresets,
+    ) -> Result<(Vec<Mount>, Vec<ValueOrigin>)> {
+        let with_origins = |mounts: &[Mount], table, name: &str, file| {
+            mounts
+                .iter()
+                .enumerate()
+                .map(|(index, mount)| {
+                    let origin = ValueOrigin {
+                        table,
+                        name: name.to_string(),
+                        file,
+                        index: Some(index),
+                    };
+                    (mount.clone(), origin)
+                })
+                .collect::<Vec<_>>()
         };
 
-        let check_unique = |field: &str, env: &TomlEnvironment, env_name: &str| -> Result<()> {
-            let mut spans: Vec<LabeledSpan> = Vec::new();
-
-            let is_env_field_preset = match field {
-                "entry_cmd" => !env.entry_cmd.is_empty(),
-                "image" => !env.provided_image.is_empty(),
-                "dockerfile" => !env.dockerfile.is_empty(),
-                "build_context" => !env.build_context.is_empty(),
-                _ => unreachable!("Unknown field {field}"),
-            };
+        let mut own = with_origins(&env.mounts, "environment", name, env.file);
+        for preset_name in &env.resolved_presets {
+            let preset = presets.get(preset_name).unexpected()?;
+            own.extend(with_origins(
+                &preset.mounts,
+                "preset",
+                preset_name,
+                preset.file,
+            ));
+        }
+
+        for (index, (mount, _)) in own.iter().enumerate() {
+            let duplicates: Vec<&ValueOrigin> = own[index..]
+                .iter()
+                .filter(|(other, _)| mount.same_target(other))
+                .map(|(_, origin)| origin)
+                .collect();
+            if duplicates.len() > 1 {
+                return Err(self.duplicate_mounts(&duplicates)?.into());
+            }
+        }
+
+        let inherited = base
+            .map(|base| {
+                base.mounts
+                    .iter()
+                    .cloned()
+                    .zip(base.mount_origins.clone())
+                    .filter(|(mount, _)| !own.iter().any(|(own, _)| own.same_target(mount)))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok([inherited, own].concat().into_iter().unzip())
+    }
+
+    /// The `env` variables of the environment `name`, taking priority over
+    /// those of its presets, with presets listed first taking priority over
+    /// later ones, and all of them over the extended environment's
+    fn merge_env(
+        &self,
+        name: &str,
+        env: &TomlEnvironment,
+        base: Option<&TomlEnvironment>,
+        presets: &TomlPresets,
+    ) -> Result<(BTreeMap<String, String>, BTreeMap<String, ValueOrigin>)> {
+        let mut variables = BTreeMap::new();
+        let mut origins = BTreeMap::new();
+
+        if let Some(base) = base {
+            variables.clone_from(&base.env);
+            origins.clone_from(&base.env_origins);
+        }
+
+        let mut sources = vec![("environment", name, &env.env, env.file)];
+        for preset_name in &env.resolved_presets {
+            let preset = presets.get(preset_name).unexpected()?;
+            sources.push(("preset", preset_name, &preset.env, preset.file));
+        }
+
+        // Lowest priority first, so higher priority values are inserted last
+        for (table, name, env, file) in sources.into_iter().rev() {
+            for (key, value) in env {
+                let origin = ValueOrigin {
+                    table,
+                    name: name.to_string(),
+                    file,
+                    index: None,
+                };
+                variables.insert(key.clone(), value.clone());
+                origins.insert(key.clone(), origin);
+            }
+        }
+
+        Ok((variables, origins))
+    }
+
+    /// Points at the targets of mounts placed at the same path, grouped by
+    /// the file they were defined in
+    fn duplicate_mounts(&self, duplicates: &[&ValueOrigin]) -> Result<ConfigError> {
+        let mut files = Vec::new();
+        for origin in duplicates {
+            if !files.contains(&origin.file) {
+                files.push(origin.file);
+            }
+        }
+
+        let mut errors = Vec::new();
+        for index in files {
+            let mut spans = Vec::new();
+            for (instance, origin) in duplicates.iter().enumerate() {
+                if origin.file == index {
+                    let text = match instance {
+                        0 => "First mounted here",
+                        _ => "Mounted again here",
+                    };
+                    spans.push(LabeledSpan::new_with_span(
+                        Some(text.to_string()),
+                        self.mount_span(origin, "target")?,
+                    ));
+                }
+            }
+            errors.push(ConfigError::DuplicateMountTarget {
+                input: self.files[index].source(),
+                spans,
+                others: Vec::new(),
+            });
+        }
+
+        let mut error = errors.remove(0);
+        if let ConfigError::DuplicateMountTarget { others, .. } = &mut error {
+            *others = errors;
+        }
+        Ok(error)
+    }
+
+    /// The values of the list `field` from the preset `name`, merged with the
+    /// presets it lists, along with where they came from. Presets in `visited`
+    /// have already been merged into the environment so are skipped
+    fn preset_list(
+        &self,
+        presets: &TomlPresets,
+        name: &str,
+        field: &str,
+        visited: &mut Vec<String>,
+    ) -> Result<Vec<(String, ValueOrigin)>> {
+        if visited.iter().any(|preset| preset == name) {
+            return Ok(Vec::new());
+        }
+        visited.push(name.to_string());
+
+        let preset = presets.get(name).unexpected()?;
+        let mut from_presets = Vec::new();
+        for child in &preset.presets {
+            from_presets.extend(self.preset_list(presets, child, field, visited)?);
+        }
+
+        self.merge_list(
+            ("preset", name, preset.file),
+            field,
+            preset.list(field),
+            Vec::new(),
+            from_presets,
+        )
+    }
+
+    /// Combines a list with the values inherited from an extended environment
+    /// and from presets, according to its strategy. `owner` is the table,
+    /// name and file index of the environment or preset defining the list
+    fn merge_list(
+        &self,
+        owner: (&'static str, &str, usize),
+        field: &str,
+        list: &TomlList,
+        from_base: Vec<(String, ValueOrigin)>,
+        from_presets: Vec<(String, ValueOrigin)>,
+    ) -> Result<Vec<(String, ValueOrigin)>> {
+        let (table, name, file) = owner;
+        let own: Vec<(String, ValueOrigin)> = list
+            .values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| {
+                let origin = ValueOrigin {
+                    table,
+                    name: name.to_string(),
+                    file,
+                    index: Some(index),
+                };
+                (value.clone(), origin)
+            })
+            .collect();
+
+        Ok(match list.strategy {
+            ListStrategy::Merge => [from_base, own, from_presets].concat(),
+            ListStrategy::Append => [from_base, from_presets, own].concat(),
+            ListStrategy::Prepend => [own, from_base, from_presets].concat(),
+            ListStrategy::Replace => own,
+            ListStrategy::Remove => {
+                let inherited = [from_base, from_presets].concat();
+                let unmatched: Vec<&String> = list
+                    .values
+                    .iter()
+                    .filter(|value| !inherited.iter().any(|(inherited, _)| inherited == *value))
+                    .collect();
+                if !unmatched.is_empty() {
+                    return Err(self.unmatched_removal(owner, field, &unmatched)?.into());
+                }
+
+                inherited
+                    .into_iter()
+                    .filter(|(value, _)| !list.values.contains(value))
+                    .collect()
+            }
+        })
+    }
 
-            if is_env_field_preset {
-                let span = find_fields_span("environment", env_name, field)?;
-                let text = format!("instance {}", spans.len() + 1);
-                let labeled_span = LabeledSpan::new_with_span(Some(text), span);
-                spans.push(labeled_span);
-            }
-
-            // Using sorted preset names isn't required but helps testings as the
-            // the HashMap doesn't guarantee the order
-            let mut sorted_preset_names: Vec<_> = config.presets.keys().collect();
-            sorted_preset_names.sort();
-
-            for preset_name in sorted_preset_names {
-                let is_preset_field_preset = match field {
-                    "entry_cmd" => !config.presets[preset_name].entry_cmd.is_empty(),
-                    "image" => !config.presets[preset_name].provided_image.is_empty(),
-                    "dockerfile" => !config.presets[preset_name].dockerfile.is_empty(),
-                    "build_context" => !config.presets[preset_name].build_context.is_empty(),
-                    _ => unreachable!("Unknown field {field}"),
-                };
+    fn unmatched_removal(
+        &self,
+        (table, name, file): (&str, &str, usize),
+        field: &str,
+        unmatched: &[&String],
+    ) -> Result<ConfigError> {
+        let file = &self.files[file];
+        let item = file.item(table, name)?.unexpected()?;
+
+        let removals = item
+            .get(field)
+            .and_then(|item| item.get("remove"))
+            .and_then(|item| item.as_array())
+            .unexpected()?;
+        let mut spans = Vec::new();
+        for value in unmatched {
+            let span = removals
+                .iter()
+                .find(|entry| entry.as_str() == Some(value.as_str()))
+                .and_then(|entry| entry.span())
+                .unexpected()?;
+            spans.push(LabeledSpan::new_with_span(
+                Some(format!("Not in the inherited '{field}'")),
+                span,
+            ));
+        }
 
-                if is_preset_field_preset {
-                    let span = find_fields_span("preset", preset_name, field)?;
-                    let text = format!("instance {}", spans.len() + 1);
-                    let labeled_span = LabeledSpan::new_with_span(Some(text), span);
-                    spans.push(labeled_span);
-                }
+        // Point at where the values were expected to be inherited from
+        for (key, text) in [
+            ("presets", "Inherited from these presets"),
+            ("extends", "Inherited from this environment"),
+        ] {
+            if let Some(span) = item.get(key).and_then(|item| item.span()) {
+                spans.push(LabeledSpan::new_with_span(Some(text.to_string()), span));
             }
+        }
 
-            // If we more than 1 span, then we have duplicate fields
-            // if zero, then non are present which is fine for some fields
-            // and is handled later.
-            if spans.len() > 1 {
-                let span = self
-                    .doc
-                    .as_ref()
-                    .unexpected()?
-                    .get("environment")
-                    .and_then(|env| env.as_table())
-                    .and_then(|table| table.get(env_name))
-                    .and_then(|item| item.get("presets"))
-                    .and_then(|item| item.span())
-                    .unexpected()?;
+        Ok(ConfigError::UnmatchedRemoval {
+            input: file.source(),
+            spans,
+        })
+    }
 
-                let labeled_span = LabeledSpan::new_with_span(
-                    Some(format!("Preset(s) causing duplicate '{field}' field")),
-                    span,
-                );
-                spans.push(labeled_span);
-
-                return Err(ConfigError::DuplicateFieldsFromPresets {
-                    input: NamedSource::new(
-                        self.app.config_path.to_str().unwrap(),
-                        self.content.to_string(),
-                    ),
-                    spans,
-                }
-                .into());
+    /// Fills in the single value fields `env` doesn't set from `base`
+    fn inherit(&self, env: &mut TomlEnvironment, base: &TomlEnvironment) {
+        // Paths are relative to the base's file rather than the environment's
+        let env_file = env.file;
+        let base_path = |path: &str| -> String {
+            if path.is_empty() || base.file == env_file {
+                path.to_string()
+            } else {
+                let file = &self.files[base.file];
+                file.resolve_path(path).display().to_string()
             }
-            Ok(())
         };
 
-        let unique_fields = ["entry_cmd", "image", "dockerfile", "build_context"];
-        for (env_name, env) in &config.environments {
-            for field in unique_fields {
-                check_unique(field, env, env_name)?;
-            }
+        let mut inherited = Vec::new();
+
+        if env.entry_cmd.is_empty() {
+            env.entry_cmd = base.entry_cmd.clone();
+            inherited.push("entry_cmd");
         }
-        Ok(config)
-    }
 
-    fn merge_presets(&self, mut config: TomlConfiguration) -> Result<TomlEnvs> {
-        for (_, env) in config.environments.iter_mut() {
-            for preset_name in env.presets.iter_mut() {
-                let preset = config.presets.get(preset_name).unexpected()?;
+        // The image and dockerfile are alternatives, so setting either
+        // overrides both, along with the dockerfile's build context
+        if env.provided_image.is_empty() && env.dockerfile.is_empty() {
+            env.provided_image = base.provided_image.clone();
+            env.dockerfile = base_path(&base.dockerfile);
+            inherited.extend(["image", "dockerfile"]);
 
-                if !preset.entry_cmd.is_empty() {
-                    env.entry_cmd = preset.entry_cmd.clone();
-                }
+            if env.build_context.is_empty() {
+                env.build_context = base_path(&base.build_context);
+                inherited.push("build_context");
+            }
+        }
 
-                if !preset.provided_image.is_empty() {
-                    env.provided_image = preset.provided_image.clone();
-                }
+        if env.workspace.is_empty() {
+            env.workspace = base.workspace.clone();
+            inherited.push("workspace");
+        }
 
-                if !preset.dockerfile.is_empty() {
-                    env.dockerfile = preset.dockerfile.clone();
-                }
+        if env.workspace_root.is_empty() {
+            env.workspace_root = base.workspace_root.clone();
+            inherited.push("workspace_root");
+        }
 
-                env.entry_options.extend_from_slice(&preset.entry_options);
-                env.exec_cmds.extend_from_slice(&preset.exec_cmds);
-                env.exec_options.extend_from_slice(&preset.exec_options);
-                env.create_options.extend_from_slice(&preset.create_options);
-                env.cp_cmds.extend_from_slice(&preset.cp_cmds);
-            }
+        if env.env_file.is_empty() {
+            env.env_file = base_path(&base.env_file);
+            inherited.push("env_file");
         }
 
-        Ok(config.environments)
+        for field in inherited {
+            if let Some(origin) = base.origins.get(field) {
+                env.origins.insert(field, origin.clone());
+            }
+        }
     }
 
     fn validate_environments(&self, envs: TomlEnvs) -> Result<TomlEnvs> {
-        let get_span = move |env_name: &str| -> Result<Range<usize>> {
-            self.doc
-                .as_ref()
-                .unexpected()?
-                .get("environment")
-                .and_then(|env| env.as_table())
-                .and_then(|table| table.get(env_name))
+        let get_span = move |file: &ConfigFile, env_name: &str| -> Result<Range<usize>> {
+            file.item("environment", env_name)?
                 .and_then(|item| item.span())
                 .unexpected()
         };
 
-        for (name, env) in &envs {
+        let mut names: Vec<&String> = envs.keys().collect();
+        names.sort();
+
+        let mut problems = Problems::default();
+        for name in names {
+            let env = &envs[name];
+            let file = &self.files[env.file];
+            let mut messages = Vec::new();
+
             if env.entry_cmd.is_empty() {
-                return Err(labeled_error!(
-                    self,
-                    EnvironmentValidation,
-                    get_span(name)?,
-                    "An environment requires a 'entry_cmd' field"
-                )
-                .into());
+                messages.push("An environment requires a 'entry_cmd' field");
             }
 
             match (env.provided_image.is_empty(), env.dockerfile.is_empty()) {
                 (true, true) => {
-                    return Err(labeled_error!(
-                        self,
-                        EnvironmentValidation,
-                        get_span(name)?,
-                        "An environment requires an 'image' or 'dockerfile' field"
-                    )
-                    .into())
+                    messages.push("An environment requires an 'image' or 'dockerfile' field")
                 }
                 (false, false) => {
-                    return Err(labeled_error!(
-                        self,
-                        EnvironmentValidation,
-                        get_span(name)?,
-                        "An environment can only have an 'image' or 'dockerfile' field"
-                    )
-                    .into())
+                    messages.push("An environment can only have an 'image' or 'dockerfile' field")
                 }
-
                 _ => (),
             }
 
             if !env.build_context.is_empty() && env.dockerfile.is_empty() {
-                return Err(labeled_error!(
-                    self,
+                messages.push("'build_context' can only be used with a 'dockerfile'");
+            }
+
+            for message in messages {
+                problems.record::<()>(Err(labeled_error!(
+                    file,
                     EnvironmentValidation,
-                    get_span(name)?,
-                    "'build_context' can only be used with a 'dockerfile'"
+                    get_span(file, name)?,
+                    message
                 )
-                .into());
+                .into()));
             }
         }
 
-        Ok(envs)
+        problems.finish(envs)
     }
 
-    fn create_environment(&self, mut envs: TomlEnvs) -> Result<Environment> {
-        let name = self.app.environment.clone();
+    /// Finds the line `field`, or an element of it, was defined on
+    fn origin(&self, field: &str, origin: &ValueOrigin) -> Result<Origin> {
+        let span = self.value_span(field, origin)?;
+        Ok(self.line_origin(origin, span))
+    }
 
-        let mut env = match envs.remove(&name) {
-            Some(env) => env,
-            None => {
-                return Err(labeled_error!(
-                    self,
-                    EnvironmentSearch,
-                    (0, self.content.len()),
-                    format!("Failed to find provided environment '{}' in config", &name)
-                )
-                .into())
+    /// Finds the line the variable `key` of the `env` table was defined on
+    fn env_origin(&self, key: &str, origin: &ValueOrigin) -> Result<Origin> {
+        let span = self.files[origin.file]
+            .item(origin.table, &origin.name)?
+            .and_then(|item| item.get("env"))
+            .and_then(|env| env.get(key))
+            .and_then(|value| value.span())
+            .unexpected()?;
+        Ok(self.line_origin(origin, span))
+    }
+
+    /// The span of the name of the variable `key` of the `env` table
+    fn env_key_span(&self, key: &str, origin: &ValueOrigin) -> Result<Range<usize>> {
+        self.files[origin.file]
+            .item(origin.table, &origin.name)?
+            .and_then(|item| item.get("env"))
+            .and_then(|env| env.as_table_like())
+            .and_then(|env| env.get_key_value(key))
+            .and_then(|(key, _)| key.span())
+            .unexpected()
+    }
+
+    fn line_origin(&self, origin: &ValueOrigin, span: Range<usize>) -> Origin {
+        let file = &self.files[origin.file];
+        Origin {
+            source: format!("{} '{}'", origin.table, origin.name),
+            path: file.path.clone(),
+            line: file.content[..span.start].matches('\n').count() + 1,
+        }
+    }
+
+    /// The span of the value of `field`, or an element of it, in the file it
+    /// was defined in
+    fn value_span(&self, field: &str, origin: &ValueOrigin) -> Result<Range<usize>> {
+        let file = &self.files[origin.file];
+        let item = file.item(origin.table, &origin.name)?.unexpected()?;
+
+        match origin.index {
+            None => item.get(field).and_then(|value| value.span()),
+            Some(index) => {
+                let list = item.get(field).unexpected()?;
+                if let Some(tables) = list.as_array_of_tables() {
+                    return tables
+                        .get(index)
+                        .and_then(|table| table.span())
+                        .unexpected();
+                }
+                // Either an array or a table holding a single merge strategy
+                let array = match list.as_array() {
+                    Some(array) => Some(array),
+                    None => list
+                        .as_table_like()
+                        .and_then(|table| table.iter().next())
+                        .and_then(|(_, item)| item.as_array()),
+                };
+                array
+                    .and_then(|array| array.get(index))
+                    .and_then(|value| value.span())
+            }
+        }
+        .unexpected()
+    }
+
+    /// The span of the `key` field of the mount `origin` points to, or the
+    /// whole mount if it doesn't have the field
+    fn mount_span(&self, origin: &ValueOrigin, key: &str) -> Result<Range<usize>> {
+        let file = &self.files[origin.file];
+        let index = origin.index.unexpected()?;
+        let mounts = file
+            .item(origin.table, &origin.name)?
+            .and_then(|item| item.get("mounts"))
+            .unexpected()?;
+
+        // Either an array of inline tables or an array of tables
+        match mounts.as_array_of_tables() {
+            Some(tables) => tables.get(index).and_then(|table| {
+                table
+                    .get(key)
+                    .and_then(|item| item.span())
+                    .or_else(|| table.span())
+            }),
+            None => mounts
+                .as_array()
+                .and_then(|array| array.get(index))
+                .and_then(|value| {
+                    value
+                        .as_inline_table()
+                        .and_then(|table| table.get(key))
+                        .and_then(|item| item.span())
+                        .or_else(|| value.span())
+                }),
+        }
+        .unexpected()
+    }
+
+    /// Checks each mount can be made, resolving the sources of bind mounts
+    /// from the file they were defined in
+    fn resolve_mounts(&self, env: &TomlEnvironment) -> Result<Vec<Mount>> {
+        let mut mounts = Vec::new();
+        let mut problems = Problems::default();
+        for (mount, origin) in env.mounts.iter().zip(&env.mount_origins) {
+            mounts.extend(problems.record(self.resolve_mount(mount, origin)));
+        }
+        problems.finish(mounts)
+    }
+
+    /// Checks a mount, making the source of a bind mount absolute
+    fn resolve_mount(&self, mount: &Mount, origin: &ValueOrigin) -> Result<Mount> {
+        let file = &self.files[origin.file];
+        let error = |key: &str, msg: &str| -> Result<Report> {
+            Ok(labeled_error!(file, InvalidMount, self.mount_span(origin, key)?, msg).into())
+        };
+
+        if !mount.target.starts_with('/') {
+            return Err(error("target", "The target must be an absolute path")?);
+        }
+
+        let mut mount = mount.clone();
+        match (mount.kind, &mount.source) {
+            (MountType::Bind, None) => {
+                return Err(error("source", "A bind mount requires a 'source'")?)
+            }
+            (MountType::Bind, Some(source)) => {
+                // The engine only accepts absolute sources
+                let resolved = file.absolute_path(source)?;
+                if !resolved.exists() {
+                    return Err(error("source", "Could not find the source on the host")?);
+                }
+                mount.source = Some(resolved.display().to_string());
+            }
+            (MountType::Tmpfs, Some(_)) => {
+                return Err(error("source", "A tmpfs mount can't have a 'source'")?)
+            }
+            _ => (),
+        }
+        Ok(mount)
+    }
+
+    /// Finds the host directory mounted at the `workspace`, from the
+    /// directory berth is run from
+    fn resolve_workspace(&self, env: &TomlEnvironment) -> Result<Option<Workspace>> {
+        let error = |field: &str, origin: &ValueOrigin, msg: &str| -> Result<Report> {
+            Ok(labeled_error!(
+                &self.files[origin.file],
+                EnvironmentValidation,
+                self.value_span(field, origin)?,
+                msg
+            )
+            .into())
+        };
+
+        let Some(origin) = env.origins.get("workspace") else {
+            return match env.origins.get("workspace_root") {
+                Some(origin) => Err(error(
+                    "workspace_root",
+                    origin,
+                    "Only used along with a workspace",
+                )?),
+                None => Ok(None),
+            };
+        };
+
+        if !env.workspace.starts_with('/') {
+            return Err(error(
+                "workspace",
+                origin,
+                "The workspace must be an absolute path",
+            )?);
+        }
+
+        let target = Mount {
+            source: None,
+            target: env.workspace.clone(),
+            kind: MountType::Bind,
+            readonly: false,
+        };
+        if env.mounts.iter().any(|mount| mount.same_target(&target)) {
+            return Err(error(
+                "workspace",
+                origin,
+                "A mount already uses the workspace's path",
+            )?);
+        }
+
+        let root = match env.workspace_root.as_str() {
+            "" | "cwd" => WorkspaceRoot::Cwd,
+            "git" => WorkspaceRoot::Git,
+            _ => {
+                let origin = env.origins.get("workspace_root").unexpected()?;
+                return Err(error(
+                    "workspace_root",
+                    origin,
+                    "Expected either 'cwd' or 'git'",
+                )?);
             }
         };
 
+        let current_dir = std::env::current_dir().unexpected()?;
+        let source = match root {
+            WorkspaceRoot::Cwd => current_dir.clone(),
+            WorkspaceRoot::Git => current_dir
+                .ancestors()
+                .find(|dir| dir.join(".git").exists())
+                .unwrap_or(&current_dir)
+                .to_path_buf(),
+        };
+
+        let relative = current_dir.strip_prefix(&source).unexpected()?;
+        let workdir = match relative.as_os_str().is_empty() {
+            true => env.workspace.clone(),
+            false => Path::new(&env.workspace)
+                .join(relative)
+                .display()
+                .to_string(),
+        };
+
+        Ok(Some(Workspace {
+            target: env.workspace.clone(),
+            root,
+            source,
+            workdir,
+        }))
+    }
+
+    /// Checks every command and option can be split into words the way a
+    /// shell would, as they are when the environment is used
+    fn validate_commands(&self, env: &TomlEnvironment) -> Result<()> {
+        let mut values = Vec::new();
+        if let Some(origin) = env.origins.get("entry_cmd") {
+            values.push(("entry_cmd", env.entry_cmd.as_str(), origin));
+        }
+        for field in LIST_FIELDS {
+            let list = env.list(field);
+            values.extend(
+                list.values
+                    .iter()
+                    .zip(&list.origins)
+                    .map(|(value, origin)| (field, value.as_str(), origin)),
+            );
+        }
+
+        let mut problems = Problems::default();
+        for (field, value, origin) in values {
+            if let Err(error) = shell_words::split(value) {
+                problems.record::<()>(Err(labeled_error!(
+                    &self.files[origin.file],
+                    InvalidCommand,
+                    self.value_span(field, origin)?,
+                    format!("Could not be split into words, {error}")
+                )
+                .into()));
+            }
+        }
+        problems.finish(())
+    }
+
+    fn create_environment(&self, name: &str, mut env: TomlEnvironment) -> Result<Environment> {
         let mut options = ExpandOptions::new();
         options.expansion_type = Some(ExpansionType::Unix);
 
         [
-            &mut env.entry_options,
-            &mut env.exec_options,
-            &mut env.create_options,
+            &mut env.entry_options.values,
+            &mut env.exec_options.values,
+            &mut env.create_options.values,
         ]
         .iter_mut()
         .for_each(|vec| {
             vec.iter_mut()
                 .for_each(|s| *s = envmnt::expand(s, Some(options)))
         });
+        env.env
+            .values_mut()
+            .for_each(|s| *s = envmnt::expand(s, Some(options)));
+
+        // Every check is run so all of the environment's problems are
+        // reported together
+        let mut problems = Problems::default();
+        problems.record(self.validate_commands(&env));
+        problems.record(self.validate_env_keys(&env));
+        let mounts = problems.record(self.resolve_mounts(&env));
+        let workspace = problems.record(self.resolve_workspace(&env));
+        let env_file = problems.record(self.validate_env_file(&env));
+        let image = problems.record(self.resolve_image(name, &env));
+        let (mounts, workspace, env_file, image) =
+            problems.finish((mounts, workspace, env_file, image))?;
+        let (mounts, workspace, env_file) = (
+            mounts.unexpected()?,
+            workspace.unexpected()?,
+            env_file.unexpected()?,
+        );
+        let (env_file, env_file_hash) = env_file.unzip();
+        let ResolvedImage {
+            image,
+            dockerfile,
+            build_context,
+            dockerfile_hash,
+        } = image.unexpected()?;
 
-        let (image, dockerfile, build_context) = match env.provided_image.as_str() {
-            "" => {
-                let dockerfile_path = self.validate_dockerfile(&env.dockerfile, &name)?;
-                let build_context = self.validate_build_context(&env.build_context, &name)?;
-                let image_name = Self::generate_image_name(&name, &dockerfile_path)?;
-                (image_name, Some(dockerfile_path), build_context)
-            }
-            _ => (env.provided_image, None, None),
-        };
+        let mut origins = HashMap::new();
+        for (field, origin) in &env.origins {
+            origins.insert(field.to_string(), vec![self.origin(field, origin)?]);
+        }
+        for field in LIST_FIELDS {
+            let list_origins = env
+                .list(field)
+                .origins
+                .iter()
+                .map(|origin| self.origin(field, origin))
+                .collect::<Result<_>>()?;
+            origins.insert(field.to_string(), list_origins);
+        }
+        let mount_origins = env
+            .mount_origins
+            .iter()
+            .map(|origin| self.origin("mounts", origin))
+            .collect::<Result<_>>()?;
+        origins.insert("mounts".to_string(), mount_origins);
+        let env_origins = env
+            .env_origins
+            .iter()
+            .map(|(key, origin)| self.env_origin(key, origin))
+            .collect::<Result<_>>()?;
+        origins.insert("env".to_string(), env_origins);
 
         let mut env = Environment {
             name: name.to_string(),
+            config_path: self.files[env.file].path.clone(),
             original_name: name.to_string(),
+            hash: String::new(),
             image,
+            dockerfile_hash,
             dockerfile,
             build_context,
             entry_cmd: env.entry_cmd,
-            entry_options: env.entry_options,
-            exec_cmds: env.exec_cmds,
-            exec_options: env.exec_options,
-            create_options: env.create_options,
-            cp_cmds: env.cp_cmds,
+            entry_options: env.entry_options.values,
+            exec_cmds: env.exec_cmds.values,
+            exec_options: env.exec_options.values,
+            create_options: env.create_options.values,
+            cp_cmds: env.cp_cmds.values,
+            on_create: env.on_create.values,
+            on_start: env.on_start.values,
+            on_enter: env.on_enter.values,
+            on_stop: env.on_stop.values,
+            host_pre_create: env.host_pre_create.values,
+            host_post_create: env.host_post_create.values,
+            host_post_exit: env.host_post_exit.values,
+            mounts,
+            workspace,
+            env: env.env,
+            env_file,
+            env_file_hash,
+            pass_env: env.pass_env.values,
+            origins,
         };
 
-        let mut hasher = DefaultHasher::new();
-        env.hash(&mut hasher);
-        env.name = format!("{}-{}-{:016x}", "berth", &name, hasher.finish());
+        env.hash = env.config_hash();
+        env.name = format!("{}-{}-{}", "berth", name, env.hash);
 
         Ok(env)
     }
 
-    fn validate_dockerfile(&self, dockerfile: &str, env_name: &str) -> Result<PathBuf> {
-        let mut options = ExpandOptions::new();
-        options.expansion_type = Some(ExpansionType::Unix);
-
-        let dockerfile = envmnt::expand(dockerfile, Some(options));
+    /// The image of the environment, built from its dockerfile if it has one
+    fn resolve_image(&self, name: &str, env: &TomlEnvironment) -> Result<ResolvedImage> {
+        if !env.provided_image.is_empty() {
+            return Ok(ResolvedImage {
+                image: env.provided_image.clone(),
+                dockerfile: None,
+                build_context: None,
+                dockerfile_hash: None,
+            });
+        }
 
-        let path = Path::new(&dockerfile);
+        let mut problems = Problems::default();
+        let dockerfile_path = problems.record(self.validate_dockerfile(&env.dockerfile, env, name));
+        let build_context = problems.record(self.validate_build_context(&env.build_context, env));
+        let (dockerfile_path, build_context) = problems.finish((dockerfile_path, build_context))?;
+        let (dockerfile_path, build_context) =
+            (dockerfile_path.unexpected()?, build_context.unexpected()?);
+
+        let dockerfile_hash = Self::hash_dockerfile(&dockerfile_path)?;
+        Ok(ResolvedImage {
+            image: format!("berth-{}-{}", name.to_lowercase(), dockerfile_hash),
+            dockerfile: Some(dockerfile_path),
+            build_context,
+            dockerfile_hash: Some(dockerfile_hash),
+        })
+    }
 
-        let resolved = if path.is_absolute() {
-            path.to_path_buf()
-        } else {
-            self.app
-                .config_path
-                .parent()
-                .ok_or_else(|| {
-                    ConfigError::FailedToInteractWithDockerfile(path.display().to_string())
-                })?
-                .join(path)
-        };
+    fn validate_dockerfile(
+        &self,
+        dockerfile: &str,
+        env: &TomlEnvironment,
+        env_name: &str,
+    ) -> Result<PathBuf> {
+        let file = &self.files[env.file];
+        let resolved = file.resolve_path(dockerfile);
 
         if !resolved.exists() || !resolved.is_file() {
-            let span = self
-                .doc
-                .as_ref()
-                .unexpected()?
-                .get("environment")
-                .and_then(|env| env.as_table())
-                .and_then(|envs| envs.get(env_name))
-                .and_then(|env| env.get("dockerfile"))
+            // The dockerfile may have come from a preset
+            let span = file
+                .item("environment", env_name)?
+                .map(|env| env.get("dockerfile").unwrap_or(env))
                 .and_then(|item| item.span())
                 .unexpected()?;
 
             return Err(labeled_error!(
-                self,
+                file,
                 InvalidDockerfilePath,
                 span,
                 "Could not find dockerfile"
@@ -561,50 +2382,67 @@ impl Configuration {
         Ok(resolved)
     }
 
+    /// The absolute path of the env file, along with the hash of its content
+    fn validate_env_file(&self, env: &TomlEnvironment) -> Result<Option<(PathBuf, String)>> {
+        let Some(origin) = env.origins.get("env_file") else {
+            return Ok(None);
+        };
+
+        // Absolute, as the file is read again when the container is created
+        let resolved = self.files[env.file].absolute_path(&env.env_file)?;
+        let Ok(content) = fs::read(&resolved) else {
+            return Err(labeled_error!(
+                &self.files[origin.file],
+                EnvironmentValidation,
+                self.value_span("env_file", origin)?,
+                "Could not find the env file"
+            )
+            .into());
+        };
+
+        Ok(Some((
+            resolved,
+            format!("{:016x}", Sha256::digest(content)),
+        )))
+    }
+
+    /// Rejects `env` variables whose names Docker can't pass to the container
+    fn validate_env_keys(&self, env: &TomlEnvironment) -> Result<()> {
+        let mut problems = Problems::default();
+        for (key, origin) in &env.env_origins {
+            if !key.is_empty() && !key.contains('=') {
+                continue;
+            }
+            problems.record::<()>(Err(labeled_error!(
+                &self.files[origin.file],
+                EnvironmentValidation,
+                self.env_key_span(key, origin)?,
+                "Variable names can't be empty or contain '='"
+            )
+            .into()));
+        }
+        problems.finish(())
+    }
+
     fn validate_build_context(
         &self,
         build_context_dir: &str,
-        env_name: &str,
+        env: &TomlEnvironment,
     ) -> Result<Option<PathBuf>> {
         if build_context_dir.is_empty() {
             return Ok(None);
         }
 
-        let mut options = ExpandOptions::new();
-        options.expansion_type = Some(ExpansionType::Unix);
-
-        let dockerfile = envmnt::expand(build_context_dir, Some(options));
-
-        let path = Path::new(&dockerfile);
-
-        let resolved = if path.is_absolute() {
-            path.to_path_buf()
-        } else {
-            self.app
-                .config_path
-                .parent()
-                .ok_or_else(|| {
-                    ConfigError::FailedToInteractWithDockerfile(path.display().to_string())
-                })?
-                .join(path)
-        };
+        let resolved = self.files[env.file].resolve_path(build_context_dir);
 
         if !resolved.exists() || !resolved.is_dir() {
-            let span = self
-                .doc
-                .as_ref()
-                .unexpected()?
-                .get("environment")
-                .and_then(|env| env.as_table())
-                .and_then(|envs| envs.get(env_name))
-                .and_then(|env| env.get("build_context"))
-                .and_then(|item| item.span())
-                .unexpected()?;
+            // The build context may have come from a preset
+            let origin = env.origins.get("build_context").unexpected()?;
 
             return Err(labeled_error!(
-                self,
+                &self.files[origin.file],
                 InvalidDockerfilePath,
-                span,
+                self.value_span("build_context", origin)?,
                 "Could not find build context / Build context must be a directory"
             )
             .into());
@@ -613,7 +2451,7 @@ impl Configuration {
         Ok(Some(resolved))
     }
 
-    fn generate_image_name(name: &str, path: &Path) -> Result<String> {
+    fn hash_dockerfile(path: &Path) -> Result<String> {
         let create_error = |path: &Path| -> miette::Report {
             ConfigError::FailedToInteractWithDockerfile(path.display().to_string()).into()
         };
@@ -631,28 +2469,206 @@ impl Configuration {
             hasher.update(&buffer[..bytes_read]);
         }
 
-        Ok(format!(
-            "{}-{}-{:016x}",
-            "berth",
-            name.to_lowercase(),
-            hasher.finalize()
-        ))
+        Ok(format!("{:016x}", hasher.finalize()))
     }
 }
 
 impl Environment {
+    /// The absolute path of the directory the environment's config file is
+    /// in, which relative paths are resolved against
+    pub fn config_dir(&self) -> Result<PathBuf> {
+        let mut config_dir = std::path::absolute(&self.config_path).unexpected()?;
+        config_dir.pop();
+        Ok(config_dir)
+    }
+
+    /// The absolute directory of the config file each value of the list
+    /// `field` was defined in, which relative paths in the value are resolved
+    /// against, as presets and extended environments may be in other files
+    pub fn value_dirs(&self, field: &str) -> Result<Vec<PathBuf>> {
+        self.origins
+            .get(field)
+            .into_iter()
+            .flatten()
+            .map(|origin| {
+                let mut dir = std::path::absolute(&origin.path).unexpected()?;
+                dir.pop();
+                Ok(dir)
+            })
+            .collect()
+    }
+
+    /// The environment as it would be resolved with its `workspace` mounted
+    /// from another host directory, which names that directory's container
+    pub fn with_workspace_source(&self, source: &Path) -> Environment {
+        let mut env = self.clone();
+        if let Some(workspace) = &mut env.workspace {
+            workspace.source = source.to_path_buf();
+            workspace.workdir = workspace.target.clone();
+            env.hash = env.config_hash();
+            env.name = format!("berth-{}-{}", env.original_name, env.hash);
+        }
+        env
+    }
+
+    /// Hashes the configuration fields of the environment with SHA-256.
+    ///
+    /// Each non-empty field is fed to the hasher as its key followed by its
+    /// values, with every key, value and list prefixed by its length as a
+    /// little-endian `u64`. Empty fields are skipped so adding new optional
+    /// fields doesn't change the hash of existing environments. The result is
+    /// the first 16 hex characters of the digest prefixed with
+    /// `CONFIG_HASH_VERSION` and a `.`, e.g. `v1.7ccaf108b441c52f`. The
+    /// host commands are left out as they don't change the container, while
+    /// the env file is hashed by its content as well as its path.
+    fn config_hash(&self) -> String {
+        fn strs(list: &[String]) -> Vec<&str> {
+            list.iter().map(String::as_str).collect()
+        }
+
+        fn path(path: &Option<PathBuf>) -> Vec<&str> {
+            path.iter().filter_map(|p| p.to_str()).collect()
+        }
+
+        let env_file: Vec<&str> = path(&self.env_file)
+            .into_iter()
+            .chain(self.env_file_hash.as_deref())
+            .collect();
+
+        let mounts: Vec<String> = self.mounts.iter().map(Mount::to_string).collect();
+        let env: Vec<String> = self
+            .env
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect();
+        // The host directory is included so each directory, or repository,
+        // has its own container
+        let workspace: Vec<&str> = match &self.workspace {
+            Some(workspace) => [Some(workspace.target.as_str()), workspace.source.to_str()]
+                .into_iter()
+                .flatten()
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let fields: [(&str, Vec<&str>); 19] = [
+            ("name", vec![&self.original_name]),
+            ("image", vec![&self.image]),
+            ("dockerfile", path(&self.dockerfile)),
+            ("build_context", path(&self.build_context)),
+            ("entry_cmd", vec![&self.entry_cmd]),
+            ("entry_options", strs(&self.entry_options)),
+            ("exec_cmds", strs(&self.exec_cmds)),
+            ("exec_options", strs(&self.exec_options)),
+            ("create_options", strs(&self.create_options)),
+            ("cp_cmds", strs(&self.cp_cmds)),
+            ("on_create", strs(&self.on_create)),
+            ("on_start", strs(&self.on_start)),
+            ("on_enter", strs(&self.on_enter)),
+            ("on_stop", strs(&self.on_stop)),
+            ("mounts", strs(&mounts)),
+            ("workspace", workspace),
+            ("env", strs(&env)),
+            ("env_file", env_file),
+            ("pass_env", strs(&self.pass_env)),
+        ];
+
+        let mut hasher = Sha256::new();
+
+        for (key, values) in fields {
+            if values.iter().all(|value| value.is_empty()) {
+                continue;
+            }
+
+            hasher.update((key.len() as u64).to_le_bytes());
+            hasher.update(key);
+            hasher.update((values.len() as u64).to_le_bytes());
+            for value in values {
+                hasher.update((value.len() as u64).to_le_bytes());
+                hasher.update(value);
+            }
+        }
+
+        let digest = format!("{:x}", hasher.finalize());
+        format!("{}.{}", CONFIG_HASH_VERSION, &digest[..16])
+    }
+
+    /// The container name berth used before the configuration hash was
+    /// versioned, which was derived from Rust's `DefaultHasher`. Its output
+    /// isn't stable across Rust releases, so this only finds containers
+    /// created by a berth built with the same toolchain as this one.
+    pub fn legacy_name(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        (
+            &self.original_name,
+            &self.original_name,
+            &self.image,
+            &self.dockerfile,
+            &self.build_context,
+            &self.entry_cmd,
+            &self.entry_options,
+            &self.exec_cmds,
+            &self.exec_options,
+            &self.create_options,
+            &self.cp_cmds,
+        )
+            .hash(&mut hasher);
+        format!("berth-{}-{:016x}", self.original_name, hasher.finish())
+    }
+
+    /// The environment's definition after it has been parsed, which can be
+    /// parsed back into an equivalent environment
     pub fn view(&self) -> Result<String> {
+        self.definition(false)
+    }
+
+    /// The view along with the values berth computes from it: the container
+    /// name, the image name and every path made absolute
+    pub fn resolved_view(&self) -> Result<String> {
+        self.definition(true)
+    }
+
+    fn definition(&self, resolved: bool) -> Result<String> {
         use toml_edit::{value, Array, DocumentMut, Item};
 
         let mut doc = DocumentMut::new();
         let mut table = toml_edit::Table::new();
 
-        if !self.image.is_empty() && self.dockerfile.is_none() {
+        let config_dir = self.config_dir()?;
+
+        if resolved {
+            table.insert("name", value(self.name.clone()));
+        }
+
+        if !self.image.is_empty() && (resolved || self.dockerfile.is_none()) {
             table.insert("image", value(self.image.clone()));
         }
 
+        // Paths are relative to the directory berth is run from when the
+        // config file was given by a relative path, so they're made absolute
+        // for the definition to be read from any file
+        let display = |path: &Path| -> Result<String> {
+            Ok(match resolved {
+                true => std::path::absolute(path).unexpected()?,
+                false => std::env::current_dir().unexpected()?.join(path),
+            }
+            .display()
+            .to_string())
+        };
+
         if let Some(path) = &self.dockerfile {
-            table.insert("dockerfile", value(path.display().to_string()));
+            table.insert("dockerfile", value(display(path)?));
+        }
+
+        let build_context = match (&self.build_context, &self.dockerfile) {
+            (Some(path), _) => Some(path.as_path()),
+            // Images are built with the config file's directory as the
+            // context when one isn't given
+            (None, Some(_)) if resolved => Some(config_dir.as_path()),
+            (None, _) => None,
+        };
+        if let Some(path) = build_context {
+            table.insert("build_context", value(display(path)?));
         }
 
         table.insert("entry_cmd", value(self.entry_cmd.clone()));
@@ -682,6 +2698,74 @@ impl Environment {
             );
         }
 
+        if !self.mounts.is_empty() {
+            let mut mounts = Array::new();
+            for mount in &self.mounts {
+                let mut mount = mount.clone();
+                if let (MountType::Bind, Some(source)) = (mount.kind, &mount.source) {
+                    mount.source = Some(display(Path::new(source))?);
+                }
+                mounts.push(mount.to_toml());
+            }
+            table.insert("mounts", value(mounts));
+        }
+
+        if !self.env.is_empty() {
+            let env: toml_edit::InlineTable = self
+                .env
+                .iter()
+                .map(|(key, variable)| (key.as_str(), variable.as_str()))
+                .collect();
+            table.insert("env", value(env));
+        }
+
+        if let Some(path) = &self.env_file {
+            table.insert("env_file", value(display(path)?));
+        }
+
+        if !self.pass_env.is_empty() {
+            table.insert("pass_env", value(Array::from_iter(self.pass_env.iter())));
+        }
+
+        if let Some(workspace) = &self.workspace {
+            table.insert("workspace", value(workspace.target.clone()));
+            if workspace.root == WorkspaceRoot::Git {
+                table.insert("workspace_root", value("git"));
+            }
+        }
+
+        if !self.cp_cmds.is_empty() {
+            let cp_cmds = match resolved {
+                true => self
+                    .cp_cmds
+                    .iter()
+                    .zip(self.value_dirs("cp_cmds")?)
+                    .map(|(cmd, dir)| Self::resolve_cp_cmd(cmd, &dir))
+                    .collect(),
+                false => self.cp_cmds.clone(),
+            };
+            table.insert("cp_cmds", value(Array::from_iter(cp_cmds.iter())));
+        }
+
+        let hooks = [
+            ("on_create", &self.on_create),
+            ("on_start", &self.on_start),
+            ("on_enter", &self.on_enter),
+            ("on_stop", &self.on_stop),
+            ("host_pre_create", &self.host_pre_create),
+            ("host_post_create", &self.host_post_create),
+            ("host_post_exit", &self.host_post_exit),
+        ];
+        for (field, cmds) in hooks {
+            if !cmds.is_empty() {
+                table.insert(field, value(Array::from_iter(cmds.iter())));
+            }
+        }
+
+        if resolved {
+            table.insert("config_path", value(display(&self.config_path)?));
+        }
+
         let env_table = doc
             .as_table_mut()
             .entry("environment")
@@ -694,4 +2778,123 @@ impl Environment {
 
         Ok(doc.to_string())
     }
+
+    /// Makes the host path of a `cp_cmds` entry absolute, as it's relative to
+    /// the directory of the config file it was defined in
+    fn resolve_cp_cmd(cmd: &str, config_dir: &Path) -> String {
+        let Ok(tokens) = shell_words::split(cmd) else {
+            return cmd.to_string();
+        };
+
+        let tokens: Vec<String> = tokens
+            .into_iter()
+            .map(
+                |token| match token.starts_with('-') || token.starts_with(COPY_CONTAINER_PREFIX) {
+                    true => token,
+                    false => config_dir.join(token).display().to_string(),
+                },
+            )
+            .collect();
+        shell_words::join(tokens)
+    }
+
+    /// The view with a comment on each field, and each element of list
+    /// fields, saying which environment or preset it came from
+    pub fn explain(&self) -> Result<String> {
+        let view = self.view()?;
+        let doc = view.parse::<toml_edit::DocumentMut>().unexpected()?;
+        let (_, environment) = doc
+            .get("environment")
+            .and_then(|item| item.as_table_like())
+            .and_then(|table| table.iter().next())
+            .unexpected()?;
+
+        let comment = |origin: Option<&Origin>| match origin {
+            Some(origin) => format!(
+                "  # {} at {}:{}",
+                origin.source,
+                origin.path.display(),
+                origin.line
+            ),
+            None => String::new(),
+        };
+
+        let mut lines = vec![view.lines().next().unexpected()?.to_string()];
+        for (key, item) in environment.as_table_like().unexpected()?.iter() {
+            let origins = self.origins.get(key);
+            let origin = |index: usize| origins.and_then(|origins| origins.get(index));
+
+            match (item.as_array(), item.as_inline_table()) {
+                (Some(array), _) => {
+                    lines.push(format!("{key} = ["));
+                    for (index, value) in array.iter().enumerate() {
+                        let value = value.to_string();
+                        lines.push(format!("    {},{}", value.trim(), comment(origin(index))));
+                    }
+                    lines.push("]".to_string());
+                }
+                // Written with dotted keys so each value gets its own line
+                (_, Some(table)) => {
+                    for (index, (name, value)) in table.iter().enumerate() {
+                        let value = value.to_string();
+                        lines.push(format!(
+                            "{key}.{name} = {}{}",
+                            value.trim(),
+                            comment(origin(index))
+                        ));
+                    }
+                }
+                _ => {
+                    let value = item.to_string();
+                    lines.push(format!("{key} = {}{}", value.trim(), comment(origin(0))));
+                }
+            }
+        }
+
+        Ok(lines.join("\n") + "\n")
+    }
+
+    /// Compares a definition previously produced by [`Environment::view`]
+    /// against the current one, returning a `-`/`+` line pair for every field
+    /// that was removed, added or changed
+    pub fn diff(&self, previous: &str) -> Result<Vec<String>> {
+        let old = Self::definition_fields(previous)?;
+        let new = Self::definition_fields(&self.view()?)?;
+
+        let mut lines = Vec::new();
+        for (key, new_value) in &new {
+            let old_value = old.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+            if old_value == Some(new_value) {
+                continue;
+            }
+            if let Some(old_value) = old_value {
+                lines.push(format!("- {key} = {old_value}"));
+            }
+            lines.push(format!("+ {key} = {new_value}"));
+        }
+
+        for (key, old_value) in &old {
+            if !new.iter().any(|(k, _)| k == key) {
+                lines.push(format!("- {key} = {old_value}"));
+            }
+        }
+
+        Ok(lines)
+    }
+
+    fn definition_fields(definition: &str) -> Result<Vec<(String, String)>> {
+        let doc = definition.parse::<toml_edit::DocumentMut>().unexpected()?;
+        let (_, environment) = doc
+            .get("environment")
+            .and_then(|item| item.as_table_like())
+            .and_then(|table| table.iter().next())
+            .unexpected()?;
+
+        Ok(environment
+            .as_table_like()
+            .unexpected()?
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string().trim().to_string()))
+            .collect())
+    }
 }
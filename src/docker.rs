@@ -1,23 +1,35 @@
+mod archive;
+mod engine;
+mod options;
+
+pub use engine::{ContainerEngine, Engine, ExecOutput};
+
 use crate::{configuration::Environment, util::Spinner, UnexpectedExt};
 use bollard::{
-    container::{
-        ListContainersOptions, RemoveContainerOptions, StartContainerOptions, StopContainerOptions,
-    },
-    secret::ContainerSummary,
-    Docker,
+    container::Config,
+    image::BuildImageOptions,
+    secret::{ContainerSummary, ImageSummary},
 };
 use log::info;
 use miette::{Diagnostic, Result};
+use serde::Serialize;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt,
     path::{Path, PathBuf},
-    process::{Command, Output},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 #[derive(Debug, thiserror::Error, Diagnostic)]
 pub enum DockerError {
-    #[error("Failed to connect to docker daemon with the following error:\n{0:?}\n")]
-    #[diagnostic(code(cli::daemon), help("Is the Docker daemon running?"))]
+    #[error("Failed to connect to the container engine with the following error:\n{0:?}\n")]
+    #[diagnostic(
+        code(cli::daemon),
+        help(
+            "Is the Docker daemon or Podman socket running? The engine can be chosen with --engine"
+        )
+    )]
     ConnectingToDaemon(bollard::errors::Error),
 
     #[error("Failed to get container information with the following error:\n{0}\n")]
@@ -32,6 +44,48 @@ pub enum DockerError {
     #[diagnostic(code(cli::container::removing), help("Is the Docker daemon running?"))]
     RemovingContainer(bollard::errors::Error),
 
+    #[error("Failed to remove image with the following error:\n{0}\n")]
+    #[diagnostic(code(cli::image::removing), help("Is the Docker daemon running?"))]
+    RemovingImage(bollard::errors::Error),
+
+    #[error("Failed to rename container with the following error:\n{0}\n")]
+    #[diagnostic(code(cli::container::renaming), help("Is the Docker daemon running?"))]
+    RenamingContainer(bollard::errors::Error),
+
+    #[error("Failed to create container with the following error:\n{0}\n")]
+    #[diagnostic(code(cli::container::creating), help("Is the Docker daemon running?"))]
+    CreatingContainer(bollard::errors::Error),
+
+    #[error("Failed to pull image with the following error:\n{0}\n")]
+    #[diagnostic(
+        code(cli::image::pulling),
+        help("Does the image exist and are you logged in to its registry?")
+    )]
+    PullingImage(String),
+
+    #[error("Building the image from the dockerfile failed with the following output:\n\n{0}")]
+    #[diagnostic(code(cli::image::building))]
+    BuildingImage(String),
+
+    #[error("Failed to run command in container with the following error:\n{0}\n")]
+    #[diagnostic(code(cli::container::exec), help("Is the Docker daemon running?"))]
+    ExecutingCommand(bollard::errors::Error),
+
+    #[error("{0}")]
+    #[diagnostic(code(cli::container::copying))]
+    CopyingArchive(bollard::errors::Error),
+
+    #[error("The following copy command failed:\n\n{cmd}\n\n{msg}")]
+    #[diagnostic(code(cli::container::copying))]
+    CopyingFiles { cmd: String, msg: String },
+
+    #[error("Invalid option '{option}': {msg}")]
+    #[diagnostic(
+        code(cli::options::invalid),
+        help("berth translates options for the Docker API itself, see the README for the supported options")
+    )]
+    InvalidOption { option: String, msg: String },
+
     #[error("Failed to start container with the following error:\n{0}\n")]
     #[diagnostic(code(cli::container::starting), help("Is the Docker daemon running?"))]
     StartingContainer(bollard::errors::Error),
@@ -48,13 +102,24 @@ pub enum DockerError {
     #[diagnostic(code(cli::container::command::exitcode))]
     CommandExitCode { cmd: String, stdout: String },
 
-    #[error("The following command failed due to an unknown signal:\n{0}")]
-    #[diagnostic(code(cli::container::command::killed))]
-    CommandKilled(String),
-
     #[error("The following command failed to run:\n{0}")]
     #[diagnostic(code(cli::container::command::failed))]
     CommandFailed(String),
+
+    #[error("The following host command exited with {status}:\n\n{cmd}\n\n{stderr}")]
+    #[diagnostic(code(cli::host::command::exitcode))]
+    HostCommandExitCode {
+        cmd: String,
+        status: String,
+        stderr: String,
+    },
+
+    #[error("The following host command failed to run:\n\n{cmd}\n\n{error}")]
+    #[diagnostic(
+        code(cli::host::command::failed),
+        help("Host commands are run from the config file's directory without a shell")
+    )]
+    HostCommandFailed { cmd: String, error: String },
 }
 
 macro_rules! docker_err {
@@ -62,49 +127,212 @@ macro_rules! docker_err {
         |error| DockerError::$variant(error)
     };
 }
+pub(crate) use docker_err;
+
+/// Errors reported part way through a stream only display their message
+/// when taken out of the error
+fn stream_error_message(error: bollard::errors::Error) -> String {
+    match error {
+        bollard::errors::Error::DockerStreamError { error } => error,
+        error => error.to_string(),
+    }
+}
+
+/// Marks the path in a copy command that is inside the container
+pub(crate) const COPY_CONTAINER_PREFIX: &str = "CONTAINER:";
+
+/// Labels attached to the containers and images created by berth
+pub mod labels {
+    /// Canonical path of the config file the environment was defined in
+    pub const CONFIG_PATH: &str = "berth.config_path";
+    /// Name of the environment as written in the config file
+    pub const ENVIRONMENT: &str = "berth.environment";
+    /// Hash of the environment's configuration, containers only
+    pub const HASH: &str = "berth.hash";
+    /// Version of berth that created the container or image
+    pub const VERSION: &str = "berth.version";
+    /// Hash of the dockerfile the image was built from
+    pub const DOCKERFILE_HASH: &str = "berth.dockerfile_hash";
+    /// Environment definition the container was created from, as shown by
+    /// `berth view`, containers only
+    pub const DEFINITION: &str = "berth.definition";
+    /// Host directory mounted at the environment's `workspace`, containers only
+    pub const WORKSPACE_SOURCE: &str = "berth.workspace_source";
+}
+
+/// Checks an environment's `create_options` and `exec_options` can be
+/// translated into Docker API requests, without contacting the engine
+pub fn check_options(environment: &Environment) -> Result<()> {
+    options::create_config(environment, &environment.value_dirs("create_options")?)?;
+    options::exec_config(
+        &environment.exec_options,
+        &environment.value_dirs("exec_options")?,
+        Vec::new(),
+    )?;
+    Ok(())
+}
+
+fn canonical_config_path(config_path: &Path) -> String {
+    std::fs::canonicalize(config_path)
+        .unwrap_or_else(|_| config_path.to_path_buf())
+        .display()
+        .to_string()
+}
+
+fn has_berth_labels(labels: Option<&HashMap<String, String>>) -> bool {
+    labels.is_some_and(|labels| labels.contains_key(labels::ENVIRONMENT))
+}
+
+fn workspace_source(container: &ContainerSummary) -> Option<&str> {
+    container
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(labels::WORKSPACE_SOURCE))
+        .map(String::as_str)
+}
+
+fn container_names(container: &ContainerSummary) -> impl Iterator<Item = &str> {
+    container
+        .names
+        .iter()
+        .flatten()
+        .map(|name| name.trim_start_matches('/'))
+}
 
-const CONTAINER_ENGINE: &str = "docker";
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContainerStatus {
+    /// No container has been created for the environment
+    Absent,
+    /// The environment's container exists but isn't running
+    Stopped,
+    /// The environment's container is running
+    Running,
+    /// Only containers created from an older version of the environment exist
+    Stale,
+}
+
+impl fmt::Display for ContainerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            ContainerStatus::Absent => "absent",
+            ContainerStatus::Stopped => "stopped",
+            ContainerStatus::Running => "running",
+            ContainerStatus::Stale => "stale",
+        };
+        write!(f, "{status}")
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EnvironmentStatus {
+    pub environment: String,
+    /// Name of the container for the current version of the environment
+    pub container: String,
+    pub image: String,
+    pub status: ContainerStatus,
+    /// Unix timestamp of when the container was created
+    pub created: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Drift {
+    /// No container has been created for the environment
+    Absent,
+    /// The environment's container was created from its current configuration
+    UpToDate { container: String },
+    /// The newest container was created from an older configuration, `diff`
+    /// is `None` if it was created before berth recorded its definition
+    Changed {
+        container: String,
+        diff: Option<Vec<String>>,
+    },
+}
 
 #[derive(Debug)]
 pub struct DockerHandler {
     env: Environment,
-    docker: Docker,
+    engine: Arc<dyn ContainerEngine>,
+    config_path: String,
     config_dir: PathBuf,
 }
 
 impl DockerHandler {
-    pub fn new(environment: Environment, config_path: &Path) -> Result<Self> {
-        let docker =
-            Docker::connect_with_local_defaults().map_err(docker_err!(ConnectingToDaemon))?;
-
-        let mut config_dir = config_path.to_path_buf();
-        config_dir.pop();
-
+    pub fn new(environment: Environment, engine: Arc<dyn ContainerEngine>) -> Result<Self> {
         Ok(DockerHandler {
+            config_path: canonical_config_path(&environment.config_path),
+            // Relative paths in the config are resolved against this, and the
+            // Docker API only accepts absolute paths for bind mounts
+            config_dir: environment.config_dir()?,
             env: environment,
-            docker,
-            config_dir,
+            engine,
         })
     }
 
+    fn labels(&self) -> Vec<(&'static str, String)> {
+        let mut labels = vec![
+            (labels::CONFIG_PATH, self.config_path.clone()),
+            (labels::ENVIRONMENT, self.env.original_name.clone()),
+            (labels::VERSION, env!("CARGO_PKG_VERSION").to_string()),
+        ];
+
+        if let Some(dockerfile_hash) = &self.env.dockerfile_hash {
+            labels.push((labels::DOCKERFILE_HASH, dockerfile_hash.clone()));
+        }
+
+        labels
+    }
+
     fn does_image_need_building(&self) -> bool {
         self.env.dockerfile.is_some()
     }
 
-    fn build_image_from_dockerfile(&self) -> Result<()> {
+    async fn build_image_from_dockerfile(&self) -> Result<()> {
         let spinner = Spinner::new("Building Dockerfile");
 
-        let dockerfile_path = self
+        let dockerfile = self.env.dockerfile.clone().unexpected()?;
+        let build_context = self
             .env
-            .dockerfile
-            .as_ref()
-            .unexpected()?
-            .as_path()
-            .to_string_lossy()
-            .to_string();
-        let args = vec!["build", "-t", &self.env.image, "-f", &dockerfile_path, "."];
-        let build_context = self.env.build_context.as_ref().unwrap_or(&self.config_dir);
-        self.run_docker_command(args, build_context)?;
+            .build_context
+            .clone()
+            .unwrap_or(self.config_dir.clone());
+
+        let (context, dockerfile) = tokio::task::spawn_blocking(move || {
+            archive::build_context(&build_context, &dockerfile)
+        })
+        .await
+        .unexpected()?
+        .map_err(|e| DockerError::BuildingImage(e.to_string()))?;
+
+        let options = BuildImageOptions {
+            dockerfile,
+            t: self.env.image.clone(),
+            labels: self
+                .labels()
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+            rm: true,
+            ..Default::default()
+        };
+
+        self.engine.build_image(options, context).await?;
+
+        spinner.finish_and_clear();
+
+        Ok(())
+    }
+
+    /// Pulls the environment's image if it isn't available locally, as
+    /// creating a container through the Docker API doesn't
+    async fn pull_image_if_missing(&self) -> Result<()> {
+        if self.get_image_info().await?.is_some() {
+            return Ok(());
+        }
+
+        let spinner = Spinner::new("Pulling Image");
+
+        self.engine.pull_image(&self.env.image).await?;
 
         spinner.finish_and_clear();
 
@@ -112,20 +340,35 @@ impl DockerHandler {
     }
 
     pub async fn create_new_environment(&self) -> Result<()> {
+        // Parsed before building or pulling so invalid options are reported straight away
+        let (config, platform) =
+            options::create_config(&self.env, &self.env.value_dirs("create_options")?)?;
+
+        self.run_host_commands("host_pre_create", &self.env.host_pre_create)
+            .await?;
+
         if self.does_image_need_building() {
-            self.build_image_from_dockerfile()?;
+            self.build_image_from_dockerfile().await?;
+        } else {
+            self.pull_image_if_missing().await?;
         }
 
         self.delete_container_if_exists().await?;
 
         let spinner = Spinner::new("Creating Container");
 
-        self.create_container()?;
-        self.start_container().await?;
-        self.copy_commands()?;
-        self.exec_setup_commands()?;
+        self.create_container(config, platform).await?;
+        self.engine.start_container(&self.env.name).await?;
+        self.copy_commands().await?;
+        self.exec_commands(&self.env.exec_cmds).await?;
+        self.exec_commands(&self.env.on_create).await?;
+        self.exec_commands(&self.env.on_start).await?;
 
         spinner.finish_and_clear();
+
+        self.run_host_commands("host_post_create", &self.env.host_post_create)
+            .await?;
+
         Ok(())
     }
 
@@ -137,25 +380,30 @@ impl DockerHandler {
     }
 
     pub async fn enter_environment(&self) -> Result<()> {
-        let mut args = vec!["exec"];
-
-        let options = Self::to_shell(&self.env.entry_options);
-        args.extend(options.iter().map(|s| s.as_str()));
-
-        args.push(&self.env.name);
-
+        // Placed first so the working directory and variables set in
+        // `entry_options` take priority
+        let mut options = match &self.env.workspace {
+            Some(workspace) => vec!["-w".to_string(), workspace.workdir.clone()],
+            None => Vec::new(),
+        };
+        if let Some(path) = &self.env.env_file {
+            options.extend(["--env-file".to_string(), path.display().to_string()]);
+        }
+        for name in &self.env.pass_env {
+            options.extend(["-e".to_string(), name.clone()]);
+        }
+        for (key, value) in &self.env.env {
+            options.extend(["-e".to_string(), format!("{key}={value}")]);
+        }
+        options.extend(Self::to_shell(&self.env.entry_options));
         let init_cmd = shell_words::split(&self.env.entry_cmd).unwrap();
-        args.extend_from_slice(&init_cmd.iter().map(|s| s.as_str()).collect::<Vec<&str>>());
-
-        let command = format!("{CONTAINER_ENGINE} {}", shell_words::join(&args));
 
-        info!("{command}");
+        self.exec_commands(&self.env.on_enter).await?;
 
-        let exit_code = Command::new(CONTAINER_ENGINE)
-            .args(&args)
-            .status()
-            .map_err(|_| DockerError::CommandFailed(command))?
-            .code();
+        let exit_code = self
+            .engine
+            .enter(&self.env.name, &options, &init_cmd)
+            .await?;
 
         let error_str = match exit_code {
             Some(0) => None,
@@ -175,25 +423,177 @@ impl DockerHandler {
             self.stop_container_if_running().await?;
         }
 
+        self.run_host_commands("host_post_exit", &self.env.host_post_exit)
+            .await?;
+
         Ok(())
     }
 
     pub async fn get_container_info(&self) -> Result<Option<ContainerSummary>> {
+        let environment_label = format!("{}={}", labels::ENVIRONMENT, self.env.original_name);
+        let hash_label = format!("{}={}", labels::HASH, self.env.hash);
+
         let mut filters = HashMap::new();
-        filters.insert("name", vec![self.env.name.as_str()]);
-        let options = Some(ListContainersOptions {
-            all: true,
-            filters,
-            ..Default::default()
-        });
+        filters.insert(
+            "label",
+            vec![environment_label.as_str(), hash_label.as_str()],
+        );
 
-        let mut container_list = self
-            .docker
-            .list_containers(options)
-            .await
-            .map_err(docker_err!(ContainerInfo))?;
+        if let Some(container) = self.engine.list_containers(filters).await?.pop() {
+            return Ok(Some(container));
+        }
+
+        // Containers created before berth labelled them, or migrated from
+        // the legacy naming, can only be found by name
+        self.get_container_by_name(&self.env.name).await
+    }
+
+    async fn get_container_by_name(&self, name: &str) -> Result<Option<ContainerSummary>> {
+        let mut filters = HashMap::new();
+        filters.insert("name", vec![name]);
+
+        Ok(self
+            .engine
+            .list_containers(filters)
+            .await?
+            .into_iter()
+            .find(|container| container_names(container).any(|n| n == name)))
+    }
+
+    /// Renames the environment's container if it was created before the
+    /// configuration hash was versioned, so it is found under the current name
+    pub async fn migrate_legacy_container(&self) -> Result<()> {
+        if self.does_environment_exist().await? {
+            return Ok(());
+        }
 
-        Ok(container_list.pop())
+        let legacy_name = self.env.legacy_name();
+        if self.get_container_by_name(&legacy_name).await?.is_some() {
+            info!(
+                "Renaming legacy container {legacy_name} to {}",
+                self.env.name
+            );
+
+            self.engine
+                .rename_container(&legacy_name, &self.env.name)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Finds every container created for this environment from this config
+    /// file, including those created from older versions of its configuration.
+    /// Containers with the `workspace` mounted from another directory belong
+    /// to that directory so are left out.
+    async fn get_environment_containers(&self) -> Result<Vec<ContainerSummary>> {
+        let environment_label = format!("{}={}", labels::ENVIRONMENT, self.env.original_name);
+        let config_label = format!("{}={}", labels::CONFIG_PATH, self.config_path);
+
+        let mut filters = HashMap::new();
+        filters.insert(
+            "label",
+            vec![environment_label.as_str(), config_label.as_str()],
+        );
+        let mut containers = self.engine.list_containers(filters).await?;
+
+        // Containers created before berth labelled them can only be found by name
+        let prefix = format!("berth-{}-", self.env.original_name);
+        let mut filters = HashMap::new();
+        filters.insert("name", vec![prefix.as_str()]);
+
+        // The name filter matches substrings, so only keep containers where the
+        // remainder after the prefix is a hash rather than another environment
+        containers.extend(
+            self.engine
+                .list_containers(filters)
+                .await?
+                .into_iter()
+                .filter(|container| !has_berth_labels(container.labels.as_ref()))
+                .filter(|container| {
+                    container_names(container).any(|name| {
+                        name.strip_prefix(&prefix)
+                            .is_some_and(|hash| !hash.is_empty() && !hash.contains('-'))
+                    })
+                }),
+        );
+
+        if let Some(workspace) = &self.env.workspace {
+            let source = workspace.source.display().to_string();
+            containers.retain(|container| {
+                workspace_source(container)
+                    .is_none_or(|container_source| container_source == source)
+            });
+        }
+
+        Ok(containers)
+    }
+
+    /// The environment's container under its current name, or under the
+    /// legacy name if it hasn't been migrated yet
+    fn find_current<'a>(&self, containers: &'a [ContainerSummary]) -> Option<&'a ContainerSummary> {
+        let legacy_name = self.env.legacy_name();
+        containers.iter().find(|container| {
+            container_names(container).any(|name| name == self.env.name || name == legacy_name)
+        })
+    }
+
+    pub async fn status(&self) -> Result<EnvironmentStatus> {
+        let containers = self.get_environment_containers().await?;
+        let current = self.find_current(&containers);
+        let container = match current {
+            Some(container) => container_names(container).next().unexpected()?.to_string(),
+            None => self.env.name.clone(),
+        };
+
+        let (status, created) = match current {
+            Some(container) if container.state.as_deref() == Some("running") => {
+                (ContainerStatus::Running, container.created)
+            }
+            Some(container) => (ContainerStatus::Stopped, container.created),
+            None if !containers.is_empty() => (
+                ContainerStatus::Stale,
+                containers.iter().filter_map(|c| c.created).max(),
+            ),
+            None => (ContainerStatus::Absent, None),
+        };
+
+        Ok(EnvironmentStatus {
+            environment: self.env.original_name.clone(),
+            container,
+            image: self.env.image.clone(),
+            status,
+            created,
+        })
+    }
+
+    /// Compares the environment's newest container against its current
+    /// configuration
+    pub async fn drift(&self) -> Result<Drift> {
+        let containers = self.get_environment_containers().await?;
+
+        if let Some(container) = self.find_current(&containers) {
+            return Ok(Drift::UpToDate {
+                container: container_names(container).next().unexpected()?.to_string(),
+            });
+        }
+
+        let Some(newest) = containers.iter().max_by_key(|container| container.created) else {
+            return Ok(Drift::Absent);
+        };
+
+        let diff = match newest
+            .labels
+            .as_ref()
+            .and_then(|labels| labels.get(labels::DEFINITION))
+        {
+            Some(definition) => Some(self.env.diff(definition)?),
+            None => None,
+        };
+
+        let container = container_names(newest).next().unexpected()?.to_string();
+
+        Ok(Drift::Changed { container, diff })
     }
 
     pub async fn is_container_running(&self) -> Result<bool> {
@@ -209,118 +609,522 @@ impl DockerHandler {
 
     pub async fn delete_container_if_exists(&self) -> Result<()> {
         if self.does_environment_exist().await? {
-            let option = RemoveContainerOptions {
-                force: true,
-                ..Default::default()
-            };
-
             Spinner::new("Removing Container");
 
-            self.docker
-                .remove_container(&self.env.name, Some(option))
-                .await
-                .map_err(docker_err!(RemovingContainer))?;
+            self.engine.remove_container(&self.env.name).await?;
         }
         Ok(())
     }
 
-    pub async fn start_container(&self) -> Result<()> {
-        self.docker
-            .start_container(&self.env.name, None::<StartContainerOptions<String>>)
-            .await
-            .map_err(docker_err!(StartingContainer))?;
+    pub async fn get_image_info(&self) -> Result<Option<ImageSummary>> {
+        let mut filters = HashMap::new();
+        filters.insert("reference", vec![self.env.image.as_str()]);
+
+        let mut image_list = self.engine.list_images(filters).await?;
+
+        Ok(image_list.pop())
+    }
+
+    pub async fn delete_image_if_built(&self) -> Result<()> {
+        if self.does_image_need_building() && self.get_image_info().await?.is_some() {
+            Spinner::new("Removing Image");
+
+            self.engine.remove_image(&self.env.image).await?;
+        }
         Ok(())
     }
 
-    fn create_container(&self) -> Result<()> {
-        let mut args = vec!["create", "--name", &self.env.name];
+    /// Starts the container and runs the `on_start` hooks, unless it's
+    /// already running
+    pub async fn start_container(&self) -> Result<()> {
+        if self.is_container_running().await? {
+            return Ok(());
+        }
 
-        let options = Self::to_shell(&self.env.create_options);
-        args.extend(options.iter().map(|s| s.as_str()));
+        self.engine.start_container(&self.env.name).await?;
+        self.exec_commands(&self.env.on_start).await
+    }
 
-        args.push(&self.env.image);
-        args.extend_from_slice(&["tail", "-f", "/dev/null"]);
-        self.run_docker_command(args, &self.config_dir)
+    async fn create_container(
+        &self,
+        mut config: Config<String>,
+        platform: Option<String>,
+    ) -> Result<()> {
+        let mut labels = self.labels();
+        labels.push((labels::HASH, self.env.hash.clone()));
+        labels.push((labels::DEFINITION, self.env.view()?));
+        if let Some(workspace) = &self.env.workspace {
+            labels.push((
+                labels::WORKSPACE_SOURCE,
+                workspace.source.display().to_string(),
+            ));
+        }
+        config.labels.get_or_insert_with(HashMap::new).extend(
+            labels
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value)),
+        );
+
+        config.image = Some(self.env.image.clone());
+        config.cmd = Some(
+            ["tail", "-f", "/dev/null"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        );
+
+        self.engine
+            .create_container(&self.env.name, platform, config)
+            .await
     }
 
-    fn exec_setup_commands(&self) -> Result<()> {
-        for cmd in &self.env.exec_cmds {
-            let mut args = vec!["exec"];
+    /// Runs a command in the container, returning its combined stdout and
+    /// stderr. `dirs` holds the directory each option was defined in.
+    async fn exec(
+        &self,
+        cmd: Vec<String>,
+        exec_options: &[String],
+        dirs: &[PathBuf],
+    ) -> Result<String> {
+        let command = shell_words::join(&cmd);
+        info!("exec {command}");
+
+        let mut config = options::exec_config(exec_options, dirs, cmd)?;
+        if let Some(workspace) = &self.env.workspace {
+            config
+                .working_dir
+                .get_or_insert_with(|| workspace.workdir.clone());
+        }
 
-            let options = Self::to_shell(&self.env.exec_options);
-            args.extend(options.iter().map(|s| s.as_str()));
+        // Placed first so variables set in the `exec_options` take priority
+        let mut env = options::environment_variables(&self.env)?;
+        env.extend(config.env.take().unwrap_or_default());
+        if !env.is_empty() {
+            config.env = Some(env);
+        }
+        let ExecOutput { output, exit_code } = self.engine.exec(&self.env.name, config).await?;
 
-            args.push(&self.env.name);
+        match exit_code {
+            0 => Ok(output),
+            _ => Err(DockerError::CommandExitCode {
+                cmd: command,
+                stdout: output,
+            }
+            .into()),
+        }
+    }
 
+    /// Runs each command in the container with the environment's `exec_options`
+    async fn exec_commands(&self, cmds: &[String]) -> Result<()> {
+        let dirs = self.env.value_dirs("exec_options")?;
+        for cmd in cmds {
             let split_cmd = shell_words::split(cmd).unwrap();
-            args.extend(split_cmd.iter().map(|s| s.as_str()));
-
-            self.run_docker_command(args, &self.config_dir)?;
+            self.exec(split_cmd, &self.env.exec_options, &dirs).await?;
         }
         Ok(())
     }
 
-    fn copy_commands(&self) -> Result<()> {
-        for cmd in &self.env.cp_cmds {
-            let mut args = vec!["cp"];
+    /// Runs each command of the list `field` on the host from the directory
+    /// of the config file it was defined in, stopping at the first to fail
+    async fn run_host_commands(&self, field: &str, cmds: &[String]) -> Result<()> {
+        for (cmd, dir) in cmds.iter().zip(self.env.value_dirs(field)?) {
+            let words = shell_words::split(cmd).unexpected()?;
+            let Some((program, args)) = words.split_first() else {
+                continue;
+            };
+
+            info!("Running host command: {cmd}");
 
-            let fixed_string = cmd.clone().replace("CONTAINER", &self.env.name);
+            let mut command = std::process::Command::new(program);
+            command.args(args).current_dir(dir);
+            let output = tokio::task::spawn_blocking(move || command.output())
+                .await
+                .unexpected()?
+                .map_err(|e| DockerError::HostCommandFailed {
+                    cmd: cmd.clone(),
+                    error: e.to_string(),
+                })?;
+
+            info!("{}", String::from_utf8_lossy(&output.stdout));
+
+            if !output.status.success() {
+                let status = match output.status.code() {
+                    Some(code) => format!("code {code}"),
+                    None => "a signal".to_string(),
+                };
+                return Err(DockerError::HostCommandExitCode {
+                    cmd: cmd.clone(),
+                    status,
+                    stderr: String::from_utf8_lossy(&output.stderr)
+                        .trim_end()
+                        .to_string(),
+                }
+                .into());
+            }
+        }
 
-            let split_cmd = shell_words::split(&fixed_string).unwrap();
-            args.extend(split_cmd.iter().map(|s| s.as_str()));
+        Ok(())
+    }
 
-            self.run_docker_command(args, &self.config_dir)?;
+    async fn copy_commands(&self) -> Result<()> {
+        for (cmd, dir) in self.env.cp_cmds.iter().zip(self.env.value_dirs("cp_cmds")?) {
+            self.copy(cmd, &dir)
+                .await
+                .map_err(|msg| DockerError::CopyingFiles {
+                    cmd: cmd.clone(),
+                    msg,
+                })?;
         }
         Ok(())
     }
 
+    /// Copies files between the host and the container following the
+    /// semantics of `docker cp`, with `CONTAINER:` marking the container's path
+    /// and host paths relative to `dir`
+    async fn copy(&self, cmd: &str, dir: &Path) -> Result<(), String> {
+        let mut follow_links = false;
+        let mut paths = Vec::new();
+
+        for token in shell_words::split(cmd).map_err(|e| e.to_string())? {
+            match token.as_str() {
+                "-L" | "--follow-link" => follow_links = true,
+                "-a" | "--archive" | "-q" | "--quiet" => {}
+                option if option.len() > 1 && option.starts_with('-') => {
+                    return Err(format!("Unsupported option '{option}'"))
+                }
+                _ => paths.push(token),
+            }
+        }
+
+        let [source, destination] = paths.as_slice() else {
+            return Err("Expected a source and a destination path".to_string());
+        };
+
+        let container_path = |path: &str| match path.strip_prefix(COPY_CONTAINER_PREFIX) {
+            Some(path) if path.starts_with('/') => Some(path.to_string()),
+            Some(path) => Some(format!("/{path}")),
+            None => None,
+        };
+
+        match (container_path(source), container_path(destination)) {
+            (None, Some(destination)) => {
+                let source = dir.join(source);
+                self.copy_to_container(source, &destination, follow_links)
+                    .await
+            }
+            (Some(source), None) => {
+                let destination = dir.join(destination);
+                self.copy_from_container(&source, &destination).await
+            }
+            _ => Err(format!(
+                "Exactly one path must start with '{COPY_CONTAINER_PREFIX}'"
+            )),
+        }
+    }
+
+    async fn copy_to_container(
+        &self,
+        source: PathBuf,
+        destination: &str,
+        follow_links: bool,
+    ) -> Result<(), String> {
+        let contents_only = source.to_string_lossy().ends_with("/.");
+        let source_name = source
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or(".".to_string());
+
+        let (path, name) = if destination.ends_with('/')
+            || self
+                .engine
+                .is_dir(&self.env.name, destination)
+                .await
+                .map_err(|e| e.to_string())?
+        {
+            let name = if contents_only { "." } else { &source_name };
+            (destination.to_string(), name.to_string())
+        } else {
+            let destination = Path::new(destination);
+            let parent = destination.parent().unwrap_or(Path::new("/"));
+            let name = destination.file_name().unwrap_or_default();
+            (
+                parent.display().to_string(),
+                name.to_string_lossy().to_string(),
+            )
+        };
+
+        let archive = tokio::task::spawn_blocking(move || {
+            archive::archive_path(&source, &name, follow_links)
+                .map_err(|e| format!("{}: {e}", source.display()))
+        })
+        .await
+        .map_err(|e| e.to_string())??;
+
+        self.engine
+            .upload(&self.env.name, &path, archive)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn copy_from_container(&self, source: &str, destination: &Path) -> Result<(), String> {
+        let destination = if destination.is_dir() && !source.ends_with("/.") {
+            let name = Path::new(source).file_name().unwrap_or_default();
+            destination.join(name)
+        } else {
+            destination.to_path_buf()
+        };
+
+        let archive = self
+            .engine
+            .download(&self.env.name, source)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        tokio::task::spawn_blocking(move || {
+            archive::extract(&archive, &destination)
+                .map_err(|e| format!("{}: {e}", destination.display()))
+        })
+        .await
+        .map_err(|e| e.to_string())?
+    }
+
+    /// Runs the `on_stop` hooks and stops the container if it's running
     pub async fn stop_container_if_running(&self) -> Result<()> {
         if self.is_container_running().await? {
-            self.docker
-                .stop_container(&self.env.name, Some(StopContainerOptions { t: 0 }))
-                .await
-                .map_err(docker_err!(StoppingContainer))?;
+            // Stopped even if a hook fails, so it isn't left running
+            let hooks = self.exec_commands(&self.env.on_stop).await;
+            self.engine.stop_container(&self.env.name).await?;
+            hooks?;
         }
         Ok(())
     }
 
+    /// Stops and removes the container without running the `on_stop` hooks,
+    /// for a container that was never fully set up
+    pub async fn discard_container(&self) -> Result<()> {
+        if self.is_container_running().await? {
+            self.engine.stop_container(&self.env.name).await?;
+        }
+        self.delete_container_if_exists().await
+    }
+
     pub async fn is_anyone_connected(&self) -> Result<bool> {
-        let args = vec!["exec", &self.env.name, "ls", "/dev/pts"];
-        let output = self.run_docker_command_with_output(args, &self.config_dir)?;
-        let ps_count = String::from_utf8(output.stdout).unwrap().lines().count();
+        let cmd = vec!["ls".to_string(), "/dev/pts".to_string()];
+        let output = self.exec(cmd, &[], &[]).await?;
+        let ps_count = output.lines().count();
 
         let no_connections_ps_count = 2;
         Ok(ps_count > no_connections_ps_count)
     }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GarbageItem {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Garbage {
+    pub containers: Vec<GarbageItem>,
+    pub images: Vec<GarbageItem>,
+}
+
+impl Garbage {
+    pub fn is_empty(&self) -> bool {
+        self.containers.is_empty() && self.images.is_empty()
+    }
+}
+
+/// Finds and removes berth containers and images which no longer belong
+/// to any of the environments in the configuration
+#[derive(Debug)]
+pub struct GarbageCollector {
+    engine: Arc<dyn ContainerEngine>,
+    config_paths: Vec<String>,
+}
 
-    fn run_docker_command_with_output(
+impl GarbageCollector {
+    pub fn new(config_paths: &[PathBuf], engine: Arc<dyn ContainerEngine>) -> Result<Self> {
+        Ok(GarbageCollector {
+            engine,
+            config_paths: config_paths
+                .iter()
+                .map(|path| canonical_config_path(path))
+                .collect(),
+        })
+    }
+
+    /// Only containers and images created from these config files, or from config
+    /// files that no longer exist, are collected. Running containers are never
+    /// collected, nor are the images they use. If `all` is set the containers
+    /// and images of the current environments are collected as well.
+    pub async fn find(
         &self,
-        args: Vec<&str>,
-        working_dir: &Path,
-    ) -> Result<Output> {
-        let command = format!("{} {}", CONTAINER_ENGINE, shell_words::join(&args));
-        info!("{command}");
-
-        let output = Command::new(CONTAINER_ENGINE)
-            .args(&args)
-            .current_dir(working_dir)
-            .output()
-            .map_err(|_| DockerError::CommandFailed(command.clone()))?;
-
-        let status_code = output.status.code();
-        match status_code {
-            None => Err(DockerError::CommandKilled(command).into()),
-            Some(0) => Ok(output),
-            Some(_) => Err(DockerError::CommandExitCode {
-                cmd: command,
-                stdout: String::from_utf8(output.stdout.clone()).unwrap(),
+        environments: &[Environment],
+        all: bool,
+        older_than: Option<Duration>,
+    ) -> Result<Garbage> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unexpected()?;
+        let older_than = older_than.map(|d| d.as_secs().try_into().unwrap_or(i64::MAX));
+        let cutoff = now.saturating_sub(older_than.unwrap_or(0));
+
+        // Containers using the legacy naming are kept so they can be migrated
+        let legacy_names: Vec<String> = environments.iter().map(|env| env.legacy_name()).collect();
+        let current_containers: HashSet<&str> = environments
+            .iter()
+            .map(|env| env.name.as_str())
+            .chain(legacy_names.iter().map(|name| name.as_str()))
+            .collect();
+        let current_images: HashSet<&str> = environments
+            .iter()
+            .filter(|env| env.dockerfile.is_some())
+            .map(|env| env.image.as_str())
+            .collect();
+
+        let mut garbage = Garbage::default();
+        let mut used_images: HashSet<String> = HashSet::new();
+
+        for container in self.berth_containers().await? {
+            let name = container_names(&container)
+                .find(|name| name.starts_with("berth-"))
+                .unwrap_or_default()
+                .to_string();
+
+            let is_running = container.state.as_deref() == Some("running");
+            let is_current = current_containers.contains(name.as_str())
+                || Self::is_current_in_other_workspace(&container, &name, environments);
+            let is_old_enough = container.created.unwrap_or(now) <= cutoff;
+
+            if self.is_collectable(container.labels.as_ref(), is_current)
+                && !is_running
+                && (all || !is_current)
+                && is_old_enough
+            {
+                garbage.containers.push(GarbageItem {
+                    id: container.id.unexpected()?,
+                    name,
+                });
+            } else {
+                used_images.extend(container.image);
+                used_images.extend(container.image_id);
             }
-            .into()),
         }
+
+        for image in self.berth_images().await? {
+            let names: Vec<&str> = image
+                .repo_tags
+                .iter()
+                .filter(|tag| tag.starts_with("berth-"))
+                .map(|tag| tag.strip_suffix(":latest").unwrap_or(tag))
+                .collect();
+
+            let is_used = used_images.contains(&image.id)
+                || image.repo_tags.iter().any(|tag| used_images.contains(tag))
+                || names.iter().any(|name| used_images.contains(*name));
+            let is_current = names.iter().any(|name| current_images.contains(name));
+            let is_old_enough = image.created <= cutoff;
+
+            if self.is_collectable(Some(&image.labels), is_current)
+                && !is_used
+                && (all || !is_current)
+                && is_old_enough
+            {
+                garbage.images.push(GarbageItem {
+                    id: image.id.clone(),
+                    name: names.first().unwrap_or(&image.id.as_str()).to_string(),
+                });
+            }
+        }
+
+        Ok(garbage)
+    }
+
+    /// Whether the container belongs to the current version of an environment
+    /// with its `workspace` mounted from another directory which still exists
+    fn is_current_in_other_workspace(
+        container: &ContainerSummary,
+        name: &str,
+        environments: &[Environment],
+    ) -> bool {
+        let Some(source) = workspace_source(container).map(Path::new) else {
+            return false;
+        };
+
+        source.is_dir()
+            && environments
+                .iter()
+                .filter(|env| env.workspace.is_some())
+                .any(|env| env.with_workspace_source(source).name == name)
     }
 
-    fn run_docker_command(&self, args: Vec<&str>, working_dir: &Path) -> Result<()> {
-        self.run_docker_command_with_output(args, working_dir)
-            .map(|_| ())
+    /// Containers and images created before berth labelled them have no
+    /// record of their config file, so are only considered when they're named
+    /// after one of the current environments
+    fn is_collectable(&self, labels: Option<&HashMap<String, String>>, is_current: bool) -> bool {
+        match labels.and_then(|labels| labels.get(labels::CONFIG_PATH)) {
+            Some(config_path) => {
+                self.config_paths.contains(config_path) || !Path::new(config_path).exists()
+            }
+            None => is_current,
+        }
+    }
+
+    pub async fn remove(&self, garbage: &Garbage) -> Result<()> {
+        for container in &garbage.containers {
+            self.engine.remove_container(&container.id).await?;
+        }
+
+        for image in &garbage.images {
+            self.engine.remove_image(&image.id).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn berth_containers(&self) -> Result<Vec<ContainerSummary>> {
+        let mut filters = HashMap::new();
+        filters.insert("label", vec![labels::ENVIRONMENT]);
+        let mut containers = self.engine.list_containers(filters).await?;
+
+        // Containers created before berth labelled them can only be found by name
+        let mut filters = HashMap::new();
+        filters.insert("name", vec!["berth-"]);
+
+        containers.extend(
+            self.engine
+                .list_containers(filters)
+                .await?
+                .into_iter()
+                .filter(|container| !has_berth_labels(container.labels.as_ref()))
+                .filter(|container| {
+                    container_names(container).any(|name| name.starts_with("berth-"))
+                }),
+        );
+
+        Ok(containers)
+    }
+
+    async fn berth_images(&self) -> Result<Vec<ImageSummary>> {
+        let mut filters = HashMap::new();
+        filters.insert("label", vec![labels::ENVIRONMENT]);
+        let mut images = self.engine.list_images(filters).await?;
+
+        // Images built before berth labelled them can only be found by name
+        let mut filters = HashMap::new();
+        filters.insert("reference", vec!["berth-*"]);
+
+        images.extend(
+            self.engine
+                .list_images(filters)
+                .await?
+                .into_iter()
+                .filter(|image| !has_berth_labels(Some(&image.labels))),
+        );
+
+        Ok(images)
     }
 }
@@ -0,0 +1,195 @@
+//! Tar archives for sending build contexts and copied files to the Docker API
+
+use glob::{MatchOptions, Pattern};
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+};
+
+/// Name the dockerfile is given in the build context when it's outside of it
+const DOCKERFILE_NAME: &str = ".berth.dockerfile";
+
+/// Paths excluded from the build context by a `.dockerignore` file
+struct DockerIgnore {
+    patterns: Vec<(Pattern, bool)>,
+}
+
+impl DockerIgnore {
+    fn new(context: &Path) -> Self {
+        let content = fs::read_to_string(context.join(".dockerignore")).unwrap_or_default();
+
+        let patterns = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| {
+                let (line, exception) = match line.strip_prefix('!') {
+                    Some(line) => (line.trim(), true),
+                    None => (line, false),
+                };
+                let line = line.trim_start_matches('/').trim_end_matches('/');
+                Pattern::new(line).ok().map(|pattern| (pattern, exception))
+            })
+            .collect();
+
+        DockerIgnore { patterns }
+    }
+
+    fn has_exceptions(&self) -> bool {
+        self.patterns.iter().any(|(_, exception)| *exception)
+    }
+
+    /// Whether `path`, relative to the build context, is excluded, which is
+    /// the case if it or any of its parents match the last matching pattern
+    fn is_excluded(&self, path: &str) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+
+        let mut excluded = false;
+        for (pattern, exception) in &self.patterns {
+            let matched = path
+                .match_indices('/')
+                .map(|(index, _)| &path[..index])
+                .chain(std::iter::once(path))
+                .any(|prefix| pattern.matches_with(prefix, options));
+
+            if matched {
+                excluded = !exception;
+            }
+        }
+        excluded
+    }
+}
+
+fn append_context_dir(
+    builder: &mut tar::Builder<Vec<u8>>,
+    context: &Path,
+    dir: &Path,
+    ignore: &DockerIgnore,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let name = path
+            .strip_prefix(context)
+            .map_err(io::Error::other)?
+            .to_string_lossy()
+            .replace('\\', "/");
+        let excluded = ignore.is_excluded(&name);
+
+        if entry.file_type()?.is_dir() {
+            if !excluded {
+                builder.append_path_with_name(&path, &name)?;
+            }
+            if !excluded || ignore.has_exceptions() {
+                append_context_dir(builder, context, &path, ignore)?;
+            }
+        } else if !excluded {
+            builder.append_path_with_name(&path, &name)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Archives the build context, excluding anything matched by its
+/// `.dockerignore`, returning the archive and the dockerfile's path within it
+pub fn build_context(context: &Path, dockerfile: &Path) -> io::Result<(Vec<u8>, String)> {
+    let context = fs::canonicalize(context)?;
+    let dockerfile = fs::canonicalize(dockerfile)?;
+
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.follow_symlinks(false);
+
+    let ignore = DockerIgnore::new(&context);
+    append_context_dir(&mut builder, &context, &context, &ignore)?;
+
+    let dockerfile_in_context = dockerfile
+        .strip_prefix(&context)
+        .ok()
+        .map(|path| path.to_string_lossy().replace('\\', "/"))
+        .filter(|path| !ignore.is_excluded(path));
+
+    let dockerfile_name = match dockerfile_in_context {
+        Some(path) => path,
+        None => {
+            let mut content = Vec::new();
+            fs::File::open(&dockerfile)?.read_to_end(&mut content)?;
+
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, DOCKERFILE_NAME, content.as_slice())?;
+
+            DOCKERFILE_NAME.to_string()
+        }
+    };
+
+    Ok((builder.into_inner()?, dockerfile_name))
+}
+
+/// Archives the file or directory at `source` under the name `name`, with a
+/// name of `.` archiving a directory's contents rather than the directory
+pub fn archive_path(source: &Path, name: &str, follow_links: bool) -> io::Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.follow_symlinks(follow_links);
+
+    let metadata = if follow_links {
+        fs::metadata(source)?
+    } else {
+        fs::symlink_metadata(source)?
+    };
+
+    if metadata.is_dir() {
+        builder.append_dir_all(name, source)?;
+    } else if follow_links {
+        builder.append_path_with_name(fs::canonicalize(source)?, name)?;
+    } else {
+        builder.append_path_with_name(source, name)?;
+    }
+
+    builder.into_inner()
+}
+
+/// Extracts an archive downloaded from a container to `destination`, with
+/// the archive's top level entry becoming `destination` itself
+pub fn extract(archive: &[u8], destination: &Path) -> io::Result<()> {
+    let mut archive = tar::Archive::new(archive);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+
+        let mut components = path.components();
+        components.next();
+        let relative: PathBuf = components.collect();
+
+        if relative
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+        {
+            return Err(io::Error::other(format!(
+                "archive contains unsafe path {}",
+                path.display()
+            )));
+        }
+
+        let target = if relative.as_os_str().is_empty() {
+            destination.to_path_buf()
+        } else {
+            destination.join(relative)
+        };
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        entry.unpack(&target)?;
+    }
+
+    Ok(())
+}
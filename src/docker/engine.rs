@@ -0,0 +1,414 @@
+//! Container engines berth can create environments with
+
+use super::{docker_err, stream_error_message, DockerError};
+use async_trait::async_trait;
+use bollard::{
+    container::{
+        Config, CreateContainerOptions, DownloadFromContainerOptions, ListContainersOptions,
+        RemoveContainerOptions, RenameContainerOptions, StartContainerOptions,
+        StopContainerOptions, UploadToContainerOptions,
+    },
+    exec::{CreateExecOptions, StartExecResults},
+    image::{BuildImageOptions, CreateImageOptions, ListImagesOptions, RemoveImageOptions},
+    secret::{ContainerSummary, ImageSummary},
+    Docker, API_DEFAULT_VERSION,
+};
+use futures_util::StreamExt;
+use log::info;
+use miette::Result;
+use serde::Deserialize;
+use std::{collections::HashMap, fmt, path::PathBuf, process::Command, sync::Arc};
+
+/// Seconds to wait for the engine to respond before giving up
+const TIMEOUT: u64 = 120;
+
+/// The container engines berth supports
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum, schemars::JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Engine {
+    #[default]
+    Docker,
+    Podman,
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Engine::Docker => write!(f, "docker"),
+            Engine::Podman => write!(f, "podman"),
+        }
+    }
+}
+
+impl Engine {
+    pub fn connect(self) -> Result<Arc<dyn ContainerEngine>> {
+        let docker = match self {
+            Engine::Docker => Docker::connect_with_local_defaults(),
+            Engine::Podman => Docker::connect_with_socket(
+                &podman_socket().display().to_string(),
+                TIMEOUT,
+                API_DEFAULT_VERSION,
+            ),
+        }
+        .map_err(docker_err!(ConnectingToDaemon))?;
+
+        Ok(Arc::new(ApiEngine {
+            docker,
+            cli: self.to_string(),
+        }))
+    }
+}
+
+/// `$CONTAINER_HOST` if set, otherwise the rootless socket if it exists, and
+/// the rootful socket if not
+fn podman_socket() -> PathBuf {
+    if let Ok(host) = std::env::var("CONTAINER_HOST") {
+        return PathBuf::from(host.trim_start_matches("unix://"));
+    }
+
+    if let Ok(runtime_dir) = std::env::var("XDG_RUNTIME_DIR") {
+        let rootless = PathBuf::from(runtime_dir)
+            .join("podman")
+            .join("podman.sock");
+        if rootless.exists() {
+            return rootless;
+        }
+    }
+
+    PathBuf::from("/run/podman/podman.sock")
+}
+
+/// Combined stdout and stderr of a command run in a container
+#[derive(Debug, Clone, Default)]
+pub struct ExecOutput {
+    pub output: String,
+    pub exit_code: i64,
+}
+
+/// The operations berth needs from a container engine. Requests and responses
+/// use the Docker API's types, which Podman's API is compatible with.
+#[async_trait]
+pub trait ContainerEngine: fmt::Debug + Send + Sync {
+    /// Lists all containers, running or not, matching the filters
+    async fn list_containers(
+        &self,
+        filters: HashMap<&str, Vec<&str>>,
+    ) -> Result<Vec<ContainerSummary>>;
+
+    async fn list_images(&self, filters: HashMap<&str, Vec<&str>>) -> Result<Vec<ImageSummary>>;
+
+    async fn pull_image(&self, image: &str) -> Result<()>;
+
+    /// Builds an image from a tar archive of its build context
+    async fn build_image(&self, options: BuildImageOptions<String>, context: Vec<u8>)
+        -> Result<()>;
+
+    async fn remove_image(&self, image: &str) -> Result<()>;
+
+    async fn create_container(
+        &self,
+        name: &str,
+        platform: Option<String>,
+        config: Config<String>,
+    ) -> Result<()>;
+
+    async fn start_container(&self, container: &str) -> Result<()>;
+
+    async fn stop_container(&self, container: &str) -> Result<()>;
+
+    async fn remove_container(&self, container: &str) -> Result<()>;
+
+    async fn rename_container(&self, container: &str, name: &str) -> Result<()>;
+
+    async fn exec(&self, container: &str, options: CreateExecOptions<String>)
+        -> Result<ExecOutput>;
+
+    /// Runs a command in the container attached to the terminal, returning
+    /// its exit code or `None` if it was killed by a signal
+    async fn enter(
+        &self,
+        container: &str,
+        options: &[String],
+        cmd: &[String],
+    ) -> Result<Option<i32>>;
+
+    /// Extracts a tar archive into the directory `path` in the container
+    async fn upload(&self, container: &str, path: &str, archive: Vec<u8>) -> Result<()>;
+
+    /// Archives the file or directory at `path` in the container
+    async fn download(&self, container: &str, path: &str) -> Result<Vec<u8>>;
+
+    /// Whether `path` is an existing directory in the container
+    async fn is_dir(&self, container: &str, path: &str) -> Result<bool>;
+}
+
+/// An engine driven through the Docker API, with the engine's CLI used to
+/// enter containers as the API can't attach to the terminal
+#[derive(Debug)]
+struct ApiEngine {
+    docker: Docker,
+    cli: String,
+}
+
+#[async_trait]
+impl ContainerEngine for ApiEngine {
+    async fn list_containers(
+        &self,
+        filters: HashMap<&str, Vec<&str>>,
+    ) -> Result<Vec<ContainerSummary>> {
+        let options = Some(ListContainersOptions {
+            all: true,
+            filters,
+            ..Default::default()
+        });
+
+        Ok(self
+            .docker
+            .list_containers(options)
+            .await
+            .map_err(docker_err!(ContainerInfo))?)
+    }
+
+    async fn list_images(&self, filters: HashMap<&str, Vec<&str>>) -> Result<Vec<ImageSummary>> {
+        let options = Some(ListImagesOptions {
+            filters,
+            ..Default::default()
+        });
+
+        Ok(self
+            .docker
+            .list_images(options)
+            .await
+            .map_err(docker_err!(ImageInfo))?)
+    }
+
+    async fn pull_image(&self, image: &str) -> Result<()> {
+        // Without a tag the Docker API pulls every tag of the image
+        let name = image.rsplit('/').next().unwrap_or_default();
+        let tag = if name.contains(':') || name.contains('@') {
+            ""
+        } else {
+            "latest"
+        };
+
+        let options = CreateImageOptions {
+            from_image: image,
+            tag,
+            ..Default::default()
+        };
+
+        let mut stream = self.docker.create_image(Some(options), None, None);
+        while let Some(info) = stream.next().await {
+            info.map_err(|e| DockerError::PullingImage(stream_error_message(e)))?;
+        }
+
+        Ok(())
+    }
+
+    async fn build_image(
+        &self,
+        options: BuildImageOptions<String>,
+        context: Vec<u8>,
+    ) -> Result<()> {
+        let mut output = String::new();
+        let mut stream = self.docker.build_image(options, None, Some(context.into()));
+        while let Some(info) = stream.next().await {
+            match info {
+                Ok(info) => output.push_str(info.stream.as_deref().unwrap_or_default()),
+                Err(error) => {
+                    output.push_str(&stream_error_message(error));
+                    return Err(DockerError::BuildingImage(output).into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn remove_image(&self, image: &str) -> Result<()> {
+        let option = RemoveImageOptions {
+            force: true,
+            ..Default::default()
+        };
+
+        self.docker
+            .remove_image(image, Some(option), None)
+            .await
+            .map_err(docker_err!(RemovingImage))?;
+        Ok(())
+    }
+
+    async fn create_container(
+        &self,
+        name: &str,
+        platform: Option<String>,
+        config: Config<String>,
+    ) -> Result<()> {
+        let options = CreateContainerOptions {
+            name: name.to_string(),
+            platform,
+        };
+
+        self.docker
+            .create_container(Some(options), config)
+            .await
+            .map_err(docker_err!(CreatingContainer))?;
+        Ok(())
+    }
+
+    async fn start_container(&self, container: &str) -> Result<()> {
+        self.docker
+            .start_container(container, None::<StartContainerOptions<String>>)
+            .await
+            .map_err(docker_err!(StartingContainer))?;
+        Ok(())
+    }
+
+    async fn stop_container(&self, container: &str) -> Result<()> {
+        self.docker
+            .stop_container(container, Some(StopContainerOptions { t: 0 }))
+            .await
+            .map_err(docker_err!(StoppingContainer))?;
+        Ok(())
+    }
+
+    async fn remove_container(&self, container: &str) -> Result<()> {
+        let option = RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+
+        self.docker
+            .remove_container(container, Some(option))
+            .await
+            .map_err(docker_err!(RemovingContainer))?;
+        Ok(())
+    }
+
+    async fn rename_container(&self, container: &str, name: &str) -> Result<()> {
+        self.docker
+            .rename_container(container, RenameContainerOptions { name })
+            .await
+            .map_err(docker_err!(RenamingContainer))?;
+        Ok(())
+    }
+
+    async fn exec(
+        &self,
+        container: &str,
+        options: CreateExecOptions<String>,
+    ) -> Result<ExecOutput> {
+        let cmd = shell_words::join(options.cmd.iter().flatten());
+        let exec = self
+            .docker
+            .create_exec(container, options)
+            .await
+            .map_err(docker_err!(ExecutingCommand))?;
+
+        let mut output = String::new();
+        if let StartExecResults::Attached {
+            output: mut stream, ..
+        } = self
+            .docker
+            .start_exec(&exec.id, None)
+            .await
+            .map_err(docker_err!(ExecutingCommand))?
+        {
+            while let Some(chunk) = stream.next().await {
+                output.push_str(&chunk.map_err(docker_err!(ExecutingCommand))?.to_string());
+            }
+        }
+
+        let exit_code = self
+            .docker
+            .inspect_exec(&exec.id)
+            .await
+            .map_err(docker_err!(ExecutingCommand))?
+            .exit_code
+            // Not knowing how the command exited isn't taken as success
+            .ok_or(DockerError::CommandFailed(cmd))?;
+
+        Ok(ExecOutput { output, exit_code })
+    }
+
+    async fn enter(
+        &self,
+        container: &str,
+        options: &[String],
+        cmd: &[String],
+    ) -> Result<Option<i32>> {
+        let mut args = vec!["exec"];
+        args.extend(options.iter().map(|s| s.as_str()));
+        args.push(container);
+        args.extend(cmd.iter().map(|s| s.as_str()));
+
+        let command = format!("{} {}", self.cli, shell_words::join(&args));
+
+        info!("{command}");
+
+        // Waited on from a blocking thread so the runtime isn't blocked for
+        // the whole session
+        let mut process = Command::new(&self.cli);
+        process.args(&args);
+        let status = tokio::task::spawn_blocking(move || process.status()).await;
+
+        match status {
+            Ok(Ok(status)) => Ok(status.code()),
+            _ => Err(DockerError::CommandFailed(command).into()),
+        }
+    }
+
+    async fn upload(&self, container: &str, path: &str, archive: Vec<u8>) -> Result<()> {
+        let options = UploadToContainerOptions {
+            path,
+            ..Default::default()
+        };
+
+        self.docker
+            .upload_to_container(container, Some(options), archive.into())
+            .await
+            .map_err(docker_err!(CopyingArchive))?;
+        Ok(())
+    }
+
+    async fn download(&self, container: &str, path: &str) -> Result<Vec<u8>> {
+        let options = DownloadFromContainerOptions { path };
+        let mut stream = self
+            .docker
+            .download_from_container(container, Some(options));
+
+        let mut archive = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            archive.extend_from_slice(&chunk.map_err(docker_err!(CopyingArchive))?);
+        }
+
+        Ok(archive)
+    }
+
+    /// Found from the first entry of the archive the Docker API returns for `path`
+    async fn is_dir(&self, container: &str, path: &str) -> Result<bool> {
+        let options = DownloadFromContainerOptions { path };
+        let mut stream = self
+            .docker
+            .download_from_container(container, Some(options));
+
+        let mut header = Vec::new();
+        while header.len() < 512 {
+            match stream.next().await {
+                Some(Ok(chunk)) => header.extend_from_slice(&chunk),
+                Some(Err(bollard::errors::Error::DockerResponseServerError {
+                    status_code: 404,
+                    ..
+                }))
+                | None => return Ok(false),
+                Some(Err(error)) => return Err(DockerError::CopyingArchive(error).into()),
+            }
+        }
+
+        Ok(tar::Header::from_byte_slice(&header[..512])
+            .entry_type()
+            .is_dir())
+    }
+}
@@ -0,0 +1,702 @@
+//! Translates the docker CLI style options in the configuration file into
+//! Docker API requests, so berth doesn't need the docker CLI to create
+//! containers or run commands in them
+
+use super::DockerError;
+use crate::configuration::{self, Environment, MountType};
+use bollard::{
+    container::Config,
+    exec::CreateExecOptions,
+    secret::{
+        DeviceMapping, DeviceRequest, HostConfig, HostConfigCgroupnsModeEnum, HostConfigLogConfig,
+        Mount, MountTypeEnum, PortBinding, ResourcesUlimits, RestartPolicy, RestartPolicyNameEnum,
+    },
+};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+struct Flag {
+    long: &'static str,
+    short: Option<char>,
+    takes_value: bool,
+}
+
+const fn flag(long: &'static str, short: Option<char>, takes_value: bool) -> Flag {
+    Flag {
+        long,
+        short,
+        takes_value,
+    }
+}
+
+const CREATE_FLAGS: &[Flag] = &[
+    flag("add-host", None, true),
+    flag("cap-add", None, true),
+    flag("cap-drop", None, true),
+    flag("cgroup-parent", None, true),
+    flag("cgroupns", None, true),
+    flag("cpu-period", None, true),
+    flag("cpu-quota", None, true),
+    flag("cpu-shares", Some('c'), true),
+    flag("cpus", None, true),
+    flag("cpuset-cpus", None, true),
+    flag("cpuset-mems", None, true),
+    flag("detach", Some('d'), false),
+    flag("device", None, true),
+    flag("device-cgroup-rule", None, true),
+    flag("dns", None, true),
+    flag("dns-opt", None, true),
+    flag("dns-option", None, true),
+    flag("dns-search", None, true),
+    flag("domainname", None, true),
+    flag("entrypoint", None, true),
+    flag("env", Some('e'), true),
+    flag("env-file", None, true),
+    flag("expose", None, true),
+    flag("gpus", None, true),
+    flag("group-add", None, true),
+    flag("hostname", Some('h'), true),
+    flag("init", None, false),
+    flag("interactive", Some('i'), false),
+    flag("ipc", None, true),
+    flag("label", Some('l'), true),
+    flag("link", None, true),
+    flag("log-driver", None, true),
+    flag("log-opt", None, true),
+    flag("mac-address", None, true),
+    flag("memory", Some('m'), true),
+    flag("memory-reservation", None, true),
+    flag("memory-swap", None, true),
+    flag("memory-swappiness", None, true),
+    flag("mount", None, true),
+    flag("net", None, true),
+    flag("network", None, true),
+    flag("oom-kill-disable", None, false),
+    flag("oom-score-adj", None, true),
+    flag("pid", None, true),
+    flag("pids-limit", None, true),
+    flag("platform", None, true),
+    flag("privileged", None, false),
+    flag("publish", Some('p'), true),
+    flag("publish-all", Some('P'), false),
+    flag("read-only", None, false),
+    flag("restart", None, true),
+    flag("rm", None, false),
+    flag("runtime", None, true),
+    flag("security-opt", None, true),
+    flag("shm-size", None, true),
+    flag("stop-signal", None, true),
+    flag("stop-timeout", None, true),
+    flag("storage-opt", None, true),
+    flag("sysctl", None, true),
+    flag("tmpfs", None, true),
+    flag("tty", Some('t'), false),
+    flag("ulimit", None, true),
+    flag("user", Some('u'), true),
+    flag("userns", None, true),
+    flag("uts", None, true),
+    flag("volume", Some('v'), true),
+    flag("volume-driver", None, true),
+    flag("volumes-from", None, true),
+    flag("workdir", Some('w'), true),
+];
+
+const EXEC_FLAGS: &[Flag] = &[
+    flag("env", Some('e'), true),
+    flag("env-file", None, true),
+    flag("interactive", Some('i'), false),
+    flag("privileged", None, false),
+    flag("tty", Some('t'), false),
+    flag("user", Some('u'), true),
+    flag("workdir", Some('w'), true),
+];
+
+fn invalid(option: &str, msg: impl Into<String>) -> DockerError {
+    DockerError::InvalidOption {
+        option: option.to_string(),
+        msg: msg.into(),
+    }
+}
+
+/// Splits options into flags and their values the same way the docker CLI
+/// does, with boolean flags given the value `true` unless set explicitly.
+/// Each value is returned with the index of the option it was written in.
+fn parse_flags(
+    options: &[String],
+    flags: &'static [Flag],
+) -> Result<Vec<(&'static str, String, usize)>, DockerError> {
+    let mut tokens = Vec::new();
+    for (index, option) in options.iter().enumerate() {
+        let split = shell_words::split(option).map_err(|e| invalid(option, e.to_string()))?;
+        tokens.extend(split.into_iter().map(|token| (token, index)));
+    }
+
+    let mut parsed = Vec::new();
+    let mut tokens = tokens.into_iter();
+    while let Some((token, index)) = tokens.next() {
+        if let Some(long) = token.strip_prefix("--") {
+            let (name, inline) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (long, None),
+            };
+
+            let flag = flags
+                .iter()
+                .find(|flag| flag.long == name)
+                .ok_or_else(|| invalid(&token, "unsupported option"))?;
+
+            let value = match (flag.takes_value, inline) {
+                (_, Some(value)) => (value, index),
+                (true, None) => tokens
+                    .next()
+                    .ok_or_else(|| invalid(&token, "missing value"))?,
+                (false, None) => ("true".to_string(), index),
+            };
+            parsed.push((flag.long, value.0, value.1));
+        } else if let Some(shorts) = token.strip_prefix('-').filter(|s| !s.is_empty()) {
+            for (position, short) in shorts.char_indices() {
+                let flag = flags
+                    .iter()
+                    .find(|flag| flag.short == Some(short))
+                    .ok_or_else(|| invalid(&format!("-{short}"), "unsupported option"))?;
+
+                if !flag.takes_value {
+                    parsed.push((flag.long, "true".to_string(), index));
+                    continue;
+                }
+
+                let rest = &shorts[position + short.len_utf8()..];
+                let rest = rest.strip_prefix('=').unwrap_or(rest);
+                let (value, index) = if rest.is_empty() {
+                    tokens
+                        .next()
+                        .ok_or_else(|| invalid(&token, "missing value"))?
+                } else {
+                    (rest.to_string(), index)
+                };
+                parsed.push((flag.long, value, index));
+                break;
+            }
+        } else {
+            return Err(invalid(&token, "expected an option starting with '-'"));
+        }
+    }
+
+    Ok(parsed)
+}
+
+fn parse_bool(option: &str, value: &str) -> Result<bool, DockerError> {
+    value
+        .parse()
+        .map_err(|_| invalid(option, format!("'{value}' is not true or false")))
+}
+
+fn parse_number(option: &str, value: &str) -> Result<i64, DockerError> {
+    value
+        .parse()
+        .map_err(|_| invalid(option, format!("'{value}' is not a number")))
+}
+
+/// Splits `key=value`, as used by options such as `--sysctl`
+fn key_value<'a>(option: &str, value: &'a str) -> Result<(&'a str, &'a str), DockerError> {
+    value
+        .split_once('=')
+        .ok_or_else(|| invalid(option, "expected key=value"))
+}
+
+/// Parses the GPUs given to `--gpus`, either `all`, a number of GPUs or
+/// `device=` followed by their IDs
+fn parse_gpus(option: &str, value: &str) -> Result<DeviceRequest, DockerError> {
+    let value = value.trim_matches('"');
+    let mut request = DeviceRequest {
+        capabilities: Some(vec![vec!["gpu".to_string()]]),
+        ..Default::default()
+    };
+
+    match value.strip_prefix("device=") {
+        Some(ids) => request.device_ids = Some(ids.split(',').map(str::to_string).collect()),
+        None if value == "all" => request.count = Some(-1),
+        None => {
+            request.count =
+                Some(value.parse().map_err(|_| {
+                    invalid(option, "expected 'all', a number of GPUs or device=IDS")
+                })?)
+        }
+    }
+
+    Ok(request)
+}
+
+/// Parses a `--ulimit` of `name=soft[:hard]`, where the hard limit defaults
+/// to the soft limit
+fn parse_ulimit(option: &str, value: &str) -> Result<ResourcesUlimits, DockerError> {
+    let (name, limits) = key_value(option, value)?;
+    let (soft, hard) = limits.split_once(':').unwrap_or((limits, limits));
+
+    Ok(ResourcesUlimits {
+        name: Some(name.to_string()),
+        soft: Some(parse_number(option, soft)?),
+        hard: Some(parse_number(option, hard)?),
+    })
+}
+
+/// Parses a `--restart` policy, with a maximum retry count only allowed for
+/// `on-failure[:count]`
+fn parse_restart(option: &str, value: &str) -> Result<RestartPolicy, DockerError> {
+    let (name, retries) = match value.split_once(':') {
+        Some((name, retries)) => (name, Some(retries)),
+        None => (value, None),
+    };
+
+    let name = match (name, retries) {
+        ("no", None) => RestartPolicyNameEnum::NO,
+        ("always", None) => RestartPolicyNameEnum::ALWAYS,
+        ("unless-stopped", None) => RestartPolicyNameEnum::UNLESS_STOPPED,
+        ("on-failure", _) => RestartPolicyNameEnum::ON_FAILURE,
+        _ => {
+            return Err(invalid(
+                option,
+                "expected no, always, unless-stopped or on-failure[:max-retries]",
+            ))
+        }
+    };
+
+    Ok(RestartPolicy {
+        name: Some(name),
+        maximum_retry_count: retries
+            .map(|retries| parse_number(option, retries))
+            .transpose()?,
+    })
+}
+
+/// Parses sizes such as `512`, `64k`, `128m` or `2g` into bytes
+fn parse_size(option: &str, value: &str) -> Result<i64, DockerError> {
+    let lower = value.to_lowercase();
+    let trimmed = lower.trim_end_matches('b');
+    let (number, multiplier) = match trimmed.chars().last() {
+        Some('k') => (&trimmed[..trimmed.len() - 1], 1 << 10),
+        Some('m') => (&trimmed[..trimmed.len() - 1], 1 << 20),
+        Some('g') => (&trimmed[..trimmed.len() - 1], 1 << 30),
+        _ => (trimmed, 1),
+    };
+
+    number
+        .parse::<i64>()
+        .map(|number| number * multiplier)
+        .map_err(|_| {
+            invalid(
+                option,
+                format!("'{value}' is not a size such as 512m or 2g"),
+            )
+        })
+}
+
+/// Resolves paths starting with `.` relative to the directory of the config
+/// file the option was written in, matching the docker CLI being run from it
+fn resolve_source(source: &str, config_dir: &Path) -> String {
+    if source.starts_with('.') {
+        config_dir.join(source).display().to_string()
+    } else {
+        source.to_string()
+    }
+}
+
+fn env_from_host(variable: &str) -> Option<String> {
+    if variable.contains('=') {
+        Some(variable.to_string())
+    } else {
+        std::env::var(variable)
+            .ok()
+            .map(|value| format!("{variable}={value}"))
+    }
+}
+
+fn read_env_file(path: &str, config_dir: &Path) -> Result<Vec<String>, DockerError> {
+    let content = fs::read_to_string(config_dir.join(path))
+        .map_err(|e| invalid(&format!("--env-file {path}"), e.to_string()))?;
+
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(env_from_host)
+        .collect())
+}
+
+/// The variables set by the `env_file`, `pass_env` and `env` fields of an
+/// environment, in that order so later fields take priority. Host variables
+/// named by `pass_env` that aren't set are left out
+pub fn environment_variables(environment: &Environment) -> Result<Vec<String>, DockerError> {
+    let mut variables = Vec::new();
+    if let Some(path) = &environment.env_file {
+        variables.extend(read_env_file(&path.display().to_string(), Path::new("/"))?);
+    }
+    variables.extend(
+        environment
+            .pass_env
+            .iter()
+            .filter_map(|name| env_from_host(name)),
+    );
+    variables.extend(
+        environment
+            .env
+            .iter()
+            .map(|(key, value)| format!("{key}={value}")),
+    );
+    Ok(variables)
+}
+
+fn parse_mount(value: &str, config_dir: &Path) -> Result<Mount, DockerError> {
+    let option = format!("--mount {value}");
+    let mut mount = Mount::default();
+
+    for field in value.split(',') {
+        let (key, field_value) = match field.split_once('=') {
+            Some((key, field_value)) => (key, Some(field_value)),
+            None => (field, None),
+        };
+
+        match (key, field_value) {
+            ("type", Some(typ)) => {
+                mount.typ = Some(match typ {
+                    "bind" => MountTypeEnum::BIND,
+                    "volume" => MountTypeEnum::VOLUME,
+                    "tmpfs" => MountTypeEnum::TMPFS,
+                    _ => return Err(invalid(&option, format!("unsupported type '{typ}'"))),
+                })
+            }
+            ("source" | "src", Some(source)) => mount.source = Some(source.to_string()),
+            ("target" | "destination" | "dst", Some(target)) => {
+                mount.target = Some(target.to_string())
+            }
+            ("readonly" | "ro", None) => mount.read_only = Some(true),
+            ("readonly" | "ro", Some(read_only)) => {
+                mount.read_only = Some(parse_bool(&option, read_only)?)
+            }
+            _ => return Err(invalid(&option, format!("unsupported field '{field}'"))),
+        }
+    }
+
+    if mount.typ.is_none() {
+        mount.typ = Some(MountTypeEnum::VOLUME);
+    }
+    if mount.typ == Some(MountTypeEnum::BIND) {
+        mount.source = mount
+            .source
+            .map(|source| resolve_source(&source, config_dir));
+    }
+
+    Ok(mount)
+}
+
+/// Translates a mount from the `mounts` field, whose source has already been
+/// resolved
+fn config_mount(mount: &configuration::Mount) -> Mount {
+    Mount {
+        typ: Some(match mount.kind {
+            MountType::Bind => MountTypeEnum::BIND,
+            MountType::Volume => MountTypeEnum::VOLUME,
+            MountType::Tmpfs => MountTypeEnum::TMPFS,
+        }),
+        source: mount.source.clone(),
+        target: Some(mount.target.clone()),
+        read_only: mount.readonly.then_some(true),
+        ..Default::default()
+    }
+}
+
+/// Adds a port published with `[ip:][host_port:]container_port[/protocol]`
+fn add_port(
+    value: &str,
+    exposed_ports: &mut HashMap<String, HashMap<(), ()>>,
+    port_bindings: &mut HashMap<String, Option<Vec<PortBinding>>>,
+) -> Result<(), DockerError> {
+    let (addresses, protocol) = value.split_once('/').unwrap_or((value, "tcp"));
+    let mut parts = addresses.rsplitn(3, ':');
+    let container_port = parts.next().unwrap_or_default();
+
+    if container_port.parse::<u16>().is_err() {
+        return Err(invalid(
+            &format!("--publish {value}"),
+            "expected [ip:][host_port:]container_port[/protocol]",
+        ));
+    }
+
+    let key = format!("{container_port}/{protocol}");
+    let binding = PortBinding {
+        host_port: Some(parts.next().unwrap_or_default().to_string()),
+        host_ip: Some(parts.next().unwrap_or_default().to_string()),
+    };
+
+    exposed_ports.insert(key.clone(), HashMap::new());
+    port_bindings
+        .entry(key)
+        .or_insert_with(|| Some(Vec::new()))
+        .get_or_insert_with(Vec::new)
+        .push(binding);
+
+    Ok(())
+}
+
+/// Builds the container configuration from the `create_options`, `mounts` and
+/// `workspace` of an environment, returning it alongside the requested platform.
+/// `dirs` holds the directory each of the `create_options` was defined in.
+pub fn create_config(
+    environment: &Environment,
+    dirs: &[PathBuf],
+) -> Result<(Config<String>, Option<String>), DockerError> {
+    let mut config = Config::<String>::default();
+    let mut host = HostConfig::default();
+    let mut platform = None;
+
+    // Placed first so `-e` and `--env-file` options take priority
+    let mut env = environment_variables(environment)?;
+    let mut labels = HashMap::new();
+    let mut volumes = HashMap::new();
+    let mut exposed_ports = HashMap::new();
+    let mut port_bindings = HashMap::new();
+
+    fn push(list: &mut Option<Vec<String>>, value: String) {
+        list.get_or_insert_with(Vec::new).push(value);
+    }
+
+    for (flag, value, index) in parse_flags(&environment.create_options, CREATE_FLAGS)? {
+        let option = format!("--{flag} {value}");
+        let config_dir = &dirs[index];
+        match flag {
+            "add-host" => push(&mut host.extra_hosts, value),
+            "cap-add" => push(&mut host.cap_add, value),
+            "cap-drop" => push(&mut host.cap_drop, value),
+            "cgroup-parent" => host.cgroup_parent = Some(value),
+            "cgroupns" => {
+                host.cgroupns_mode = Some(match value.as_str() {
+                    "host" => HostConfigCgroupnsModeEnum::HOST,
+                    "private" => HostConfigCgroupnsModeEnum::PRIVATE,
+                    _ => return Err(invalid(&option, "expected host or private")),
+                })
+            }
+            "cpu-period" => host.cpu_period = Some(parse_number(&option, &value)?),
+            "cpu-quota" => host.cpu_quota = Some(parse_number(&option, &value)?),
+            "cpu-shares" => host.cpu_shares = Some(parse_number(&option, &value)?),
+            "cpus" => {
+                let cpus: f64 = value
+                    .parse()
+                    .map_err(|_| invalid(&option, "expected a number of CPUs"))?;
+                host.nano_cpus = Some((cpus * 1e9) as i64);
+            }
+            // Containers are always created detached, so this is accepted and ignored
+            "detach" => {}
+            "device" => {
+                let mut parts = value.splitn(3, ':');
+                let path_on_host = parts.next().unwrap_or_default().to_string();
+                let path_in_container = parts
+                    .next()
+                    .map(str::to_string)
+                    .unwrap_or(path_on_host.clone());
+                let cgroup_permissions = parts.next().unwrap_or("rwm").to_string();
+
+                host.devices
+                    .get_or_insert_with(Vec::new)
+                    .push(DeviceMapping {
+                        path_on_host: Some(path_on_host),
+                        path_in_container: Some(path_in_container),
+                        cgroup_permissions: Some(cgroup_permissions),
+                    });
+            }
+            "cpuset-cpus" => host.cpuset_cpus = Some(value),
+            "cpuset-mems" => host.cpuset_mems = Some(value),
+            "device-cgroup-rule" => push(&mut host.device_cgroup_rules, value),
+            "dns" => push(&mut host.dns, value),
+            "dns-opt" | "dns-option" => push(&mut host.dns_options, value),
+            "dns-search" => push(&mut host.dns_search, value),
+            "domainname" => config.domainname = Some(value),
+            "entrypoint" => {
+                config.entrypoint =
+                    Some(shell_words::split(&value).map_err(|e| invalid(&option, e.to_string()))?)
+            }
+            "env" => env.extend(env_from_host(&value)),
+            "env-file" => env.extend(read_env_file(&value, config_dir)?),
+            "expose" => {
+                let port = if value.contains('/') {
+                    value
+                } else {
+                    format!("{value}/tcp")
+                };
+                exposed_ports.insert(port, HashMap::new());
+            }
+            "gpus" => host
+                .device_requests
+                .get_or_insert_with(Vec::new)
+                .push(parse_gpus(&option, &value)?),
+            "group-add" => push(&mut host.group_add, value),
+            "hostname" => config.hostname = Some(value),
+            "init" => host.init = Some(parse_bool(&option, &value)?),
+            "interactive" => {
+                let interactive = parse_bool(&option, &value)?;
+                config.open_stdin = Some(interactive);
+                config.attach_stdin = Some(interactive);
+            }
+            "ipc" => host.ipc_mode = Some(value),
+            "label" => {
+                let (key, label) = value.split_once('=').unwrap_or((&value, ""));
+                labels.insert(key.to_string(), label.to_string());
+            }
+            "link" => push(&mut host.links, value),
+            "log-driver" => {
+                host.log_config
+                    .get_or_insert_with(HostConfigLogConfig::default)
+                    .typ = Some(value)
+            }
+            "log-opt" => {
+                let (key, log_opt) = key_value(&option, &value)?;
+                host.log_config
+                    .get_or_insert_with(HostConfigLogConfig::default)
+                    .config
+                    .get_or_insert_with(HashMap::new)
+                    .insert(key.to_string(), log_opt.to_string());
+            }
+            "mac-address" => config.mac_address = Some(value),
+            "memory" => host.memory = Some(parse_size(&option, &value)?),
+            "memory-reservation" => host.memory_reservation = Some(parse_size(&option, &value)?),
+            // `-1` allows unlimited swap
+            "memory-swap" => host.memory_swap = Some(parse_size(&option, &value)?),
+            "memory-swappiness" => host.memory_swappiness = Some(parse_number(&option, &value)?),
+            "mount" => host
+                .mounts
+                .get_or_insert_with(Vec::new)
+                .push(parse_mount(&value, config_dir)?),
+            "net" | "network" => host.network_mode = Some(value),
+            "oom-kill-disable" => host.oom_kill_disable = Some(parse_bool(&option, &value)?),
+            "oom-score-adj" => host.oom_score_adj = Some(parse_number(&option, &value)?),
+            "pid" => host.pid_mode = Some(value),
+            "pids-limit" => host.pids_limit = Some(parse_number(&option, &value)?),
+            "platform" => platform = Some(value),
+            "privileged" => host.privileged = Some(parse_bool(&option, &value)?),
+            "publish" => add_port(&value, &mut exposed_ports, &mut port_bindings)?,
+            "publish-all" => host.publish_all_ports = Some(parse_bool(&option, &value)?),
+            "read-only" => host.readonly_rootfs = Some(parse_bool(&option, &value)?),
+            "restart" => host.restart_policy = Some(parse_restart(&option, &value)?),
+            "rm" => host.auto_remove = Some(parse_bool(&option, &value)?),
+            "runtime" => host.runtime = Some(value),
+            "security-opt" => push(&mut host.security_opt, value),
+            "shm-size" => host.shm_size = Some(parse_size(&option, &value)?),
+            "stop-signal" => config.stop_signal = Some(value),
+            "stop-timeout" => config.stop_timeout = Some(parse_number(&option, &value)?),
+            "storage-opt" => {
+                let (key, storage_opt) = key_value(&option, &value)?;
+                host.storage_opt
+                    .get_or_insert_with(HashMap::new)
+                    .insert(key.to_string(), storage_opt.to_string());
+            }
+            "sysctl" => {
+                let (key, sysctl) = key_value(&option, &value)?;
+                host.sysctls
+                    .get_or_insert_with(HashMap::new)
+                    .insert(key.to_string(), sysctl.to_string());
+            }
+            "tmpfs" => {
+                let (path, tmpfs) = value.split_once(':').unwrap_or((&value, ""));
+                host.tmpfs
+                    .get_or_insert_with(HashMap::new)
+                    .insert(path.to_string(), tmpfs.to_string());
+            }
+            "tty" => config.tty = Some(parse_bool(&option, &value)?),
+            "ulimit" => host
+                .ulimits
+                .get_or_insert_with(Vec::new)
+                .push(parse_ulimit(&option, &value)?),
+            "user" => config.user = Some(value),
+            "userns" => host.userns_mode = Some(value),
+            "uts" => host.uts_mode = Some(value),
+            "volume" => match value.split_once(':') {
+                Some((source, rest)) => {
+                    push(
+                        &mut host.binds,
+                        format!("{}:{rest}", resolve_source(source, config_dir)),
+                    );
+                }
+                None => {
+                    volumes.insert(value, HashMap::new());
+                }
+            },
+            "volume-driver" => host.volume_driver = Some(value),
+            "volumes-from" => push(&mut host.volumes_from, value),
+            "workdir" => config.working_dir = Some(value),
+            _ => unreachable!("Every create flag is handled"),
+        }
+    }
+
+    if !env.is_empty() {
+        config.env = Some(env);
+    }
+    if !labels.is_empty() {
+        config.labels = Some(labels);
+    }
+    if !volumes.is_empty() {
+        config.volumes = Some(volumes);
+    }
+    if !exposed_ports.is_empty() {
+        config.exposed_ports = Some(exposed_ports);
+    }
+    if !port_bindings.is_empty() {
+        host.port_bindings = Some(port_bindings);
+    }
+    if !environment.mounts.is_empty() {
+        host.mounts
+            .get_or_insert_with(Vec::new)
+            .extend(environment.mounts.iter().map(config_mount));
+    }
+    if let Some(workspace) = &environment.workspace {
+        host.mounts.get_or_insert_with(Vec::new).push(Mount {
+            typ: Some(MountTypeEnum::BIND),
+            source: Some(workspace.source.display().to_string()),
+            target: Some(workspace.target.clone()),
+            ..Default::default()
+        });
+    }
+    config.host_config = Some(host);
+
+    Ok((config, platform))
+}
+
+/// Builds the request to run `cmd` in a container from the `exec_options` of
+/// an environment, attaching to its output. `dirs` holds the directory each
+/// option was defined in.
+pub fn exec_config(
+    options: &[String],
+    dirs: &[PathBuf],
+    cmd: Vec<String>,
+) -> Result<CreateExecOptions<String>, DockerError> {
+    let mut exec = CreateExecOptions {
+        attach_stdout: Some(true),
+        attach_stderr: Some(true),
+        cmd: Some(cmd),
+        ..Default::default()
+    };
+    let mut env = Vec::new();
+
+    for (flag, value, index) in parse_flags(options, EXEC_FLAGS)? {
+        let option = format!("--{flag} {value}");
+        let config_dir = &dirs[index];
+        match flag {
+            "env" => env.extend(env_from_host(&value)),
+            "env-file" => env.extend(read_env_file(&value, config_dir)?),
+            // Nothing is sent to the command's input, so this is accepted and ignored
+            "interactive" => {}
+            "privileged" => exec.privileged = Some(parse_bool(&option, &value)?),
+            "tty" => exec.tty = Some(parse_bool(&option, &value)?),
+            "user" => exec.user = Some(value),
+            "workdir" => exec.working_dir = Some(value),
+            _ => unreachable!("Every exec flag is handled"),
+        }
+    }
+
+    if !env.is_empty() {
+        exec.env = Some(env);
+    }
+
+    Ok(exec)
+}
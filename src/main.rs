@@ -1,6 +1,7 @@
-use berth::cli;
-use berth::util::Spinner;
-use berth::{cli::AppConfig, configuration::Configuration, docker::DockerHandler};
+use berth::cli::{self, OutputFormat};
+use berth::{
+    actions, cli::AppConfig, configuration::Configuration, docker::DockerHandler, util::to_json,
+};
 use log::info;
 use log4rs::append::file::FileAppender;
 use log4rs::config::{Appender, Config, Root};
@@ -25,28 +26,6 @@ fn init_logger() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-async fn build(docker: &DockerHandler) -> Result<()> {
-    docker.create_new_environment().await?;
-    docker.stop_container_if_running().await?;
-
-    Ok(())
-}
-
-async fn up(docker: &DockerHandler) -> Result<()> {
-    if !docker.does_environment_exist().await? {
-        docker.create_new_environment().await?;
-    } else {
-        let spinner = Spinner::new("Starting Container");
-
-        docker.start_container().await?;
-
-        spinner.finish_and_clear();
-    }
-    docker.enter_environment().await?;
-
-    Ok(())
-}
-
 #[tokio::main]
 async fn main() -> Result<()> {
     init_logger().expect("Failed to setup logger");
@@ -56,33 +35,68 @@ async fn main() -> Result<()> {
     let args = std::env::args_os();
     let app_config = AppConfig::new(args)?;
 
-    eprintln!("Using config file at {:?}", app_config.config_path);
+    if app_config.action == cli::Action::Schema {
+        println!("{}", Configuration::schema()?);
+        return Ok(());
+    }
 
-    let environment = Configuration::new(&app_config)?.find_environment_from_configuration()?;
+    for config_path in &app_config.config_paths {
+        eprintln!("Using config file at {:?}", config_path);
+    }
 
-    let docker = DockerHandler::new(environment.clone(), &app_config.config_path)?;
+    let mut configuration = Configuration::new(&app_config)?;
 
-    let result = {
-        match &app_config.action {
-            cli::Action::Up => up(&docker).await,
-            cli::Action::Build => build(&docker).await,
-            cli::Action::View => {
-                println!("{}", environment.view()?);
-                return Ok(());
-            }
-        }
-    };
+    if app_config.action == cli::Action::Check {
+        return actions::check(&mut configuration);
+    }
 
-    if let Err(command_error) = result {
-        docker.stop_container_if_running().await?;
-        docker.delete_container_if_exists().await?;
-        return Err(command_error);
+    if let cli::Action::List { format } = app_config.action {
+        let environments = configuration.find_all_environments()?;
+        let engine = configuration.engine().connect()?;
+        return actions::list(environments, engine, format).await;
     }
 
-    if app_config.cleanup {
-        docker.delete_container_if_exists().await?;
+    if let cli::Action::Gc {
+        dry_run,
+        all,
+        older_than,
+    } = app_config.action
+    {
+        let environments = configuration.find_all_environments()?;
+        let engine = configuration.engine().connect()?;
+        return actions::gc(
+            &environments,
+            &configuration.config_paths(),
+            engine,
+            dry_run,
+            all,
+            older_than,
+        )
+        .await;
     }
 
+    let environment = configuration.find_environment_from_configuration()?;
+
+    if let cli::Action::View {
+        explain,
+        resolved,
+        format,
+    } = app_config.action
+    {
+        match (explain, resolved, format) {
+            (_, _, OutputFormat::Json) => println!("{}", to_json(&environment)?),
+            (true, _, _) => print!("{}", environment.explain()?),
+            (_, true, _) => println!("{}", environment.resolved_view()?),
+            _ => println!("{}", environment.view()?),
+        }
+        return Ok(());
+    }
+
+    let engine = configuration.engine().connect()?;
+    let docker = DockerHandler::new(environment, engine)?;
+
+    actions::run(&docker, &app_config.action, app_config.cleanup).await?;
+
     info!("Done!");
 
     Ok(())
@@ -1,4 +1,8 @@
-use std::{collections::HashMap, env, time::Duration};
+use std::{
+    collections::HashMap,
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use indicatif::{ProgressBar, ProgressStyle};
 
@@ -52,6 +56,59 @@ impl Spinner {
     }
 }
 
+/// Formats rows into left aligned columns separated by three spaces,
+/// the first row is treated as the header
+pub fn format_table(rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = Vec::new();
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            let width = cell.chars().count();
+            match widths.get_mut(i) {
+                Some(w) => *w = (*w).max(width),
+                None => widths.push(width),
+            }
+        }
+    }
+
+    let mut table = String::new();
+    for row in rows {
+        let line = row
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| format!("{:<width$}", cell, width = widths[i]))
+            .collect::<Vec<_>>()
+            .join("   ");
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// Formats a value as pretty printed JSON for `--format json`
+pub fn to_json<T: serde::Serialize>(value: &T) -> miette::Result<String> {
+    serde_json::to_string_pretty(value).unexpected()
+}
+
+/// Formats a unix timestamp as a human readable age, e.g. "3 hours ago"
+pub fn format_age(timestamp: i64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(timestamp);
+    let seconds = (now - timestamp).max(0);
+
+    let (amount, unit) = match seconds {
+        s if s < 60 => return "less than a minute ago".to_string(),
+        s if s < 60 * 60 => (s / 60, "minute"),
+        s if s < 60 * 60 * 24 => (s / (60 * 60), "hour"),
+        s if s < 60 * 60 * 24 * 7 => (s / (60 * 60 * 24), "day"),
+        s => (s / (60 * 60 * 24 * 7), "week"),
+    };
+
+    let plural = if amount == 1 { "" } else { "s" };
+    format!("{amount} {unit}{plural} ago")
+}
+
 pub trait UnexpectedExt<T> {
     fn unexpected(self) -> miette::Result<T>;
 }
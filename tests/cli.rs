@@ -1,10 +1,14 @@
-use berth::cli::AppConfig;
+use berth::{
+    cli::{Action, AppConfig, OutputFormat},
+    docker::Engine,
+};
 use color_eyre::Result;
-use indoc::indoc;
+use indoc::{formatdoc, indoc};
 use pretty_assertions::assert_eq;
 use std::{
     fs::{self},
     path::PathBuf,
+    time::Duration,
 };
 use tempfile::{NamedTempFile, TempDir};
 use test_utils::TestOutput;
@@ -26,7 +30,8 @@ fn no_commands() {
         error: the following required arguments were not provided:
           <ENVIRONMENT>
  
-        Usage: berth <ENVIRONMENT>
+        Usage: berth [OPTIONS] <ENVIRONMENT>
+               berth [OPTIONS] <COMMAND>
   
         For more information, try '--help'.
         "#
@@ -44,15 +49,29 @@ fn help() -> Result<()> {
             berth, A CLI to help create development environments without touching repository code
 
             Usage: berth [OPTIONS] <ENVIRONMENT>
+                   berth [OPTIONS] <COMMAND>
+
+            Commands:
+              up      Start and enter an environment, building it first if required
+              build   Build/rebuild an environment without entering it
+              view    View environment definition after it has been parsed by berth
+              diff    Show what has changed in an environment's configuration since its container was created
+              list    List the environments defined in the config file and the state of their containers
+              status  Show the state of an environment's container
+              stop    Stop an environment's container if it is running
+              rm      Remove an environment's container
+              gc      Remove containers and images left behind by changed or deleted environments [aliases: prune]
+              check   Check every environment in the config files for problems without creating anything
+              schema  Print a JSON Schema of the config file format for editors to use
+              help    Print this message or the help of the given subcommand(s)
 
             Arguments:
-              <ENVIRONMENT>  The environment to be used
+              <ENVIRONMENT>  The environment to be used, shorthand for `berth up <ENVIRONMENT>`
 
             Options:
                   --config-path <FILE>  Path to config file
+                  --engine <ENGINE>     Container engine to use, overriding the engine set in the config file [possible values: docker, podman]
                   --cleanup             Deletes container on exit
-                  --build               Build/rebuild the environment instead of starting it
-                  --view                View environment definition after it has been parsed by berth
               -h, --help                Print help
   
             "#
@@ -122,8 +141,147 @@ fn env_name_with_config_in_home_path() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn check_valid_config() -> Result<()> {
+    TestOutput::new()
+        .config(indoc!(
+            r#"
+            image = "alpine:edge"
+            entry_cmd = "/bin/ash"
+            "#,
+        ))?
+        .args(vec!["--config-path", "[config_path]", "check"])?
+        .stdout("No problems found in 1 environment\n")?
+        .stderr("Using config file at \"[config_path]\"\n")?
+        .code(0)?
+        .run()
+}
+
+#[test]
+fn check_env_file_with_relative_config_path() -> Result<()> {
+    let tmp_dir = TempDir::new().unwrap();
+    fs::write(tmp_dir.path().join(".env"), "A=1\n").unwrap();
+
+    TestOutput::new()
+        .config_with_path(
+            indoc!(
+                r#"
+            image = "alpine:edge"
+            entry_cmd = "/bin/ash"
+            env_file = ".env"
+            "#,
+            ),
+            &tmp_dir.path().join("berth.toml"),
+        )?
+        .args(vec!["--config-path", "berth.toml", "check"])?
+        .working_dir(tmp_dir.path().to_str().unwrap())?
+        .stdout("No problems found in 1 environment\n")?
+        .stderr("Using config file at \"berth.toml\"\n")?
+        .code(0)?
+        .run()?;
+
+    tmp_dir.close().unwrap();
+    Ok(())
+}
+
+#[test]
+fn bind_mount_source_with_relative_config_path() -> Result<()> {
+    let tmp_dir = TempDir::new().unwrap();
+    let config_dir = tmp_dir.path().join("project");
+    fs::create_dir_all(config_dir.join("src")).unwrap();
+    let source = config_dir.canonicalize().unwrap().join("src");
+
+    TestOutput::new()
+        .config_with_path(
+            indoc!(
+                r#"
+            image = "alpine:edge"
+            entry_cmd = "/bin/ash"
+            mounts = [{ source = "src", target = "/src" }]
+            "#,
+            ),
+            &config_dir.join("berth.toml"),
+        )?
+        .args(vec![
+            "--config-path",
+            "project/berth.toml",
+            "view",
+            "[name]",
+        ])?
+        .working_dir(tmp_dir.path().to_str().unwrap())?
+        .stdout(formatdoc!(
+            r#"
+            [environment.[name]]
+            image = "alpine:edge"
+            entry_cmd = "/bin/ash"
+            mounts = [{{ source = "{}", target = "/src" }}]
+
+            "#,
+            source.display()
+        ))?
+        .stderr("Using config file at \"project/berth.toml\"\n")?
+        .code(0)?
+        .run()?;
+
+    tmp_dir.close().unwrap();
+    Ok(())
+}
+
+#[test]
+fn view_round_trips_with_relative_config_path() -> Result<()> {
+    let tmp_dir = TempDir::new().unwrap();
+    let config_dir = tmp_dir.path().join("project");
+    fs::create_dir_all(config_dir.join("ctx")).unwrap();
+    fs::write(config_dir.join("Dockerfile"), "FROM alpine:edge\n").unwrap();
+    let dir = config_dir.canonicalize().unwrap();
+
+    let view = formatdoc!(
+        r#"
+        dockerfile = "{0}/Dockerfile"
+        build_context = "{0}/ctx"
+        entry_cmd = "/bin/ash"
+        mounts = [{{ source = "{0}/ctx", target = "/ctx" }}]
+        "#,
+        dir.display()
+    );
+
+    // The view of the original file, then of the view itself
+    let configs = [
+        (
+            indoc!(
+                r#"
+                dockerfile = "Dockerfile"
+                build_context = "ctx"
+                entry_cmd = "/bin/ash"
+                mounts = [{ source = "ctx", target = "/ctx" }]
+                "#
+            ),
+            "berth.toml",
+        ),
+        (view.as_str(), "view.toml"),
+    ];
+    for (content, file_name) in configs {
+        let config_path = format!("project/{file_name}");
+        TestOutput::new()
+            .config_with_path(content, &config_dir.join(file_name))?
+            .args(vec!["--config-path", &config_path, "view", "[name]"])?
+            .working_dir(tmp_dir.path().to_str().unwrap())?
+            .stdout(format!("[environment.[name]]\n{view}\n"))?
+            .stderr(format!("Using config file at {config_path:?}\n"))?
+            .code(0)?
+            .run()?;
+    }
+
+    tmp_dir.close().unwrap();
+    Ok(())
+}
+
 #[test]
 fn env_name_with_no_config_in_env() -> Result<()> {
+    // A git repository stops the search for a project config file
+    let tmp_dir = TempDir::new().unwrap();
+    fs::create_dir(tmp_dir.path().join(".git")).unwrap();
+
     // Note: TestOutput doesn't inherit envs
     TestOutput::new()
         .config(indoc!(
@@ -133,14 +291,118 @@ fn env_name_with_no_config_in_env() -> Result<()> {
             "#,
         ))?
         .args(vec!["[name]"])?
+        .working_dir(tmp_dir.path().to_str().unwrap())?
         .stderr(indoc!(
             r#"
-            Error:   × Could not find config file in $XDG_CONFIG_HOME or $HOME
+            Error:   × Could not find a .berth.toml or berth.toml in the current directory or its
+              │ parents, or a config file in $XDG_CONFIG_HOME or $HOME
             
             "#
         ))?
         .code(1)?
-        .run()
+        .run()?;
+
+    tmp_dir.close().unwrap();
+    Ok(())
+}
+
+#[test]
+fn env_name_with_project_config_in_parent_dir() -> Result<()> {
+    let tmp_dir = TempDir::new().unwrap();
+    let project_dir = tmp_dir.path().join("project");
+    let working_dir = project_dir.join("src").join("nested");
+    fs::create_dir_all(&working_dir).unwrap();
+    fs::create_dir(project_dir.join(".git")).unwrap();
+    let file_path = project_dir.join(".berth.toml");
+
+    TestOutput::new()
+        .config_with_path(
+            indoc!(
+                r#"
+            image = "alpine:edge"
+            entry_cmd = "/bin/ash"
+            "#,
+            ),
+            &file_path,
+        )?
+        .args(vec!["view", "[name]"])?
+        .working_dir(working_dir.to_str().unwrap())?
+        .stdout(indoc!(
+            r#"
+            [environment.[name]]
+            image = "alpine:edge"
+            entry_cmd = "/bin/ash"
+
+            "#
+        ))?
+        .stderr(format!(
+            "Using config file at {:?}\n",
+            file_path.canonicalize().unwrap()
+        ))?
+        .code(0)?
+        .run()?;
+
+    tmp_dir.close().unwrap();
+    Ok(())
+}
+
+#[test]
+fn project_config_is_layered_on_global_config() -> Result<()> {
+    let tmp_dir = TempDir::new().unwrap();
+    let global_path = tmp_dir
+        .path()
+        .join(".config")
+        .join("berth")
+        .join("config.toml");
+    fs::create_dir_all(global_path.parent().unwrap()).unwrap();
+    fs::write(
+        &global_path,
+        indoc!(
+            r#"
+            [preset.Tools]
+            exec_cmds = ["apk add helix"]
+            "#
+        ),
+    )
+    .unwrap();
+
+    let project_dir = tmp_dir.path().join("project");
+    fs::create_dir_all(project_dir.join(".git")).unwrap();
+    let project_path = project_dir.join("berth.toml");
+
+    TestOutput::new()
+        .config_with_path(
+            indoc!(
+                r#"
+            image = "alpine:edge"
+            entry_cmd = "/bin/ash"
+            presets = ["Tools"]
+            "#,
+            ),
+            &project_path,
+        )?
+        .args(vec!["view", "[name]"])?
+        .working_dir(project_dir.to_str().unwrap())?
+        .envs(vec![("HOME", tmp_dir.path().to_str().unwrap())])?
+        .stdout(indoc!(
+            r#"
+            [environment.[name]]
+            image = "alpine:edge"
+            entry_cmd = "/bin/ash"
+            exec_cmds = ["apk add helix"]
+
+            "#
+        ))?
+        .stderr(format!(
+            "Using config file at {:?}\nUsing config file at {:?}\n",
+            global_path,
+            project_path.canonicalize().unwrap()
+        ))?
+        .code(0)?
+        .run()?;
+
+    tmp_dir.close().unwrap();
+    Ok(())
 }
 
 #[test]
@@ -151,7 +413,10 @@ fn valid_config_file() {
 
     let app_config = AppConfig::new(args).unwrap();
     assert_eq!(app_config.environment, "Name".to_string());
-    assert_eq!(app_config.config_path.to_str(), Some(config_file_path))
+    assert_eq!(
+        app_config.config_paths,
+        vec![PathBuf::from(config_file_path)]
+    )
 }
 
 #[test]
@@ -182,6 +447,27 @@ fn incorrect_option_command() {
           tip: to pass '--bad-command' as a value, use '-- --bad-command'
 
         Usage: berth [OPTIONS] <ENVIRONMENT>
+               berth [OPTIONS] <COMMAND>
+
+        For more information, try '--help'.
+        "#
+        )
+    );
+}
+
+#[test]
+fn environment_and_subcommand_not_allowed() {
+    let args = vec!["berth", "Env", "list"];
+
+    let app_config = AppConfig::new(args).err().unwrap();
+    assert_eq!(
+        app_config.to_string(),
+        indoc!(
+            r#"
+        error: '<ENVIRONMENT>' and '--cleanup' cannot be used with a subcommand
+
+        Usage: berth [OPTIONS] <ENVIRONMENT>
+               berth [OPTIONS] <COMMAND>
 
         For more information, try '--help'.
         "#
@@ -190,17 +476,250 @@ fn incorrect_option_command() {
 }
 
 #[test]
-fn no_two_actions_allowed() {
-    let args = vec!["berth", "--build", "--view", "Env"];
+fn environment_is_shorthand_for_up() {
+    let config_file = NamedTempFile::new().unwrap();
+    let config_file_path = config_file.path().to_str().unwrap();
+
+    let shorthand = vec![
+        "berth",
+        "--config-path",
+        config_file_path,
+        "--cleanup",
+        "Env",
+    ];
+    let app_config = AppConfig::new(shorthand).unwrap();
+    assert_eq!(app_config.action, Action::Up);
+    assert!(app_config.cleanup);
+    assert_eq!(app_config.environment, "Env");
+
+    let subcommand = vec![
+        "berth",
+        "up",
+        "--cleanup",
+        "--config-path",
+        config_file_path,
+        "Env",
+    ];
+    let app_config = AppConfig::new(subcommand).unwrap();
+    assert_eq!(app_config.action, Action::Up);
+    assert!(app_config.cleanup);
+    assert_eq!(app_config.environment, "Env");
+}
+
+#[test]
+fn subcommands() {
+    let config_file = NamedTempFile::new().unwrap();
+    let config_file_path = config_file.path().to_str().unwrap();
+
+    let cases = [
+        (vec!["build", "Env"], Action::Build),
+        (
+            vec!["view", "Env"],
+            Action::View {
+                explain: false,
+                resolved: false,
+                format: OutputFormat::Text,
+            },
+        ),
+        (
+            vec!["view", "--explain", "Env"],
+            Action::View {
+                explain: true,
+                resolved: false,
+                format: OutputFormat::Text,
+            },
+        ),
+        (
+            vec!["view", "--resolved", "Env"],
+            Action::View {
+                explain: false,
+                resolved: true,
+                format: OutputFormat::Text,
+            },
+        ),
+        (
+            vec!["view", "--format", "json", "Env"],
+            Action::View {
+                explain: false,
+                resolved: false,
+                format: OutputFormat::Json,
+            },
+        ),
+        (
+            vec!["status", "Env"],
+            Action::Status {
+                format: OutputFormat::Text,
+            },
+        ),
+        (
+            vec!["status", "--format", "json", "Env"],
+            Action::Status {
+                format: OutputFormat::Json,
+            },
+        ),
+        (vec!["diff", "Env"], Action::Diff),
+        (vec!["stop", "Env"], Action::Stop),
+        (vec!["rm", "Env"], Action::Rm { image: false }),
+        (vec!["rm", "--image", "Env"], Action::Rm { image: true }),
+    ];
+
+    for (command, action) in cases {
+        let args = ["berth", "--config-path", config_file_path]
+            .into_iter()
+            .chain(command);
+        let app_config = AppConfig::new(args).unwrap();
+        assert_eq!(app_config.action, action);
+        assert_eq!(app_config.environment, "Env");
+        assert!(!app_config.cleanup);
+    }
+
+    let args = vec!["berth", "--config-path", config_file_path, "check"];
+    let app_config = AppConfig::new(args).unwrap();
+    assert_eq!(app_config.action, Action::Check);
+
+    // The schema is printed without reading a config file
+    let args = vec!["berth", "--config-path", "nonexistent.toml", "schema"];
+    let app_config = AppConfig::new(args).unwrap();
+    assert_eq!(app_config.action, Action::Schema);
+    assert!(app_config.config_paths.is_empty());
+
+    let args = vec!["berth", "--config-path", config_file_path, "list"];
+    let app_config = AppConfig::new(args).unwrap();
+    assert_eq!(
+        app_config.action,
+        Action::List {
+            format: OutputFormat::Text
+        }
+    );
+
+    let args = vec![
+        "berth",
+        "--config-path",
+        config_file_path,
+        "list",
+        "--format",
+        "json",
+    ];
+    let app_config = AppConfig::new(args).unwrap();
+    assert_eq!(
+        app_config.action,
+        Action::List {
+            format: OutputFormat::Json
+        }
+    );
+
+    let args = vec![
+        "berth",
+        "--config-path",
+        config_file_path,
+        "view",
+        "--explain",
+        "--format",
+        "json",
+        "Env",
+    ];
+    assert!(AppConfig::new(args).is_err());
+}
+
+#[test]
+fn gc_options() {
+    let config_file = NamedTempFile::new().unwrap();
+    let config_file_path = config_file.path().to_str().unwrap();
+
+    let cases = [
+        (
+            vec!["gc"],
+            Action::Gc {
+                dry_run: false,
+                all: false,
+                older_than: None,
+            },
+        ),
+        (
+            vec!["prune", "--dry-run", "--all"],
+            Action::Gc {
+                dry_run: true,
+                all: true,
+                older_than: None,
+            },
+        ),
+        (
+            vec!["gc", "--older-than", "90s"],
+            Action::Gc {
+                dry_run: false,
+                all: false,
+                older_than: Some(Duration::from_secs(90)),
+            },
+        ),
+        (
+            vec!["gc", "--older-than", "2w"],
+            Action::Gc {
+                dry_run: false,
+                all: false,
+                older_than: Some(Duration::from_secs(2 * 7 * 24 * 60 * 60)),
+            },
+        ),
+    ];
+
+    for (command, action) in cases {
+        let args = ["berth", "--config-path", config_file_path]
+            .into_iter()
+            .chain(command);
+        let app_config = AppConfig::new(args).unwrap();
+        assert_eq!(app_config.action, action);
+    }
+}
+
+#[test]
+fn engine_option() {
+    let config_file = NamedTempFile::new().unwrap();
+    let config_file_path = config_file.path().to_str().unwrap();
+
+    let cases = [
+        (vec!["Env"], None),
+        (vec!["--engine", "podman", "Env"], Some(Engine::Podman)),
+        (
+            vec!["build", "--engine", "docker", "Env"],
+            Some(Engine::Docker),
+        ),
+    ];
+
+    for (command, engine) in cases {
+        let args = ["berth", "--config-path", config_file_path]
+            .into_iter()
+            .chain(command);
+        let app_config = AppConfig::new(args).unwrap();
+        assert_eq!(app_config.engine, engine);
+    }
+}
+
+#[test]
+fn gc_age_overflow() {
+    let args = vec!["berth", "gc", "--older-than", "30500000000000000w"];
 
     let app_config = AppConfig::new(args).err().unwrap();
     assert_eq!(
         app_config.to_string(),
         indoc!(
             r#"
-        error: the argument '--build' cannot be used with '--view'
+        error: invalid value '30500000000000000w' for '--older-than <AGE>': '30500000000000000w' is not an age such as 30s, 30m, 12h, 7d or 2w
 
-        Usage: berth --build <ENVIRONMENT>
+        For more information, try '--help'.
+        "#
+        )
+    );
+}
+
+#[test]
+fn gc_invalid_age() {
+    let args = vec!["berth", "gc", "--older-than", "7days"];
+
+    let app_config = AppConfig::new(args).err().unwrap();
+    assert_eq!(
+        app_config.to_string(),
+        indoc!(
+            r#"
+        error: invalid value '7days' for '--older-than <AGE>': '7days' is not an age such as 30s, 30m, 12h, 7d or 2w
 
         For more information, try '--help'.
         "#
@@ -1,5 +1,11 @@
+use berth::{
+    configuration::{Configuration, Workspace, WorkspaceRoot},
+    docker::Engine,
+};
 use indoc::{formatdoc, indoc};
 use pretty_assertions::assert_eq;
+use serde_json::json;
+use std::collections::BTreeMap;
 use std::fs::{self, File};
 use std::io::Write;
 use tempfile::{NamedTempFile, TempDir};
@@ -30,6 +36,28 @@ fn multiple_envs_in_config() {
     assert_eq!(env2.entry_cmd, "init2");
 }
 
+#[test]
+fn all_environments_are_sorted() {
+    let config = ConfigTest::new(
+        r#"
+        [environment.Env2]
+        image = "image2"
+        entry_cmd = "init2"
+
+        [environment.Env1]
+        image = "image1"
+        entry_cmd = "init1"
+    "#,
+    );
+
+    let envs = config.get_all_envs().unwrap();
+    let names: Vec<_> = envs.iter().map(|env| env.original_name.as_str()).collect();
+
+    assert_eq!(names, vec!["Env1", "Env2"]);
+    assert_eq!(envs[0].image, "image1");
+    assert_eq!(envs[1].image, "image2");
+}
+
 #[test]
 fn simple_preset() {
     let config = ConfigTest::new(
@@ -88,6 +116,46 @@ fn multiple_preset() {
     );
 }
 
+#[test]
+fn nested_presets() {
+    let config = ConfigTest::new(
+        r#"
+        [preset.Interactive]
+        entry_options = ["-it"]
+
+        [preset.MountPwd]
+        create_options = ["-v $PWD:/work"]
+
+        [preset.Shared]
+        exec_cmds = ["echo shared"]
+
+        [preset.Dev]
+        entry_cmd = "init"
+        exec_cmds = ["echo dev"]
+        presets = ["Interactive", "MountPwd", "Shared"]
+
+        [preset.Tools]
+        exec_cmds = ["echo tools"]
+        presets = ["Shared"]
+
+        [environment.Env]
+        image = "image"
+        presets = ["Dev", "Tools"]
+    "#,
+    );
+
+    let env = config.get_env("Env").unwrap();
+
+    assert_eq!(env.entry_cmd, "init");
+    assert_eq!(env.entry_options, vec!["-it"]);
+    assert_eq!(
+        env.create_options,
+        vec![format!("-v {}:/work", std::env::var("PWD").unwrap())]
+    );
+    // A preset listed more than once in the tree is only merged once
+    assert_eq!(env.exec_cmds, vec!["echo dev", "echo shared", "echo tools"]);
+}
+
 #[test]
 fn dockerfile_absolute_path() {
     let dockerfile = NamedTempFile::new().expect("Failed to create temporary file for config");
@@ -107,6 +175,53 @@ fn dockerfile_absolute_path() {
     dockerfile.close().unwrap();
 }
 
+#[test]
+fn hashes_are_part_of_names() {
+    let dockerfile = NamedTempFile::new().expect("Failed to create temporary file for config");
+    let dockerfile_path = dockerfile.path().to_str().unwrap();
+
+    let config = ConfigTest::new(&formatdoc! {r#"
+        [environment.Image]
+        entry_cmd = "hello"
+        image = "image"
+
+        [environment.Dockerfile]
+        entry_cmd = "hello"
+        dockerfile = "{}"
+        "#,
+        dockerfile_path
+    });
+
+    let env = config.get_env("Image").unwrap();
+    assert_eq!(env.name, format!("berth-Image-{}", env.hash));
+    assert_eq!(env.dockerfile_hash, None);
+
+    let env = config.get_env("Dockerfile").unwrap();
+    let dockerfile_hash = env.dockerfile_hash.unwrap();
+    assert_eq!(env.name, format!("berth-Dockerfile-{}", env.hash));
+    assert_eq!(env.image, format!("berth-dockerfile-{}", dockerfile_hash));
+
+    dockerfile.close().unwrap();
+}
+
+#[test]
+fn config_hash_is_stable() {
+    // Changing the hash orphans every existing container, so it should only
+    // ever change alongside the hash version
+    let env = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "image"
+        entry_cmd = "cmd"
+        exec_cmds = ["exec1", "exec2"]
+        create_options = ["-it"]
+        "#})
+    .get_env("Env")
+    .unwrap();
+
+    assert_eq!(env.hash, "v1.4861fe245a92b985");
+    assert_eq!(env.name, "berth-Env-v1.4861fe245a92b985");
+}
+
 #[test]
 fn dockerfile_relative_to_config_file() {
     let tmp_dir = TempDir::new().unwrap();
@@ -193,6 +308,42 @@ fn view_parsed_config() {
     );
 }
 
+#[test]
+fn diff_against_previous_view() {
+    let previous = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "image"
+        entry_cmd = "cmd"
+        exec_cmds = ["exec1"]
+        create_options = ["-it"]
+        "#})
+    .get_env("Env")
+    .unwrap()
+    .view()
+    .unwrap();
+
+    let env = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "image"
+        entry_cmd = "cmd"
+        entry_options = ["-it"]
+        exec_cmds = ["exec1", "exec2"]
+        "#})
+    .get_env("Env")
+    .unwrap();
+
+    assert_eq!(
+        env.diff(&previous).unwrap(),
+        vec![
+            r#"+ entry_options = ["-it"]"#,
+            r#"- exec_cmds = ["exec1"]"#,
+            r#"+ exec_cmds = ["exec1", "exec2"]"#,
+            r#"- create_options = ["-it"]"#,
+        ]
+    );
+    assert!(env.diff(&env.view().unwrap()).unwrap().is_empty());
+}
+
 #[test]
 fn test_intermediate_view_with_env_vars() {
     let dockerfile = NamedTempFile::new().expect("Failed to create temporary dockerfile");
@@ -233,6 +384,175 @@ fn test_intermediate_view_with_env_vars() {
     assert_eq!(view_output, expected);
 }
 
+#[test]
+fn view_round_trips() {
+    let tmp_dir = TempDir::new().unwrap();
+    let config_path = tmp_dir.path().join("config.toml");
+    let view_path = tmp_dir.path().join("view.toml");
+
+    File::create(tmp_dir.path().join("dockerfile")).unwrap();
+    fs::create_dir(tmp_dir.path().join("context")).unwrap();
+
+    let content = indoc! {r#"
+        [preset.Preset]
+        exec_cmds = ["exec2"]
+        cp_cmds = ["file.txt CONTAINER:/root/file.txt"]
+        mounts = [{ source = "context", target = "/context", readonly = true }]
+
+        [environment.Base]
+        dockerfile = "dockerfile"
+        build_context = "context"
+        entry_cmd = "init"
+        entry_options = ["-it"]
+
+        [environment.Env]
+        extends = "Base"
+        presets = ["Preset"]
+        exec_cmds = ["exec1"]
+        create_options = ["--privileged"]
+        mounts = [{ target = "/tmp", type = "tmpfs" }]
+        "#};
+    fs::write(&config_path, content).unwrap();
+
+    let env = ConfigTest::from_file(&config_path).get_env("Env").unwrap();
+    let view = env.view().unwrap();
+    fs::write(&view_path, &view).unwrap();
+
+    let viewed_env = ConfigTest::from_file(&view_path).get_env("Env").unwrap();
+
+    assert_eq!(viewed_env.name, env.name);
+    assert_eq!(viewed_env.view().unwrap(), view);
+    assert!(view.contains(r#"cp_cmds = ["file.txt CONTAINER:/root/file.txt"]"#));
+    assert!(view.contains(r#"build_context = "#));
+
+    tmp_dir.close().unwrap();
+}
+
+#[test]
+fn resolved_view() {
+    let tmp_dir = TempDir::new().unwrap();
+    let config_path = tmp_dir.path().join("config.toml");
+    let dockerfile_path = tmp_dir.path().join("dockerfile");
+    File::create(&dockerfile_path).unwrap();
+
+    let content = indoc! {r#"
+        [environment.Env]
+        dockerfile = "dockerfile"
+        entry_cmd = "init"
+        cp_cmds = ["-a 'my file.txt' CONTAINER:/root/file.txt", "CONTAINER:/etc/hosts hosts"]
+        "#};
+    fs::write(&config_path, content).unwrap();
+
+    let env = ConfigTest::from_file(&config_path).get_env("Env").unwrap();
+    let dir = tmp_dir.path().display();
+
+    assert_eq!(
+        env.resolved_view().unwrap(),
+        formatdoc! {r#"
+            [environment.Env]
+            name = "{}"
+            image = "{}"
+            dockerfile = "{}"
+            build_context = "{dir}"
+            entry_cmd = "init"
+            cp_cmds = ["-a '{dir}/my file.txt' CONTAINER:/root/file.txt", "CONTAINER:/etc/hosts {dir}/hosts"]
+            config_path = "{}"
+            "#,
+            env.name,
+            env.image,
+            dockerfile_path.display(),
+            config_path.display(),
+        }
+    );
+
+    tmp_dir.close().unwrap();
+}
+
+#[test]
+fn environment_as_json() {
+    let config = ConfigTest::new(indoc! {r#"
+        [preset.Preset]
+        exec_cmds = ["exec2"]
+
+        [environment.Env]
+        presets = ["Preset"]
+        image = "image"
+        entry_cmd = "init"
+        exec_cmds = ["exec1"]
+        "#});
+    let env = config.get_env("Env").unwrap();
+
+    assert_eq!(
+        serde_json::to_value(&env).unwrap(),
+        json!({
+            "name": env.name,
+            "config_path": config.file_path(),
+            "environment": "Env",
+            "hash": env.hash,
+            "image": "image",
+            "dockerfile_hash": null,
+            "dockerfile": null,
+            "build_context": null,
+            "entry_cmd": "init",
+            "entry_options": [],
+            "exec_cmds": ["exec1", "exec2"],
+            "exec_options": [],
+            "create_options": [],
+            "cp_cmds": [],
+            "on_create": [],
+            "on_start": [],
+            "on_enter": [],
+            "on_stop": [],
+            "host_pre_create": [],
+            "host_post_create": [],
+            "host_post_exit": [],
+            "mounts": [],
+            "workspace": null,
+            "env": {},
+            "env_file": null,
+            "env_file_hash": null,
+            "pass_env": [],
+        })
+    );
+}
+
+#[test]
+fn schema_describes_config_format() {
+    let schema: serde_json::Value =
+        serde_json::from_str(&Configuration::schema().unwrap()).unwrap();
+
+    assert_eq!(schema["title"], "berth configuration");
+    assert_eq!(schema["additionalProperties"], false);
+    assert_eq!(
+        schema["properties"]["environment"]["additionalProperties"]["$ref"],
+        "#/$defs/Environment"
+    );
+    assert_eq!(
+        schema["$defs"]["Engine"]["enum"],
+        json!(["docker", "podman"])
+    );
+
+    let environment = &schema["$defs"]["Environment"];
+    assert_eq!(environment["additionalProperties"], false);
+    assert_eq!(environment["properties"]["image"]["type"], "string");
+    assert_eq!(
+        environment["properties"]["exec_cmds"]["$ref"],
+        "#/$defs/List"
+    );
+    assert!(environment["properties"]["resolved_presets"].is_null());
+    assert!(environment["properties"]["extends"]["description"].is_string());
+
+    let preset = &schema["$defs"]["Preset"];
+    assert!(preset["properties"]["extends"].is_null());
+    assert_eq!(preset["properties"]["cp_cmds"]["$ref"], "#/$defs/List");
+
+    let strategies = &schema["$defs"]["List"]["oneOf"][1];
+    assert_eq!(strategies["maxProperties"], 1);
+    for strategy in ["append", "prepend", "replace", "remove"] {
+        assert_eq!(strategies["properties"][strategy]["type"], "array");
+    }
+}
+
 #[test]
 fn environment_not_in_config() {
     let config = ConfigTest::new(indoc! {r#"
@@ -297,105 +617,745 @@ fn non_existent_dockerfile() {
 }
 
 #[test]
-fn invalid_field_type_in_config() {
+fn mounts_are_merged_from_presets_and_extends() {
+    let tmp_dir = TempDir::new().unwrap();
+    let config_path = tmp_dir.path().join("config.toml");
+    fs::create_dir(tmp_dir.path().join("src")).unwrap();
+    fs::create_dir(tmp_dir.path().join("data")).unwrap();
+
+    fs::write(
+        &config_path,
+        indoc! {r#"
+        [preset.Cache]
+        mounts = [{ source = "cargo", target = "/root/.cargo", type = "volume" }]
+
+        [environment.Base]
+        image = "image"
+        entry_cmd = "cmd"
+        mounts = [
+            { source = "src", target = "/work" },
+            { target = "/tmp", type = "tmpfs" },
+        ]
+
+        [environment.Env]
+        extends = "Base"
+        presets = ["Cache"]
+
+        [[environment.Env.mounts]]
+        source = "./data"
+        target = "/work/"
+        readonly = true
+        "#},
+    )
+    .unwrap();
+
+    let env = ConfigTest::from_file(&config_path).get_env("Env").unwrap();
+
+    assert_eq!(
+        env.mounts
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>(),
+        vec![
+            "type=tmpfs,target=/tmp".to_string(),
+            format!(
+                "type=bind,source={},target=/work/,readonly",
+                tmp_dir.path().join("./data").display()
+            ),
+            "type=volume,source=cargo,target=/root/.cargo".to_string(),
+        ]
+    );
+    assert_eq!(env.origins["mounts"][1].line, 16);
+}
+
+#[test]
+fn mount_source_must_exist() {
     let config = ConfigTest::new(indoc! {r#"
         [environment.Env]
-        image = 5
+        image = "image"
+        entry_cmd = "cmd"
+        mounts = [{ source = "/not/a/real/path", target = "/work" }]
     "#});
 
-    let err = config.get_env("").unwrap_err();
+    let err = config.get_env("Env").unwrap_err();
 
     assert_eq!(
         err.render(),
         formatdoc!(
             r#"
-            configuration::parsing
+             configuration::environment::mount
 
-              × Malformed TOML
-               ╭─[{}:2:9]
-             1 │ [environment.Env]
-             2 │ image = 5
-               ·         ┬
-               ·         ╰── invalid type: integer `5`, expected a string
-               ╰────
-        "#,
+               × Invalid Mount
+                ╭─[{}:4:22]
+              3 │ entry_cmd = "cmd"
+              4 │ mounts = [{{ source = "/not/a/real/path", target = "/work" }}]
+                ·                      ─────────┬────────
+                ·                               ╰── Could not find the source on the host
+                ╰────
+            "#,
             config.file_path()
         )
     );
 }
 
 #[test]
-fn unknown_field_in_config() {
+fn duplicate_mount_target_from_preset() {
     let config = ConfigTest::new(indoc! {r#"
+        [preset.Preset]
+        mounts = [{ source = "cache", target = "/cache", type = "volume" }]
+
         [environment.Env]
-        unknown = "Should Fail"
+        image = "image"
+        entry_cmd = "cmd"
+        presets = ["Preset"]
+        mounts = [{ target = "/cache", type = "tmpfs" }]
     "#});
 
-    let err = config.get_env("").unwrap_err();
+    let err = config.get_env("Env").unwrap_err();
+
     assert_eq!(
         err.render(),
-        formatdoc! {
-        r#"
-        configuration::parsing
+        formatdoc!(
+            r#"
+             configuration::mount::duplication
+
+               × Duplicate Mount Target
+                ╭─[{0}:2:40]
+              1 │ [preset.Preset]
+              2 │ mounts = [{{ source = "cache", target = "/cache", type = "volume" }}]
+                ·                                        ────┬───
+                ·                                            ╰── Mounted again here
+              3 │ 
+                ╰────
+                ╭─[{0}:8:22]
+              7 │ presets = ["Preset"]
+              8 │ mounts = [{{ target = "/cache", type = "tmpfs" }}]
+                ·                      ────┬───
+                ·                          ╰── First mounted here
+                ╰────
+               help: Each mount of an environment and its presets needs a different target
+            "#,
+            config.file_path()
+        )
+    );
+}
 
-          × Malformed TOML
-           ╭─[{}:2:1]
-         1 │ [environment.Env]
-         2 │ unknown = "Should Fail"
-           · ───┬───
-           ·    ╰── Unknown field
-           ╰────
-        "#, config.file_path()
-        }
+#[test]
+fn workspace_mounts_current_dir() {
+    let config = ConfigTest::new(indoc! {r#"
+        [preset.Repo]
+        workspace_root = "git"
+
+        [environment.Dir]
+        image = "image"
+        entry_cmd = "cmd"
+        workspace = "/workspace"
+
+        [environment.Repo]
+        extends = "Dir"
+        presets = ["Repo"]
+    "#});
+    let current_dir = std::env::current_dir().unwrap();
+
+    let dir = config.get_env("Dir").unwrap();
+    let repo = config.get_env("Repo").unwrap();
+
+    assert_eq!(
+        dir.workspace,
+        Some(Workspace {
+            target: "/workspace".to_string(),
+            root: WorkspaceRoot::Cwd,
+            source: current_dir.clone(),
+            workdir: "/workspace".to_string(),
+        })
     );
+    assert_eq!(repo.workspace.unwrap().root, WorkspaceRoot::Git);
+    assert!(dir.view().unwrap().contains("workspace = \"/workspace\"\n"));
 }
 
 #[test]
-fn duplicate_field_in_config() {
+fn unknown_workspace_root() {
     let config = ConfigTest::new(indoc! {r#"
         [environment.Env]
-        image = "1"
-        image = "2"
+        image = "image"
+        entry_cmd = "cmd"
+        workspace = "/workspace"
+        workspace_root = "home"
     "#});
 
-    let err = config.get_env("").unwrap_err();
+    let err = config.get_env("Env").unwrap_err();
+
     assert_eq!(
         err.render(),
-        formatdoc! {
-        r#"
-          configuration::parsing
- 
-            × Malformed TOML
-             ╭─[{}:3:1]
-           2 │ image = "1"
-           3 │ image = "2"
-             · ┬
-             · ╰── duplicate key `image` in table `environment.Env`
-             ╰────
-        "#, config.file_path()
-        }
+        formatdoc!(
+            r#"
+             configuration::environment::validation
+
+               × Malformed Environment
+                ╭─[{}:5:18]
+              4 │ workspace = "/workspace"
+              5 │ workspace_root = "home"
+                ·                  ───┬──
+                ·                     ╰── Expected either 'cwd' or 'git'
+                ╰────
+            "#,
+            config.file_path()
+        )
     );
 }
 
 #[test]
-fn missing_field_in_config() {
+fn workspace_root_without_workspace() {
     let config = ConfigTest::new(indoc! {r#"
         [environment.Env]
-        image = "1"
+        image = "image"
+        entry_cmd = "cmd"
+        workspace_root = "git"
     "#});
 
-    let err = config.get_env("").unwrap_err();
+    let err = config.get_env("Env").unwrap_err();
+
+    assert_eq!(
+        err.render(),
+        formatdoc!(
+            r#"
+             configuration::environment::validation
+
+               × Malformed Environment
+                ╭─[{}:4:18]
+              3 │ entry_cmd = "cmd"
+              4 │ workspace_root = "git"
+                ·                  ──┬──
+                ·                    ╰── Only used along with a workspace
+                ╰────
+            "#,
+            config.file_path()
+        )
+    );
+}
+
+#[test]
+fn env_is_merged_key_wise() {
+    let var = TmpEnvVar::new("expanded");
+    let config = ConfigTest::new(&formatdoc! {r#"
+        [preset.First]
+        env = {{ A = "first", B = "first" }}
+        pass_env = ["TERM"]
+
+        [preset.Second]
+        env = {{ B = "second", C = "second" }}
+
+        [environment.Base]
+        image = "image"
+        entry_cmd = "cmd"
+        env = {{ C = "base", D = "base" }}
+
+        [environment.Env]
+        extends = "Base"
+        presets = ["First", "Second"]
+        pass_env = ["COLORTERM"]
+
+        [environment.Env.env]
+        A = "own"
+        E = "${{{}}}"
+        "#,
+        var.name()
+    });
+
+    let env = config.get_env("Env").unwrap();
+
+    assert_eq!(
+        env.env,
+        BTreeMap::from(
+            [
+                ("A", "own"),
+                ("B", "first"),
+                ("C", "second"),
+                ("D", "base"),
+                ("E", var.value()),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+        )
+    );
+    assert_eq!(env.pass_env, vec!["COLORTERM", "TERM"]);
+    let lines: Vec<usize> = env.origins["env"]
+        .iter()
+        .map(|origin| origin.line)
+        .collect();
+    assert_eq!(lines, vec![19, 2, 6, 11, 20]);
+    assert!(env.explain().unwrap().contains(&format!(
+        "env.B = \"first\"  # preset 'First' at {}:2",
+        config.file_path()
+    )));
+}
+
+#[test]
+fn env_file_must_exist() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "image"
+        entry_cmd = "cmd"
+        env_file = "/not/a/real/.env"
+    "#});
+
+    let err = config.get_env("Env").unwrap_err();
+
+    assert_eq!(
+        err.render(),
+        formatdoc!(
+            r#"
+             configuration::environment::validation
+
+               × Malformed Environment
+                ╭─[{}:4:12]
+              3 │ entry_cmd = "cmd"
+              4 │ env_file = "/not/a/real/.env"
+                ·            ─────────┬────────
+                ·                     ╰── Could not find the env file
+                ╰────
+            "#,
+            config.file_path()
+        )
+    );
+}
+
+#[test]
+fn env_file_content_is_part_of_hash() {
+    let tmp_dir = TempDir::new().unwrap();
+    let env_file = tmp_dir.path().join(".env");
+    fs::write(&env_file, "A=1\n").unwrap();
+    let config = ConfigTest::new(&formatdoc! {r#"
+        [environment.Env]
+        image = "image"
+        entry_cmd = "cmd"
+        env_file = "{}"
+    "#,
+        env_file.to_str().unwrap()
+    });
+
+    let before = config.get_env("Env").unwrap();
+    fs::write(&env_file, "A=2\n").unwrap();
+    let after = config.get_env("Env").unwrap();
+
+    assert_ne!(before.env_file_hash, after.env_file_hash);
+    assert_ne!(before.name, after.name);
+}
+
+#[test]
+fn env_keys_must_be_valid() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "image"
+        entry_cmd = "cmd"
+        env = { "" = "empty", "A=B" = "value" }
+    "#});
+
+    let err = config.get_env("Env").unwrap_err();
+
+    assert_eq!(
+        err.render(),
+        formatdoc!(
+            r#"
+             configuration::problems
+
+               × Found 2 problems in the config
+
+             Error: configuration::environment::validation
+
+               × Malformed Environment
+                ╭─[{0}:4:9]
+              3 │ entry_cmd = "cmd"
+              4 │ env = {{ "" = "empty", "A=B" = "value" }}
+                ·         ─┬
+                ·          ╰── Variable names can't be empty or contain '='
+                ╰────
+
+             Error: configuration::environment::validation
+
+               × Malformed Environment
+                ╭─[{0}:4:23]
+              3 │ entry_cmd = "cmd"
+              4 │ env = {{ "" = "empty", "A=B" = "value" }}
+                ·                       ──┬──
+                ·                         ╰── Variable names can't be empty or contain '='
+                ╰────
+            "#,
+            config.file_path()
+        )
+    );
+}
+
+#[test]
+fn unclosed_quote_in_command() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "image"
+        entry_cmd = "cmd"
+        exec_cmds = ["echo 'hello"]
+    "#});
+
+    let err = config.get_env("Env").unwrap_err();
+
+    assert_eq!(
+        err.render(),
+        formatdoc!(
+            r#"
+             configuration::environment::command
+
+               × Malformed Command
+                ╭─[{}:4:14]
+              3 │ entry_cmd = "cmd"
+              4 │ exec_cmds = ["echo 'hello"]
+                ·              ──────┬──────
+                ·                    ╰── Could not be split into words, missing closing quote
+                ╰────
+               help: Commands and options are split into words like a shell, so quotes must be closed
+            "#,
+            config.file_path()
+        )
+    );
+}
+
+#[test]
+fn check_collects_every_invalid_environment() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.MissingDockerfile]
+        dockerfile = "/tmp/file_that_is_not_real"
+        entry_cmd = "cmd"
+
+        [environment.UnclosedQuote]
+        image = "image"
+        entry_cmd = "cmd 'arg"
+
+        [environment.Valid]
+        image = "image"
+        entry_cmd = "cmd"
+    "#});
+
+    let (environments, problems) = config.check_all_envs().unwrap();
+
+    let names: Vec<_> = environments.iter().map(|env| &env.original_name).collect();
+    assert_eq!(names, vec!["Valid"]);
+
+    let error = problems.finish(()).unwrap_err();
+    let codes: Vec<_> = error
+        .related()
+        .unwrap()
+        .map(|problem| problem.code().unwrap().to_string())
+        .collect();
+    assert_eq!(
+        codes,
+        vec![
+            "configuration::environment::dockerfile",
+            "configuration::environment::command"
+        ]
+    );
+}
+
+#[test]
+fn every_problem_is_reported() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env1]
+        image = "image"
+        presets = ["Missing1"]
+
+        [environment.Env2]
+        entry_cmd = "cmd"
+        presets = ["Missing2"]
+    "#});
+
+    let err = config.get_env("Env1").unwrap_err();
+
+    assert_eq!(
+        err.render(),
+        formatdoc!(
+            r#"
+             configuration::problems
+
+               × Found 2 problems in the config
+
+             Error: configuration::preset::unknown
+
+               × Unknown Preset
+                ╭─[{0}:3:12]
+              2 │ image = "image"
+              3 │ presets = ["Missing1"]
+                ·            ─────┬────
+                ·                 ╰── Failed to find provided preset
+              4 │ 
+                ╰────
+
+             Error: configuration::preset::unknown
+
+               × Unknown Preset
+                ╭─[{0}:7:12]
+              6 │ entry_cmd = "cmd"
+              7 │ presets = ["Missing2"]
+                ·            ─────┬────
+                ·                 ╰── Failed to find provided preset
+                ╰────
+            "#,
+            config.file_path()
+        )
+    );
+}
+
+#[test]
+fn every_problem_of_an_environment_is_reported() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        dockerfile = "missing/Dockerfile"
+        build_context = "missing"
+        entry_cmd = "cmd"
+        exec_cmds = ["echo 'unclosed", "echo \"unclosed"]
+        mounts = [{ source = "/tmp", target = "relative" }, { target = "/data" }]
+        workspace = "relative"
+    "#});
+
+    let (_, problems) = config.check_all_envs().unwrap();
+
+    let error = problems.finish(()).unwrap_err();
+    let codes: Vec<_> = error
+        .related()
+        .unwrap()
+        .map(|problem| problem.code().unwrap().to_string())
+        .collect();
+    assert_eq!(
+        codes,
+        vec![
+            "configuration::environment::command",
+            "configuration::environment::command",
+            "configuration::environment::mount",
+            "configuration::environment::mount",
+            "configuration::environment::validation",
+            "configuration::environment::dockerfile",
+            "configuration::environment::dockerfile",
+        ]
+    );
+}
+
+#[test]
+fn every_preset_cycle_is_reported_once() {
+    let config = ConfigTest::new(indoc! {r#"
+        [preset.A]
+        presets = ["B"]
+
+        [preset.B]
+        presets = ["A"]
+
+        [preset.C]
+        presets = ["D"]
+
+        [preset.D]
+        presets = ["C"]
+
+        [environment.Env]
+        image = "image"
+        entry_cmd = "cmd"
+    "#});
+
+    let error = config.get_env("Env").unwrap_err();
+    let messages: Vec<_> = error
+        .related()
+        .unwrap()
+        .flat_map(|problem| problem.labels().unwrap())
+        .map(|label| label.label().unwrap().to_string())
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Presets form a cycle: A -> B -> A",
+            "Presets form a cycle: C -> D -> C"
+        ]
+    );
+}
+
+#[test]
+fn problem_repeated_by_extending_environments_is_reported_once() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Base]
+        extends = "Missing"
+
+        [environment.Env]
+        extends = "Base"
+
+        [environment.Invalid]
+        image = "image"
+    "#});
+
+    let err = config.get_env("Env").unwrap_err();
+    assert_eq!(
+        err.code().unwrap().to_string(),
+        "configuration::environment::extends"
+    );
+    assert!(err.related().is_none());
+
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.NoEntryCmd]
+        image = "image"
+
+        [environment.NoImage]
+        entry_cmd = "cmd"
+    "#});
+
+    let err = config.get_env("NoImage").unwrap_err();
+    assert_eq!(err.related().unwrap().count(), 2);
+}
+
+#[test]
+fn invalid_field_type_in_config() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = 5
+    "#});
+
+    let err = config.get_env("").unwrap_err();
+
+    assert_eq!(
+        err.render(),
+        formatdoc!(
+            r#"
+            configuration::parsing
+
+              × Malformed TOML
+               ╭─[{}:2:9]
+             1 │ [environment.Env]
+             2 │ image = 5
+               ·         ┬
+               ·         ╰── invalid type: integer `5`, expected a string
+               ╰────
+        "#,
+            config.file_path()
+        )
+    );
+}
+
+#[test]
+fn unknown_field_in_config() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        unknown = "Should Fail"
+    "#});
+
+    let err = config.get_env("").unwrap_err();
     assert_eq!(
         err.render(),
         formatdoc! {
         r#"
-          configuration::environment::validation
- 
-            × Malformed Environment
-             ╭─[{}:1:1]
-           1 │ ╭─▶ [environment.Env]
-           2 │ ├─▶ image = "1"
-             · ╰──── An environment requires a 'entry_cmd' field
+        configuration::parsing
+
+          × Malformed TOML
+           ╭─[{}:2:1]
+         1 │ [environment.Env]
+         2 │ unknown = "Should Fail"
+           · ───┬───
+           ·    ╰── Unknown field
+           ╰────
+        "#, config.file_path()
+        }
+    );
+}
+
+#[test]
+fn engine_in_config() {
+    let config = ConfigTest::new(indoc! {r#"
+        engine = "podman"
+
+        [environment.Env]
+        image = "image"
+        entry_cmd = "/bin/bash"
+    "#});
+
+    assert_eq!(config.get_engine(None).unwrap(), Engine::Podman);
+    assert_eq!(
+        config.get_engine(Some(Engine::Docker)).unwrap(),
+        Engine::Docker
+    );
+
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "image"
+        entry_cmd = "/bin/bash"
+    "#});
+
+    assert_eq!(config.get_engine(None).unwrap(), Engine::Docker);
+}
+
+#[test]
+fn unknown_engine_in_config() {
+    let config = ConfigTest::new(indoc! {r#"
+        engine = "lxc"
+
+        [environment.Env]
+        image = "image"
+        entry_cmd = "/bin/bash"
+    "#});
+
+    let err = config.get_engine(None).unwrap_err();
+    assert_eq!(
+        err.render(),
+        formatdoc! {
+        r#"
+        configuration::parsing
+
+          × Malformed TOML
+           ╭─[{}:1:10]
+         1 │ engine = "lxc"
+           ·          ──┬──
+           ·            ╰── unknown variant `lxc`, expected `docker` or `podman`
+         2 │ 
+           ╰────
+        "#, config.file_path()
+        }
+    );
+}
+
+#[test]
+fn duplicate_field_in_config() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "1"
+        image = "2"
+    "#});
+
+    let err = config.get_env("").unwrap_err();
+    assert_eq!(
+        err.render(),
+        formatdoc! {
+        r#"
+          configuration::parsing
+ 
+            × Malformed TOML
+             ╭─[{}:3:1]
+           2 │ image = "1"
+           3 │ image = "2"
+             · ┬
+             · ╰── duplicate key `image` in table `environment.Env`
+             ╰────
+        "#, config.file_path()
+        }
+    );
+}
+
+#[test]
+fn missing_field_in_config() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "1"
+    "#});
+
+    let err = config.get_env("").unwrap_err();
+    assert_eq!(
+        err.render(),
+        formatdoc! {
+        r#"
+          configuration::environment::validation
+ 
+            × Malformed Environment
+             ╭─[{}:1:1]
+           1 │ ╭─▶ [environment.Env]
+           2 │ ├─▶ image = "1"
+             · ╰──── An environment requires a 'entry_cmd' field
              ╰────
         "#, config.file_path()
         }
@@ -403,24 +1363,589 @@ fn missing_field_in_config() {
 }
 
 #[test]
-fn no_dockerfile_or_image_in_config() {
+fn no_dockerfile_or_image_in_config() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        entry_cmd = "hello"
+    "#});
+    let err = config.get_env("Env").unwrap_err().render();
+    assert_eq!(
+        err,
+        formatdoc!(
+            r#"
+             configuration::environment::validation
+
+               × Malformed Environment
+                ╭─[{}:1:1]
+              1 │ ╭─▶ [environment.Env]
+              2 │ ├─▶ entry_cmd = "hello"
+                · ╰──── An environment requires an 'image' or 'dockerfile' field
+                ╰────
+            "#,
+            config.file_path()
+        )
+    );
+}
+
+#[test]
+fn both_dockerfile_or_image() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        entry_cmd = "hello"
+        image = "world"
+        dockerfile = "!"
+    "#});
+    let err = config.get_env("Env").unwrap_err().render();
+    assert_eq!(
+        err,
+        formatdoc!(
+            r#"
+             configuration::environment::validation
+
+               × Malformed Environment
+                ╭─[{}:1:1]
+              1 │ ╭─▶ [environment.Env]
+              2 │ │   entry_cmd = "hello"
+              3 │ │   image = "world"
+              4 │ ├─▶ dockerfile = "!"
+                · ╰──── An environment can only have an 'image' or 'dockerfile' field
+                ╰────
+            "#,
+            config.file_path()
+        )
+    );
+}
+
+#[test]
+fn build_context_and_no_dockerfile() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "foo"
+        entry_cmd = "hello"
+        build_context = "world"
+    "#});
+    let err = config.get_env("Env").unwrap_err().render();
+    assert_eq!(
+        err,
+        formatdoc!(
+            r#"
+             configuration::environment::validation
+
+               × Malformed Environment
+                ╭─[{}:1:1]
+              1 │ ╭─▶ [environment.Env]
+              2 │ │   image = "foo"
+              3 │ │   entry_cmd = "hello"
+              4 │ ├─▶ build_context = "world"
+                · ╰──── 'build_context' can only be used with a 'dockerfile'
+                ╰────
+            "#,
+            config.file_path()
+        )
+    );
+}
+
+#[test]
+fn preset_not_found() {
+    let config = ConfigTest::new(indoc! {r#"
+        [preset.preset]
+        entry_options = ["a"]
+        
+        [environment.Env]
+        entry_cmd = "hello"
+        image = "world"
+        presets = ["preset", "different_preset"]
+    "#});
+    let err = config.get_env("Env").unwrap_err().render();
+    assert_eq!(
+        err,
+        formatdoc!(
+            r#"
+             configuration::preset::unknown
+ 
+               × Unknown Preset
+                ╭─[{}:7:22]
+              6 │ image = "world"
+              7 │ presets = ["preset", "different_preset"]
+                ·                      ─────────┬────────
+                ·                               ╰── Failed to find provided preset
+                ╰────
+            "#,
+            config.file_path()
+        )
+    );
+}
+
+#[test]
+fn multiple_unique_fields_from_presets() {
+    let config = ConfigTest::new(
+        r#"
+        [preset.Preset1]
+        image = "image1"
+
+        [preset.Preset2]
+        image = "image2"
+
+        [environment.Env]
+        image = "image"
+        entry_cmd = "init"
+        presets = ["Preset1", "Preset2"]
+    "#,
+    );
+
+    let err = config.get_env("Env").unwrap_err().render();
+    assert_eq!(
+        err,
+        formatdoc!(
+            r#"
+             configuration::preset::duplication
+ 
+               × Duplicate Fields From Presets
+                 ╭─[{}:3:9]
+               2 │         [preset.Preset1]
+               3 │         image = "image1"
+                 ·         ────────┬───────
+                 ·                 ╰── instance 2
+               4 │ 
+               5 │         [preset.Preset2]
+               6 │         image = "image2"
+                 ·         ────────┬───────
+                 ·                 ╰── instance 3
+               7 │ 
+               8 │         [environment.Env]
+               9 │         image = "image"
+                 ·         ───────┬───────
+                 ·                ╰── instance 1
+              10 │         entry_cmd = "init"
+              11 │         presets = ["Preset1", "Preset2"]
+                 ·                   ───────────┬──────────
+                 ·                              ╰── Preset(s) causing duplicate 'image' field
+              12 │     
+                 ╰────
+            "#,
+            config.file_path()
+        )
+    );
+}
+
+#[test]
+fn later_config_files_override_earlier_ones() {
+    let tmp_dir = TempDir::new().unwrap();
+    let global_dir = tmp_dir.path().join("global");
+    let project_dir = tmp_dir.path().join("project");
+    fs::create_dir_all(&global_dir).unwrap();
+    fs::create_dir_all(&project_dir).unwrap();
+
+    let global_path = global_dir.join("config.toml");
+    fs::write(global_dir.join("Dockerfile"), "").unwrap();
+    fs::write(
+        &global_path,
+        indoc! {r#"
+        [preset.Build]
+        dockerfile = "Dockerfile"
+
+        [environment.Shared]
+        image = "global"
+        entry_cmd = "init"
+
+        [environment.Global]
+        image = "global"
+        entry_cmd = "init"
+    "#},
+    )
+    .unwrap();
+
+    let project_path = project_dir.join(".berth.toml");
+    fs::write(
+        &project_path,
+        indoc! {r#"
+        [environment.Shared]
+        image = "project"
+        entry_cmd = "init"
+
+        [environment.Project]
+        entry_cmd = "init"
+        presets = ["Build"]
+    "#},
+    )
+    .unwrap();
+
+    let config = ConfigTest::from_files(&[&global_path, &project_path]);
+
+    let shared = config.get_env("Shared").unwrap();
+    assert_eq!(shared.image, "project");
+    assert_eq!(shared.config_path, project_path);
+
+    let global = config.get_env("Global").unwrap();
+    assert_eq!(global.config_path, global_path);
+
+    // Paths in a preset are relative to the file defining the preset
+    let project = config.get_env("Project").unwrap();
+    assert_eq!(project.dockerfile.unwrap(), global_dir.join("Dockerfile"));
+
+    let names: Vec<String> = config
+        .get_all_envs()
+        .unwrap()
+        .into_iter()
+        .map(|env| env.original_name)
+        .collect();
+    assert_eq!(names, vec!["Global", "Project", "Shared"]);
+
+    tmp_dir.close().unwrap();
+}
+
+#[test]
+fn unique_fields_from_presets_in_other_files() {
+    let tmp_dir = TempDir::new().unwrap();
+
+    let global_path = tmp_dir.path().join("config.toml");
+    fs::write(
+        &global_path,
+        indoc! {r#"
+        [preset.Preset]
+        image = "image1"
+    "#},
+    )
+    .unwrap();
+
+    let project_path = tmp_dir.path().join(".berth.toml");
+    fs::write(
+        &project_path,
+        indoc! {r#"
+        [environment.Env]
+        image = "image"
+        entry_cmd = "init"
+        presets = ["Preset"]
+    "#},
+    )
+    .unwrap();
+
+    let config = ConfigTest::from_files(&[&global_path, &project_path]);
+
+    let err = config.get_env("Env").unwrap_err().render();
+    assert_eq!(
+        err,
+        formatdoc!(
+            r#"
+            configuration::preset::duplication
+
+              × Duplicate Fields From Presets
+               ╭─[{}:2:1]
+             1 │ [environment.Env]
+             2 │ image = "image"
+               · ───────┬───────
+               ·        ╰── instance 1
+             3 │ entry_cmd = "init"
+             4 │ presets = ["Preset"]
+               ·           ─────┬────
+               ·                ╰── Preset(s) causing duplicate 'image' field
+               ╰────
+
+            Error: configuration::preset::duplication
+
+              × Duplicate Fields From Presets
+               ╭─[{}:2:1]
+             1 │ [preset.Preset]
+             2 │ image = "image1"
+               · ────────┬───────
+               ·         ╰── instance 2
+               ╰────
+            "#,
+            project_path.display(),
+            global_path.display()
+        )
+    );
+
+    tmp_dir.close().unwrap();
+}
+
+#[test]
+fn included_files() {
+    let tmp_dir = TempDir::new().unwrap();
+    let presets_dir = tmp_dir.path().join("presets");
+    fs::create_dir_all(&presets_dir).unwrap();
+
+    fs::write(
+        presets_dir.join("a.toml"),
+        indoc! {r#"
+        [preset.A]
+        exec_cmds = ["echo a"]
+    "#},
+    )
+    .unwrap();
+    fs::write(
+        presets_dir.join("b.toml"),
+        indoc! {r#"
+        [preset.B]
+        exec_cmds = ["echo b"]
+
+        [environment.Included]
+        image = "included"
+        entry_cmd = "init"
+    "#},
+    )
+    .unwrap();
+    fs::write(presets_dir.join("ignored.txt"), "").unwrap();
+
+    let team_path = tmp_dir.path().join("team.toml");
+    fs::write(
+        &team_path,
+        indoc! {r#"
+        [environment.Env]
+        image = "team"
+        entry_cmd = "init"
+    "#},
+    )
+    .unwrap();
+
+    let config_path = tmp_dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        indoc! {r#"
+        include = ["team.toml", "presets/*.toml"]
+
+        [environment.Env]
+        image = "image"
+        entry_cmd = "init"
+        presets = ["A", "B"]
+    "#},
+    )
+    .unwrap();
+
+    let config = ConfigTest::from_file(&config_path);
+
+    // The including file takes priority over the files it includes
+    let env = config.get_env("Env").unwrap();
+    assert_eq!(env.image, "image");
+    assert_eq!(env.exec_cmds, vec!["echo a", "echo b"]);
+
+    let included = config.get_env("Included").unwrap();
+    assert_eq!(
+        included.config_path,
+        presets_dir.join("b.toml").canonicalize().unwrap()
+    );
+
+    tmp_dir.close().unwrap();
+}
+
+#[test]
+fn build_context_from_included_preset() {
+    let tmp_dir = TempDir::new().unwrap();
+    let presets_dir = tmp_dir.path().join("presets");
+    fs::create_dir_all(presets_dir.join("context")).unwrap();
+    fs::write(presets_dir.join("Dockerfile"), "FROM alpine").unwrap();
+    fs::write(
+        presets_dir.join("build.toml"),
+        indoc! {r#"
+        [preset.Build]
+        dockerfile = "Dockerfile"
+        build_context = "context"
+    "#},
+    )
+    .unwrap();
+
+    let config_path = tmp_dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        indoc! {r#"
+        include = ["presets/build.toml"]
+
+        [environment.Env]
+        entry_cmd = "init"
+        presets = ["Build"]
+    "#},
+    )
+    .unwrap();
+
+    let env = ConfigTest::from_file(&config_path).get_env("Env").unwrap();
+    let presets_dir = presets_dir.canonicalize().unwrap();
+    assert_eq!(env.dockerfile, Some(presets_dir.join("Dockerfile")));
+    assert_eq!(env.build_context, Some(presets_dir.join("context")));
+
+    tmp_dir.close().unwrap();
+}
+
+#[test]
+fn missing_build_context_from_preset() {
+    let dockerfile = NamedTempFile::new().unwrap();
+    let config = ConfigTest::new(&formatdoc! {r#"
+        [preset.Build]
+        build_context = "missing"
+
+        [environment.Env]
+        dockerfile = "{}"
+        entry_cmd = "init"
+        presets = ["Build"]
+    "#,
+        dockerfile.path().display()
+    });
+
+    let err = config.get_env("Env").unwrap_err();
+
+    assert_eq!(
+        err.render(),
+        formatdoc!(
+            r#"
+             configuration::environment::dockerfile
+
+               × Nonexistent Dockerfile
+                ╭─[{}:2:17]
+              1 │ [preset.Build]
+              2 │ build_context = "missing"
+                ·                 ────┬────
+                ·                     ╰── Could not find build context / Build context must be a directory
+              3 │ 
+                ╰────
+            "#,
+            config.file_path()
+        )
+    );
+}
+
+#[test]
+fn error_in_included_file() {
+    let tmp_dir = TempDir::new().unwrap();
+    let included_path = tmp_dir.path().join("included.toml");
+    fs::write(
+        &included_path,
+        indoc! {r#"
+        [preset.Preset]
+        not_a_field = "value"
+    "#},
+    )
+    .unwrap();
+
+    let config_path = tmp_dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        indoc! {r#"
+        include = ["included.toml"]
+
+        [environment.Env]
+        image = "image"
+        entry_cmd = "init"
+    "#},
+    )
+    .unwrap();
+
+    let err = ConfigTest::from_file(&config_path)
+        .get_env("Env")
+        .unwrap_err()
+        .render();
+    assert_eq!(
+        err,
+        formatdoc!(
+            r#"
+            configuration::parsing
+
+              × Malformed TOML
+               ╭─[{}:2:1]
+             1 │ [preset.Preset]
+             2 │ not_a_field = "value"
+               · ─────┬─────
+               ·      ╰── Unknown field
+               ╰────
+            "#,
+            included_path.canonicalize().unwrap().display()
+        )
+    );
+
+    tmp_dir.close().unwrap();
+}
+
+#[test]
+fn nonexistent_included_file() {
+    let config = ConfigTest::new(indoc! {r#"
+        include = ["/tmp/file_that_is_not_real.toml"]
+
+        [environment.Env]
+        image = "image"
+        entry_cmd = "init"
+    "#});
+
+    let err = config.get_env("Env").unwrap_err().render();
+    assert_eq!(
+        err,
+        formatdoc!(
+            r#"
+            configuration::include::unknown
+
+              × Unknown Include
+               ╭─[{}:1:12]
+             1 │ include = ["/tmp/file_that_is_not_real.toml"]
+               ·            ────────────────┬────────────────
+               ·                            ╰── Failed to find included file at "/tmp/file_that_is_not_real.toml"
+             2 │ 
+               ╰────
+              help: Included paths are relative to the file including them
+            "#,
+            config.file_path()
+        )
+    );
+}
+
+#[test]
+fn circular_include() {
+    let tmp_dir = TempDir::new().unwrap();
+    let config_path = tmp_dir.path().join("config.toml");
+    let other_path = tmp_dir.path().join("other.toml");
+    fs::write(&config_path, "include = [\"other.toml\"]\n").unwrap();
+    fs::write(&other_path, "include = [\"config.toml\"]\n").unwrap();
+
+    let err = ConfigTest::from_file(&config_path)
+        .get_env("Env")
+        .unwrap_err()
+        .render();
+
+    let config_path = config_path.canonicalize().unwrap();
+    let other_path = other_path.canonicalize().unwrap();
+    assert_eq!(
+        err,
+        formatdoc!(
+            r#"
+            configuration::include::cycle
+
+              × Circular Include
+               ╭─[{other}:1:12]
+             1 │ include = ["config.toml"]
+               ·            ──────┬──────
+               ·                  ╰── Including this file creates a cycle: {config} -> {other} -> {config}
+               ╰────
+            "#,
+            config = config_path.display(),
+            other = other_path.display(),
+        )
+    );
+
+    tmp_dir.close().unwrap();
+}
+
+#[test]
+fn nested_preset_not_found() {
     let config = ConfigTest::new(indoc! {r#"
+        [preset.Preset]
+        presets = ["Missing"]
+
         [environment.Env]
         entry_cmd = "hello"
+        image = "world"
+        presets = ["Preset"]
     "#});
     let err = config.get_env("Env").unwrap_err().render();
     assert_eq!(
         err,
         formatdoc!(
             r#"
-             configuration::environment::validation
-
-               × Malformed Environment
-                ╭─[{}:1:1]
-              1 │ ╭─▶ [environment.Env]
-              2 │ ├─▶ entry_cmd = "hello"
-                · ╰──── An environment requires an 'image' or 'dockerfile' field
-                ╰────
+            configuration::preset::unknown
+
+              × Unknown Preset
+               ╭─[{}:2:12]
+             1 │ [preset.Preset]
+             2 │ presets = ["Missing"]
+               ·            ────┬────
+               ·                ╰── Failed to find provided preset
+             3 │ 
+               ╰────
             "#,
             config.file_path()
         )
@@ -428,57 +1953,246 @@ fn no_dockerfile_or_image_in_config() {
 }
 
 #[test]
-fn both_dockerfile_or_image() {
+fn circular_presets() {
     let config = ConfigTest::new(indoc! {r#"
+        [preset.A]
+        presets = ["B"]
+
+        [preset.B]
+        presets = ["C"]
+
+        [preset.C]
+        presets = ["A"]
+
         [environment.Env]
         entry_cmd = "hello"
         image = "world"
-        dockerfile = "!"
     "#});
     let err = config.get_env("Env").unwrap_err().render();
     assert_eq!(
         err,
         formatdoc!(
             r#"
-             configuration::environment::validation
+            configuration::preset::cycle
+
+              × Circular Preset
+               ╭─[{}:8:12]
+             7 │ [preset.C]
+             8 │ presets = ["A"]
+               ·            ─┬─
+               ·             ╰── Presets form a cycle: A -> B -> C -> A
+             9 │ 
+               ╰────
+            "#,
+            config.file_path()
+        )
+    );
+}
 
-               × Malformed Environment
-                ╭─[{}:1:1]
-              1 │ ╭─▶ [environment.Env]
-              2 │ │   entry_cmd = "hello"
-              3 │ │   image = "world"
-              4 │ ├─▶ dockerfile = "!"
-                · ╰──── An environment can only have an 'image' or 'dockerfile' field
+#[test]
+fn unique_fields_from_nested_presets() {
+    let config = ConfigTest::new(indoc! {r#"
+        [preset.Base]
+        image = "image1"
+
+        [preset.Dev]
+        presets = ["Base"]
+
+        [environment.Env]
+        image = "image"
+        entry_cmd = "init"
+        presets = ["Dev"]
+    "#});
+
+    let err = config.get_env("Env").unwrap_err().render();
+    assert_eq!(
+        err,
+        formatdoc!(
+            r#"
+            configuration::preset::duplication
+
+              × Duplicate Fields From Presets
+               ╭─[{path}:2:1]
+             1 │ [preset.Base]
+             2 │ image = "image1"
+               · ────────┬───────
+               ·         ╰── instance 2
+             3 │ 
+               ╰────
+                ╭─[{path}:8:1]
+              7 │ [environment.Env]
+              8 │ image = "image"
+                · ───────┬───────
+                ·        ╰── instance 1
+              9 │ entry_cmd = "init"
+             10 │ presets = ["Dev"]
+                ·           ───┬───
+                ·              ╰── Preset(s) causing duplicate 'image' field
                 ╰────
             "#,
-            config.file_path()
+            path = config.file_path()
         )
     );
 }
 
 #[test]
-fn build_context_and_no_dockerfile() {
+fn preset_listed_by_several_presets_is_not_a_duplicate() {
     let config = ConfigTest::new(indoc! {r#"
+        [preset.Base]
+        image = "image"
+
+        [preset.Dev]
+        presets = ["Base"]
+
+        [preset.Test]
+        presets = ["Base"]
+
         [environment.Env]
-        image = "foo"
-        entry_cmd = "hello"
-        build_context = "world"
+        entry_cmd = "init"
+        presets = ["Dev", "Test"]
     "#});
+
+    let env = config.get_env("Env").unwrap();
+    assert_eq!(env.image, "image");
+}
+
+#[test]
+fn extends_environment() {
+    let config = ConfigTest::new(indoc! {r#"
+        [preset.Nightly]
+        exec_cmds = ["rustup default nightly"]
+
+        [environment.rust]
+        image = "rust:latest"
+        entry_cmd = "/bin/bash"
+        entry_options = ["-it"]
+        exec_cmds = ["cargo install ripgrep"]
+        create_options = ["--privileged"]
+
+        [environment.rust-nightly]
+        extends = "rust"
+        entry_cmd = "/bin/zsh"
+        create_options = { replace = ["-v /tmp:/tmp"] }
+        presets = ["Nightly"]
+
+        [environment.rust-nightly-slim]
+        extends = "rust-nightly"
+        image = "rust:slim"
+    "#});
+
+    let nightly = config.get_env("rust-nightly").unwrap();
+    assert_eq!(nightly.image, "rust:latest");
+    assert_eq!(nightly.entry_cmd, "/bin/zsh");
+    assert_eq!(nightly.entry_options, vec!["-it"]);
+    assert_eq!(
+        nightly.exec_cmds,
+        vec!["cargo install ripgrep", "rustup default nightly"]
+    );
+    assert_eq!(nightly.create_options, vec!["-v /tmp:/tmp"]);
+
+    let slim = config.get_env("rust-nightly-slim").unwrap();
+    assert_eq!(slim.image, "rust:slim");
+    assert_eq!(slim.entry_cmd, "/bin/zsh");
+    assert_eq!(slim.exec_cmds, nightly.exec_cmds);
+    assert_eq!(slim.create_options, nightly.create_options);
+
+    // The base environment is unchanged
+    let rust = config.get_env("rust").unwrap();
+    assert_eq!(rust.entry_cmd, "/bin/bash");
+    assert_eq!(rust.create_options, vec!["--privileged"]);
+}
+
+#[test]
+fn extended_image_overrides_dockerfile() {
+    let tmp_dir = TempDir::new().unwrap();
+    fs::write(tmp_dir.path().join("Dockerfile"), "").unwrap();
+
+    let config_path = tmp_dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        indoc! {r#"
+        [environment.Base]
+        dockerfile = "Dockerfile"
+        entry_cmd = "init"
+
+        [environment.FromImage]
+        extends = "Base"
+        image = "image"
+
+        [environment.FromDockerfile]
+        extends = "Base"
+    "#},
+    )
+    .unwrap();
+
+    let config = ConfigTest::from_file(&config_path);
+
+    let from_image = config.get_env("FromImage").unwrap();
+    assert_eq!(from_image.image, "image");
+    assert_eq!(from_image.dockerfile, None);
+
+    let from_dockerfile = config.get_env("FromDockerfile").unwrap();
+    assert_eq!(
+        from_dockerfile.dockerfile.unwrap(),
+        tmp_dir.path().join("Dockerfile")
+    );
+
+    tmp_dir.close().unwrap();
+}
+
+#[test]
+fn image_overrides_extended_dockerfile_and_build_context() {
+    let tmp_dir = TempDir::new().unwrap();
+    let config_path = tmp_dir.path().join("config.toml");
+    fs::create_dir(tmp_dir.path().join("ctx")).unwrap();
+    fs::write(tmp_dir.path().join("Dockerfile"), "FROM alpine").unwrap();
+
+    fs::write(
+        &config_path,
+        indoc! {r#"
+        [environment.Base]
+        dockerfile = "Dockerfile"
+        build_context = "ctx"
+        entry_cmd = "cmd"
+
+        [environment.Env]
+        extends = "Base"
+        image = "image"
+        "#},
+    )
+    .unwrap();
+
+    let env = ConfigTest::from_file(&config_path).get_env("Env").unwrap();
+
+    assert_eq!(env.image, "image");
+    assert_eq!(env.dockerfile, None);
+    assert_eq!(env.build_context, None);
+}
+
+#[test]
+fn extends_unknown_environment() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        extends = "Missing"
+        image = "image"
+        entry_cmd = "init"
+    "#});
+
     let err = config.get_env("Env").unwrap_err().render();
     assert_eq!(
         err,
         formatdoc!(
             r#"
-             configuration::environment::validation
+            configuration::environment::extends
 
-               × Malformed Environment
-                ╭─[{}:1:1]
-              1 │ ╭─▶ [environment.Env]
-              2 │ │   image = "foo"
-              3 │ │   entry_cmd = "hello"
-              4 │ ├─▶ build_context = "world"
-                · ╰──── 'build_context' can only be used with a 'dockerfile'
-                ╰────
+              × Unknown Environment
+               ╭─[{}:2:11]
+             1 │ [environment.Env]
+             2 │ extends = "Missing"
+               ·           ────┬────
+               ·               ╰── Failed to find environment to extend
+             3 │ image = "image"
+               ╰────
             "#,
             config.file_path()
         )
@@ -486,30 +2200,61 @@ fn build_context_and_no_dockerfile() {
 }
 
 #[test]
-fn preset_not_found() {
+fn circular_extends() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.A]
+        extends = "B"
+
+        [environment.B]
+        extends = "A"
+        image = "image"
+        entry_cmd = "init"
+    "#});
+
+    let err = config.get_env("A").unwrap_err().render();
+    assert_eq!(
+        err,
+        formatdoc!(
+            r#"
+            configuration::environment::cycle
+
+              × Circular Extends
+               ╭─[{}:5:11]
+             4 │ [environment.B]
+             5 │ extends = "A"
+               ·           ─┬─
+               ·            ╰── Environments form a cycle: A -> B -> A
+             6 │ image = "image"
+               ╰────
+            "#,
+            config.file_path()
+        )
+    );
+}
+
+#[test]
+fn unknown_list_merge_strategy() {
     let config = ConfigTest::new(indoc! {r#"
-        [preset.preset]
-        entry_options = ["a"]
-        
         [environment.Env]
-        entry_cmd = "hello"
-        image = "world"
-        presets = ["preset", "different_preset"]
+        image = "image"
+        entry_cmd = "init"
+        exec_cmds = { overwrite = ["echo"] }
     "#});
+
     let err = config.get_env("Env").unwrap_err().render();
     assert_eq!(
         err,
         formatdoc!(
             r#"
-             configuration::preset::unknown
- 
-               × Unknown Preset
-                ╭─[{}:7:22]
-              6 │ image = "world"
-              7 │ presets = ["preset", "different_preset"]
-                ·                      ─────────┬────────
-                ·                               ╰── Failed to find provided preset
-                ╰────
+            configuration::parsing
+
+              × Malformed TOML
+               ╭─[{}:4:13]
+             3 │ entry_cmd = "init"
+             4 │ exec_cmds = {{ overwrite = ["echo"] }}
+               ·             ────────────┬───────────
+               ·                         ╰── Unknown merge strategy 'overwrite', expected one of 'append', 'prepend', 'replace' or 'remove'
+               ╰────
             "#,
             config.file_path()
         )
@@ -517,53 +2262,171 @@ fn preset_not_found() {
 }
 
 #[test]
-fn multiple_unique_fields_from_presets() {
-    let config = ConfigTest::new(
-        r#"
-        [preset.Preset1]
-        image = "image1"
+fn list_merge_strategies() {
+    let config = ConfigTest::new(indoc! {r#"
+        [preset.Privileged]
+        create_options = ["--privileged", "--init"]
 
-        [preset.Preset2]
-        image = "image2"
+        [preset.Tools]
+        exec_cmds = ["apk add helix", "apk add git"]
+        entry_options = ["-it"]
+        cp_cmds = ["init.sh CONTAINER:/init.sh"]
+
+        [preset.Unprivileged]
+        presets = ["Privileged"]
+        create_options = { remove = ["--privileged"] }
 
         [environment.Env]
         image = "image"
         entry_cmd = "init"
-        presets = ["Preset1", "Preset2"]
-    "#,
+        presets = ["Tools", "Unprivileged"]
+        exec_cmds = { append = ["echo done"] }
+        entry_options = { prepend = ["-e TERM"] }
+        cp_cmds = { replace = ["other.sh CONTAINER:/init.sh"] }
+        exec_options = ["-u root"]
+
+        [environment.Variant]
+        extends = "Env"
+        exec_cmds = { remove = ["apk add git"] }
+        create_options = { prepend = ["--rm"] }
+    "#});
+
+    let env = config.get_env("Env").unwrap();
+    assert_eq!(
+        env.exec_cmds,
+        vec!["apk add helix", "apk add git", "echo done"]
     );
+    assert_eq!(env.entry_options, vec!["-e TERM", "-it"]);
+    assert_eq!(env.cp_cmds, vec!["other.sh CONTAINER:/init.sh"]);
+    assert_eq!(env.exec_options, vec!["-u root"]);
+    assert_eq!(env.create_options, vec!["--init"]);
+
+    let variant = config.get_env("Variant").unwrap();
+    assert_eq!(variant.exec_cmds, vec!["apk add helix", "echo done"]);
+    assert_eq!(variant.create_options, vec!["--rm", "--init"]);
+}
+
+#[test]
+fn hooks_are_merged_from_presets() {
+    let env = ConfigTest::new(indoc! {r#"
+        [preset.Preset]
+        on_create = ["preset create"]
+        on_enter = ["preset enter"]
+
+        [environment.Env]
+        image = "image"
+        entry_cmd = "cmd"
+        presets = ["Preset"]
+        on_create = ["env create"]
+        on_start = ["env start"]
+        on_enter = { replace = ["env enter"] }
+        on_stop = ["env stop"]
+    "#})
+    .get_env("Env")
+    .unwrap();
+
+    assert_eq!(env.on_create, vec!["env create", "preset create"]);
+    assert_eq!(env.on_start, vec!["env start"]);
+    assert_eq!(env.on_enter, vec!["env enter"]);
+    assert_eq!(env.on_stop, vec!["env stop"]);
+}
+
+#[test]
+fn plain_list_order_with_presets_and_extends() {
+    let config = ConfigTest::new(indoc! {r#"
+        [preset.Preset]
+        exec_cmds = ["preset"]
+
+        [environment.Base]
+        image = "image"
+        entry_cmd = "init"
+        exec_cmds = ["base"]
+
+        [environment.Env]
+        extends = "Base"
+        presets = ["Preset"]
+        exec_cmds = ["env"]
+    "#});
+
+    let env = config.get_env("Env").unwrap();
+    assert_eq!(env.exec_cmds, vec!["base", "env", "preset"]);
+}
+
+#[test]
+fn removed_value_not_inherited() {
+    let config = ConfigTest::new(indoc! {r#"
+        [preset.Preset]
+        create_options = ["--privileged"]
+
+        [environment.Env]
+        image = "image"
+        entry_cmd = "init"
+        presets = ["Preset"]
+        create_options = { remove = ["--privileged", "--init"] }
+    "#});
 
     let err = config.get_env("Env").unwrap_err().render();
     assert_eq!(
         err,
         formatdoc!(
             r#"
-             configuration::preset::duplication
- 
-               × Duplicate Fields From Presets
-                 ╭─[{}:3:9]
-               2 │         [preset.Preset1]
-               3 │         image = "image1"
-                 ·         ────────┬───────
-                 ·                 ╰── instance 2
-               4 │ 
-               5 │         [preset.Preset2]
-               6 │         image = "image2"
-                 ·         ────────┬───────
-                 ·                 ╰── instance 3
-               7 │ 
-               8 │         [environment.Env]
-               9 │         image = "image"
-                 ·         ───────┬───────
-                 ·                ╰── instance 1
-              10 │         entry_cmd = "init"
-              11 │         presets = ["Preset1", "Preset2"]
-                 ·                   ───────────┬──────────
-                 ·                              ╰── Preset(s) causing duplicate 'image' field
-              12 │     
-                 ╰────
+            configuration::list::remove
+
+              × Removed Values Not Inherited
+               ╭─[{}:7:11]
+             6 │ entry_cmd = "init"
+             7 │ presets = ["Preset"]
+               ·           ─────┬────
+               ·                ╰── Inherited from these presets
+             8 │ create_options = {{ remove = ["--privileged", "--init"] }}
+               ·                                              ────┬───
+               ·                                                  ╰── Not in the inherited 'create_options'
+               ╰────
+              help: 'remove' can only remove values inherited from presets or an extended environment
             "#,
             config.file_path()
         )
     );
 }
+
+#[test]
+fn explain_shows_origin_of_values() {
+    let config = ConfigTest::new(indoc! {r#"
+        [preset.Base]
+        image = "alpine:edge"
+
+        [preset.Tools]
+        exec_cmds = ["apk add helix", "apk add git"]
+
+        [environment.Root]
+        presets = ["Base"]
+        entry_cmd = "/bin/ash"
+        create_options = ["--privileged"]
+
+        [environment.Env]
+        extends = "Root"
+        presets = ["Tools"]
+        exec_cmds = { remove = ["apk add git"] }
+        create_options = { append = ["--init"] }
+    "#});
+
+    let explain = config.get_env("Env").unwrap().explain().unwrap();
+    assert_eq!(
+        explain,
+        formatdoc!(
+            r#"
+            [environment.Env]
+            image = "alpine:edge"  # preset 'Base' at {path}:2
+            entry_cmd = "/bin/ash"  # environment 'Root' at {path}:9
+            exec_cmds = [
+                "apk add helix",  # preset 'Tools' at {path}:5
+            ]
+            create_options = [
+                "--privileged",  # environment 'Root' at {path}:10
+                "--init",  # environment 'Env' at {path}:16
+            ]
+            "#,
+            path = config.file_path()
+        )
+    );
+}
@@ -6,9 +6,10 @@ use std::{
     collections::HashMap,
     fs::{create_dir, File},
     io::Write,
+    path::Path,
 };
 use tempfile::{NamedTempFile, TempDir};
-use test_utils::{TestHarness, TestOutput, APK_ADD_ARGS, DEFAULT_TIMEOUT};
+use test_utils::{ConfigTest, TestHarness, TestOutput, APK_ADD_ARGS, DEFAULT_TIMEOUT};
 
 pub mod test_utils;
 
@@ -91,6 +92,58 @@ fn copy_cmds() -> Result<()> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn copy_from_container() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+    let copied_path = dir.path().join("release");
+
+    TestOutput::new()
+        .config(&formatdoc!(
+            r#"
+            image = "alpine:edge"
+            entry_cmd = "/bin/ash"
+            cp_cmds = ["CONTAINER:/etc/alpine-release {}"]
+            "#,
+            copied_path.to_str().unwrap()
+        ))?
+        .args(vec!["--config-path", "[config_path]", "build", "[name]"])?
+        .stderr("Using config file at \"[config_path]\"\n")?
+        .code(0)?
+        .run()?;
+
+    assert!(copied_path.is_file());
+    dir.close()?;
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn unsupported_create_option() -> Result<()> {
+    TestOutput::new()
+        .config(indoc!(
+            r#"
+            image = "alpine:edge"
+            entry_cmd = "/bin/ash"
+            create_options = ["--name other"]
+            "#
+        ))?
+        .args(vec!["--config-path", "[config_path]", "build", "[name]"])?
+        .stderr(indoc!(
+            r#"
+            Using config file at "[config_path]"
+            Error: cli::options::invalid
+
+              × Invalid option '--name': unsupported option
+              help: berth translates options for the Docker API itself, see the README for
+                    the supported options
+
+            "#
+        ))?
+        .code(1)?
+        .run()
+}
+
 #[test]
 #[serial]
 fn relative_to_config_file() -> Result<()> {
@@ -114,7 +167,7 @@ fn relative_to_config_file() -> Result<()> {
             copy_file_path.file_name().unwrap().to_str().unwrap(),
             copy_file_path.to_str().unwrap()
         ),
-        &config_file_path,
+        config_file_path,
     )?;
 
     harness
@@ -171,7 +224,7 @@ async fn build() -> Result<()> {
             "#,
             APK_ADD_ARGS
         ))?
-        .args(vec!["--config-path", "[config_path]", "--build", "[name]"])?
+        .args(vec!["--config-path", "[config_path]", "build", "[name]"])?
         .stderr("Using config file at \"[config_path]\"\n")?
         .code(0)?;
 
@@ -388,6 +441,50 @@ fn dockerfile_provided_build_context() -> Result<()> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn dockerignore_excludes_files() -> Result<()> {
+    let dir = TempDir::new().unwrap();
+
+    let dockerfile = File::create(dir.path().join("dockerfile")).unwrap();
+    File::create(dir.path().join("test_file")).unwrap();
+    File::create(dir.path().join("ignored_file")).unwrap();
+    let content = indoc! {
+    r#"
+    FROM alpine:edge
+    COPY . /context
+    "#};
+    write!(&dockerfile, "{}", content).unwrap();
+
+    let dockerignore = File::create(dir.path().join(".dockerignore")).unwrap();
+    write!(&dockerignore, "ignored_*").unwrap();
+
+    TestHarness::new()
+        .config(&formatdoc!(
+            r#"
+            dockerfile = "{}"
+            build_context = "{}"
+            entry_cmd = "/bin/ash"
+            create_options = ["-it"]
+            entry_options = ["-it"]
+            "#,
+            dir.path().join("dockerfile").to_str().unwrap(),
+            dir.path().to_str().unwrap(),
+        ))?
+        .args(vec!["--config-path", "[config_path]", "[name]"])?
+        .run(DEFAULT_TIMEOUT)?
+        .send_line("test -e /context/test_file && echo found-$((1+1))")?
+        .expect_string("found-2")?
+        .send_line("test -e /context/ignored_file || echo ignored-$((1+1))")?
+        .expect_string("ignored-2")?
+        .send_line("exit")?
+        .expect_terminate()?
+        .success()?;
+
+    dir.close()?;
+    Ok(())
+}
+
 #[test]
 fn badly_formed_dockerfile() -> Result<()> {
     let dockerfile = NamedTempFile::new().unwrap();
@@ -409,9 +506,137 @@ fn badly_formed_dockerfile() -> Result<()> {
             dockerfile.path().to_str().unwrap(),
         ))?
         .args(vec!["--config-path", "[config_path]", "[name]"])?
-        .stderr("Error: cli::container::command::exitcode")?
+        .stderr("Error: cli::image::building")?
         .code(1)?;
 
     dockerfile.close().unwrap();
     Ok(())
 }
+
+#[test]
+#[serial]
+fn list_shows_container_status() -> Result<()> {
+    let mut test = TestOutput::new().config(indoc!(
+        r#"
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        "#
+    ))?;
+    let name = test.name().to_string();
+    let header = format!("{:<width$}", "NAME", width = name.len());
+
+    test = test
+        .args(vec!["--config-path", "[config_path]", "list"])?
+        .stdout(formatdoc!(
+            r#"
+            {header}   IMAGE         STATUS   CREATED
+            [name]   alpine:edge   absent   -
+            "#
+        ))?
+        .stderr("Using config file at \"[config_path]\"\n")?
+        .code(0)?;
+    test.run()?;
+
+    TestOutput::new()
+        .args(vec!["--config-path", test.config_path(), "build", &name])?
+        .stderr(format!("Using config file at \"{}\"\n", test.config_path()))?
+        .code(0)?
+        .run()?;
+
+    test.stdout(formatdoc!(
+        r#"
+        {header}   IMAGE         STATUS    CREATED
+        [name]   alpine:edge   stopped   less than a minute ago
+        "#
+    ))?
+    .run()
+}
+
+#[tokio::test]
+#[serial]
+async fn containers_are_labelled() -> Result<()> {
+    let mut test = TestOutput::new()
+        .config(indoc!(
+            r#"
+            image = "alpine:edge"
+            entry_cmd = "/bin/ash"
+            "#
+        ))?
+        .args(vec!["--config-path", "[config_path]", "build", "[name]"])?
+        .stderr("Using config file at \"[config_path]\"\n")?
+        .code(0)?;
+    test.run()?;
+
+    let docker = Docker::connect_with_local_defaults().unwrap();
+    let environment_label = format!("berth.environment={}", test.name());
+    let mut filters = HashMap::new();
+    filters.insert("label", vec![environment_label.as_str()]);
+    let options = Some(ListContainersOptions {
+        all: true,
+        filters,
+        ..Default::default()
+    });
+    let containers = docker.list_containers(options).await.unwrap();
+    assert_eq!(containers.len(), 1);
+
+    let labels = containers[0].labels.clone().unwrap();
+    let config_path = std::fs::canonicalize(test.config_path()).unwrap();
+    assert_eq!(
+        labels["berth.config_path"],
+        config_path.display().to_string()
+    );
+    assert_eq!(labels["berth.version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(
+        containers[0].names.clone().unwrap(),
+        vec![format!("/berth-{}-{}", test.name(), labels["berth.hash"])]
+    );
+    Ok(())
+}
+
+#[test]
+#[serial]
+fn diff_shows_changed_fields() -> Result<()> {
+    let mut test = TestOutput::new()
+        .config(indoc!(
+            r#"
+            image = "alpine:edge"
+            entry_cmd = "/bin/ash"
+            "#
+        ))?
+        .args(vec!["--config-path", "[config_path]", "build", "[name]"])?
+        .stderr("Using config file at \"[config_path]\"\n")?
+        .code(0)?;
+    test.run()?;
+
+    let container = ConfigTest::from_file(Path::new(test.config_path()))
+        .get_env(test.name())
+        .unwrap()
+        .name;
+
+    test = test
+        .args(vec!["--config-path", "[config_path]", "diff", "[name]"])?
+        .stdout(format!(
+            "Container {container} matches the current configuration\n"
+        ))?;
+    test.run()?;
+
+    let config_path = test.config_path().to_string();
+    test = test
+        .config_with_path(
+            indoc!(
+                r#"
+                image = "alpine:edge"
+                entry_cmd = "/bin/sh"
+                "#
+            ),
+            Path::new(&config_path),
+        )?
+        .stdout(formatdoc!(
+            r#"
+            Container {container} was created from a different configuration:
+            - entry_cmd = "/bin/ash"
+            + entry_cmd = "/bin/sh"
+            "#
+        ))?;
+    test.run()
+}
@@ -0,0 +1,1016 @@
+use berth::{
+    actions,
+    cli::{Action, OutputFormat},
+    docker::{labels, DockerError, DockerHandler, Drift},
+};
+use bollard::secret::{
+    DeviceRequest, Mount, MountTypeEnum, ResourcesUlimits, RestartPolicy, RestartPolicyNameEnum,
+};
+use indoc::indoc;
+use pretty_assertions::assert_eq;
+use serde_json::json;
+use std::{fs, path::PathBuf, sync::Arc};
+use tempfile::TempDir;
+use test_utils::{ConfigTest, Event, FakeEngine};
+
+pub mod test_utils;
+
+const IMAGE: &str = "alpine:edge";
+
+fn handler(config: &ConfigTest, engine: &Arc<FakeEngine>) -> DockerHandler {
+    let environment = config.get_env("Env").unwrap();
+    DockerHandler::new(environment, engine.clone()).unwrap()
+}
+
+fn exec(container: &str, cmd: &[&str]) -> Event {
+    Event::Exec {
+        container: container.to_string(),
+        cmd: cmd.iter().map(|s| s.to_string()).collect(),
+        user: None,
+        workdir: None,
+    }
+}
+
+fn entered(container: &str, options: &[&str], cmd: &[&str]) -> Event {
+    Event::Entered {
+        container: container.to_string(),
+        options: options.iter().map(|s| s.to_string()).collect(),
+        cmd: cmd.iter().map(|s| s.to_string()).collect(),
+    }
+}
+
+#[tokio::test]
+async fn build_pulls_image_and_sets_up_container() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        exec_cmds = ["apk add helix", "echo done"]
+        exec_options = ["-u", "root"]
+    "#});
+    let engine = Arc::new(FakeEngine::new());
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    actions::run(&docker, &Action::Build, false).await.unwrap();
+
+    let exec_as_root = |cmd: &[&str]| Event::Exec {
+        container: name.clone(),
+        cmd: cmd.iter().map(|s| s.to_string()).collect(),
+        user: Some("root".to_string()),
+        workdir: None,
+    };
+    assert_eq!(
+        engine.events(),
+        vec![
+            Event::Pulled(IMAGE.to_string()),
+            Event::Created(name.clone()),
+            Event::Started(name.clone()),
+            exec_as_root(&["apk", "add", "helix"]),
+            exec_as_root(&["echo", "done"]),
+            Event::Stopped(name.clone()),
+        ]
+    );
+
+    let container = engine.container(&name).unwrap();
+    assert!(!container.running);
+    assert_eq!(container.config.image.as_deref(), Some(IMAGE));
+    assert_eq!(
+        container
+            .labels()
+            .get(labels::ENVIRONMENT)
+            .map(String::as_str),
+        Some("Env")
+    );
+}
+
+#[tokio::test]
+async fn up_creates_then_reuses_container() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash -l"
+        entry_options = ["-it", "-u root"]
+    "#});
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    actions::run(&docker, &Action::Up, false).await.unwrap();
+
+    assert_eq!(
+        engine.events(),
+        vec![
+            Event::Created(name.clone()),
+            Event::Started(name.clone()),
+            entered(&name, &["-it", "-u", "root"], &["/bin/ash", "-l"]),
+            exec(&name, &["ls", "/dev/pts"]),
+            Event::Stopped(name.clone()),
+        ]
+    );
+
+    engine.clear_events();
+    actions::run(&docker, &Action::Up, false).await.unwrap();
+
+    assert_eq!(
+        engine.events(),
+        vec![
+            Event::Started(name.clone()),
+            entered(&name, &["-it", "-u", "root"], &["/bin/ash", "-l"]),
+            exec(&name, &["ls", "/dev/pts"]),
+            Event::Stopped(name.clone()),
+        ]
+    );
+    assert_eq!(engine.containers().len(), 1);
+}
+
+#[tokio::test]
+async fn hooks_run_at_each_stage() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        exec_cmds = ["echo exec"]
+        on_create = ["echo create"]
+        on_start = ["echo start"]
+        on_enter = ["echo enter"]
+        on_stop = ["echo stop"]
+    "#});
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    actions::run(&docker, &Action::Up, false).await.unwrap();
+
+    assert_eq!(
+        engine.events(),
+        vec![
+            Event::Created(name.clone()),
+            Event::Started(name.clone()),
+            exec(&name, &["echo", "exec"]),
+            exec(&name, &["echo", "create"]),
+            exec(&name, &["echo", "start"]),
+            exec(&name, &["echo", "enter"]),
+            entered(&name, &[], &["/bin/ash"]),
+            exec(&name, &["ls", "/dev/pts"]),
+            exec(&name, &["echo", "stop"]),
+            Event::Stopped(name.clone()),
+        ]
+    );
+
+    engine.clear_events();
+    actions::run(&docker, &Action::Up, false).await.unwrap();
+
+    assert_eq!(
+        engine.events(),
+        vec![
+            Event::Started(name.clone()),
+            exec(&name, &["echo", "start"]),
+            exec(&name, &["echo", "enter"]),
+            entered(&name, &[], &["/bin/ash"]),
+            exec(&name, &["ls", "/dev/pts"]),
+            exec(&name, &["echo", "stop"]),
+            Event::Stopped(name.clone()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn up_leaves_container_running_while_others_are_connected() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+    "#});
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE).connected());
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    actions::run(&docker, &Action::Up, false).await.unwrap();
+
+    assert!(!engine.events().contains(&Event::Stopped(name.clone())));
+    assert!(engine.container(&name).unwrap().running);
+}
+
+#[tokio::test]
+async fn up_with_cleanup_removes_container() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+    "#});
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    actions::run(&docker, &Action::Up, true).await.unwrap();
+
+    assert_eq!(engine.events().last(), Some(&Event::Removed(name)));
+    assert!(engine.containers().is_empty());
+}
+
+#[tokio::test]
+async fn failed_setup_command_removes_container() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        exec_cmds = ["apk add missing"]
+    "#});
+    let engine = Arc::new(
+        FakeEngine::new()
+            .with_image(IMAGE)
+            .fail_exec("apk add missing", "ERROR: unable to select packages"),
+    );
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    let error = actions::run(&docker, &Action::Up, false).await.unwrap_err();
+
+    assert!(matches!(
+        error.downcast_ref::<DockerError>(),
+        Some(DockerError::CommandExitCode { cmd, stdout })
+            if cmd == "apk add missing" && stdout == "ERROR: unable to select packages"
+    ));
+    assert_eq!(
+        engine.events()[engine.events().len() - 2..],
+        [Event::Stopped(name.clone()), Event::Removed(name)]
+    );
+    assert!(engine.containers().is_empty());
+}
+
+#[tokio::test]
+async fn failed_setup_removes_container_without_on_stop_hooks() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        exec_cmds = ["apk add missing"]
+        on_stop = ["save-state"]
+    "#});
+    let engine = Arc::new(
+        FakeEngine::new()
+            .with_image(IMAGE)
+            .fail_exec("apk add missing", "ERROR: unable to select packages")
+            .fail_exec("save-state", "no state"),
+    );
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    let error = actions::run(&docker, &Action::Up, false).await.unwrap_err();
+
+    assert!(matches!(
+        error.downcast_ref::<DockerError>(),
+        Some(DockerError::CommandExitCode { cmd, .. }) if cmd == "apk add missing"
+    ));
+    assert!(!engine.events().contains(&exec(&name, &["save-state"])));
+    assert_eq!(
+        engine.events()[engine.events().len() - 2..],
+        [Event::Stopped(name.clone()), Event::Removed(name)]
+    );
+    assert!(engine.containers().is_empty());
+}
+
+#[tokio::test]
+async fn rm_removes_container_when_on_stop_hook_fails() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        on_stop = ["save-state"]
+    "#});
+    let engine = Arc::new(
+        FakeEngine::new()
+            .with_image(IMAGE)
+            .connected()
+            .fail_exec("save-state", "no state"),
+    );
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+    actions::run(&docker, &Action::Up, false).await.unwrap();
+    engine.clear_events();
+
+    let error = actions::run(&docker, &Action::Rm { image: false }, false)
+        .await
+        .unwrap_err();
+
+    assert!(matches!(
+        error.downcast_ref::<DockerError>(),
+        Some(DockerError::CommandExitCode { cmd, .. }) if cmd == "save-state"
+    ));
+    assert_eq!(
+        engine.events(),
+        vec![
+            exec(&name, &["save-state"]),
+            Event::Stopped(name.clone()),
+            Event::Removed(name),
+        ]
+    );
+    assert!(engine.containers().is_empty());
+}
+
+#[tokio::test]
+async fn failed_on_start_hook_keeps_existing_container() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        on_start = ["mount-secrets"]
+    "#});
+    let engine = Arc::new(
+        FakeEngine::new()
+            .with_image(IMAGE)
+            .fail_exec("mount-secrets", "no secrets"),
+    );
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+    engine.add_container(&name, &[], 0);
+
+    let error = actions::run(&docker, &Action::Up, false).await.unwrap_err();
+
+    assert!(matches!(
+        error.downcast_ref::<DockerError>(),
+        Some(DockerError::CommandExitCode { cmd, .. }) if cmd == "mount-secrets"
+    ));
+    assert!(!engine.events().contains(&Event::Removed(name.clone())));
+    assert!(engine.container(&name).is_some());
+}
+
+#[tokio::test]
+async fn failed_on_enter_hook_keeps_existing_container() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        on_enter = ["check-login"]
+    "#});
+    let engine = Arc::new(
+        FakeEngine::new()
+            .with_image(IMAGE)
+            .fail_exec("check-login", "not logged in"),
+    );
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+    engine.add_container(&name, &[], 0);
+
+    let error = actions::run(&docker, &Action::Up, false).await.unwrap_err();
+
+    assert!(matches!(
+        error.downcast_ref::<DockerError>(),
+        Some(DockerError::CommandExitCode { cmd, .. }) if cmd == "check-login"
+    ));
+    assert!(!engine.events().contains(&Event::Removed(name.clone())));
+    assert!(engine.container(&name).is_some());
+}
+
+#[tokio::test]
+async fn copies_files_to_and_from_container() {
+    let tmp_dir = TempDir::new().unwrap();
+    fs::write(tmp_dir.path().join("script.sh"), "echo hello").unwrap();
+    fs::create_dir(tmp_dir.path().join("dir")).unwrap();
+    fs::write(tmp_dir.path().join("dir").join("nested.txt"), "nested").unwrap();
+
+    let config_path = tmp_dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        cp_cmds = [
+            "script.sh CONTAINER:/root/init.sh",
+            "dir CONTAINER:/root/",
+            "CONTAINER:/etc/os-release ./os-release",
+        ]
+    "#},
+    )
+    .unwrap();
+    let config = ConfigTest::from_file(&config_path);
+
+    let engine = Arc::new(
+        FakeEngine::new()
+            .with_image(IMAGE)
+            .with_file("/etc/os-release", "NAME=Alpine"),
+    );
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    actions::run(&docker, &Action::Build, false).await.unwrap();
+
+    let container = engine.container(&name).unwrap();
+    assert_eq!(container.file("/root/init.sh").unwrap(), "echo hello");
+    assert_eq!(container.file("/root/dir/nested.txt").unwrap(), "nested");
+    assert_eq!(
+        fs::read_to_string(tmp_dir.path().join("os-release")).unwrap(),
+        "NAME=Alpine"
+    );
+}
+
+#[tokio::test]
+async fn mounts_are_added_to_container() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        create_options = ["--mount type=volume,target=/data"]
+        mounts = [
+            { source = "/tmp", target = "/host_tmp", readonly = true },
+            { target = "/scratch", type = "tmpfs" },
+        ]
+    "#});
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    actions::run(&docker, &Action::Build, false).await.unwrap();
+
+    let container = engine.container(&name).unwrap();
+    let mounts = container.config.host_config.unwrap().mounts.unwrap();
+    assert_eq!(
+        mounts,
+        vec![
+            Mount {
+                typ: Some(MountTypeEnum::VOLUME),
+                target: Some("/data".to_string()),
+                ..Default::default()
+            },
+            Mount {
+                typ: Some(MountTypeEnum::BIND),
+                source: Some("/tmp".to_string()),
+                target: Some("/host_tmp".to_string()),
+                read_only: Some(true),
+                ..Default::default()
+            },
+            Mount {
+                typ: Some(MountTypeEnum::TMPFS),
+                target: Some("/scratch".to_string()),
+                ..Default::default()
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn resource_options_are_translated() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        create_options = [
+            "--gpus all",
+            "--ulimit nofile=1024:2048",
+            "--restart on-failure:3",
+            "--device-cgroup-rule 'c 189:* rmw'",
+            "--pids-limit 100",
+            "--log-opt max-size=10m",
+        ]
+    "#});
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    actions::run(&docker, &Action::Build, false).await.unwrap();
+
+    let host = engine.container(&name).unwrap().config.host_config.unwrap();
+    assert_eq!(
+        host.device_requests,
+        Some(vec![DeviceRequest {
+            count: Some(-1),
+            capabilities: Some(vec![vec!["gpu".to_string()]]),
+            ..Default::default()
+        }])
+    );
+    assert_eq!(
+        host.ulimits,
+        Some(vec![ResourcesUlimits {
+            name: Some("nofile".to_string()),
+            soft: Some(1024),
+            hard: Some(2048),
+        }])
+    );
+    assert_eq!(
+        host.restart_policy,
+        Some(RestartPolicy {
+            name: Some(RestartPolicyNameEnum::ON_FAILURE),
+            maximum_retry_count: Some(3),
+        })
+    );
+    assert_eq!(
+        host.device_cgroup_rules,
+        Some(vec!["c 189:* rmw".to_string()])
+    );
+    assert_eq!(host.pids_limit, Some(100));
+    assert_eq!(host.log_config.unwrap().config.unwrap()["max-size"], "10m");
+}
+
+#[tokio::test]
+async fn workspace_is_mounted_and_used_as_working_dir() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        exec_cmds = ["cargo build"]
+        workspace = "/workspace"
+    "#});
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    actions::run(&docker, &Action::Up, false).await.unwrap();
+
+    let container = engine.container(&name).unwrap();
+    let mounts = container.config.host_config.unwrap().mounts.unwrap();
+    assert_eq!(
+        mounts,
+        vec![Mount {
+            typ: Some(MountTypeEnum::BIND),
+            source: Some(std::env::current_dir().unwrap().display().to_string()),
+            target: Some("/workspace".to_string()),
+            ..Default::default()
+        }]
+    );
+
+    let events = engine.events();
+    assert!(events.contains(&Event::Exec {
+        container: name.clone(),
+        cmd: vec!["cargo".to_string(), "build".to_string()],
+        user: None,
+        workdir: Some("/workspace".to_string()),
+    }));
+    assert!(events.contains(&entered(&name, &["-w", "/workspace"], &["/bin/ash"])));
+}
+
+#[tokio::test]
+async fn env_is_set_for_container_and_commands() {
+    let tmp_dir = TempDir::new().unwrap();
+    fs::write(tmp_dir.path().join(".env"), "# comment\nFROM_FILE=file\n").unwrap();
+
+    let config_path = tmp_dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        env = { EDITOR = "hx" }
+        env_file = ".env"
+        pass_env = ["HOME"]
+    "#},
+    )
+    .unwrap();
+    let config = ConfigTest::from_file(&config_path);
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    actions::run(&docker, &Action::Up, false).await.unwrap();
+
+    let home = format!("HOME={}", std::env::var("HOME").unwrap());
+    let container = engine.container(&name).unwrap();
+    assert_eq!(
+        container.config.env.unwrap(),
+        vec!["FROM_FILE=file", &home, "EDITOR=hx"]
+    );
+
+    let env_file = tmp_dir.path().join(".env");
+    let options = [
+        "--env-file",
+        env_file.to_str().unwrap(),
+        "-e",
+        "HOME",
+        "-e",
+        "EDITOR=hx",
+    ];
+    assert!(engine
+        .events()
+        .contains(&entered(&name, &options, &["/bin/ash"])));
+}
+
+#[tokio::test]
+async fn host_hooks_run_from_config_dir() {
+    let tmp_dir = TempDir::new().unwrap();
+    let config_path = tmp_dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        cp_cmds = ["token CONTAINER:/root/token"]
+        host_pre_create = ["sh -c 'echo secret > token'", "sh -c 'echo pre_create >> hooks'"]
+        host_post_create = ["sh -c 'echo post_create >> hooks'"]
+        host_post_exit = ["sh -c 'echo post_exit >> hooks'"]
+    "#},
+    )
+    .unwrap();
+    let config = ConfigTest::from_file(&config_path);
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    actions::run(&docker, &Action::Up, false).await.unwrap();
+    actions::run(&docker, &Action::Up, false).await.unwrap();
+
+    let container = engine.container(&name).unwrap();
+    assert_eq!(container.file("/root/token").unwrap(), "secret\n");
+    assert_eq!(
+        fs::read_to_string(tmp_dir.path().join("hooks")).unwrap(),
+        "pre_create\npost_create\npost_exit\npost_exit\n"
+    );
+}
+
+#[tokio::test]
+async fn included_preset_paths_are_relative_to_its_file() {
+    let tmp_dir = TempDir::new().unwrap();
+    let shared_dir = tmp_dir.path().join("shared");
+    fs::create_dir_all(shared_dir.join("cache")).unwrap();
+    fs::write(
+        shared_dir.join("presets.toml"),
+        indoc! {r#"
+        [preset.Shared]
+        cp_cmds = ["token CONTAINER:/root/token"]
+        create_options = ["-v", "./cache:/cache"]
+        host_pre_create = ["sh -c 'echo secret > token'"]
+    "#},
+    )
+    .unwrap();
+    let config_path = tmp_dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        indoc! {r#"
+        include = ["shared/presets.toml"]
+
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        presets = ["Shared"]
+    "#},
+    )
+    .unwrap();
+    let config = ConfigTest::from_file(&config_path);
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    actions::run(&docker, &Action::Build, false).await.unwrap();
+
+    let container = engine.container(&name).unwrap();
+    assert!(shared_dir.join("token").exists());
+    assert_eq!(container.file("/root/token").unwrap(), "secret\n");
+    assert_eq!(
+        container.config.host_config.unwrap().binds.unwrap(),
+        vec![format!("{}/./cache:/cache", shared_dir.display())]
+    );
+}
+
+#[tokio::test]
+async fn failed_host_hook_is_reported_with_stderr() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        host_pre_create = ["sh -c 'echo no credentials >&2; exit 3'"]
+    "#});
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    let docker = handler(&config, &engine);
+
+    let error = actions::run(&docker, &Action::Up, false).await.unwrap_err();
+
+    assert!(matches!(
+        error.downcast_ref::<DockerError>(),
+        Some(DockerError::HostCommandExitCode { cmd, status, stderr })
+            if cmd == "sh -c 'echo no credentials >&2; exit 3'"
+                && status == "code 3"
+                && stderr == "no credentials"
+    ));
+    assert!(engine.containers().is_empty());
+}
+
+#[tokio::test]
+async fn failed_host_post_exit_hook_keeps_container() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        host_post_exit = ["sh -c 'exit 1'"]
+    "#});
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    let error = actions::run(&docker, &Action::Up, false).await.unwrap_err();
+
+    assert!(matches!(
+        error.downcast_ref::<DockerError>(),
+        Some(DockerError::HostCommandExitCode { cmd, .. }) if cmd == "sh -c 'exit 1'"
+    ));
+    assert!(!engine.events().contains(&Event::Removed(name.clone())));
+    assert!(engine.container(&name).is_some());
+
+    engine.clear_events();
+    actions::run(&docker, &Action::Up, false).await.unwrap_err();
+
+    assert!(!engine.events().contains(&Event::Created(name.clone())));
+    assert!(engine.container(&name).is_some());
+}
+
+#[tokio::test]
+async fn dockerfile_is_built_and_removed_with_image() {
+    let tmp_dir = TempDir::new().unwrap();
+    fs::write(tmp_dir.path().join("Dockerfile"), "FROM alpine:edge").unwrap();
+    fs::write(tmp_dir.path().join("included.txt"), "").unwrap();
+    fs::write(tmp_dir.path().join("ignored.txt"), "").unwrap();
+    fs::write(tmp_dir.path().join(".dockerignore"), "ignored.txt").unwrap();
+
+    let config_path = tmp_dir.path().join("config.toml");
+    fs::write(
+        &config_path,
+        indoc! {r#"
+        [environment.Env]
+        dockerfile = "Dockerfile"
+        entry_cmd = "/bin/ash"
+    "#},
+    )
+    .unwrap();
+    let config = ConfigTest::from_file(&config_path);
+
+    let engine = Arc::new(FakeEngine::new());
+    let docker = handler(&config, &engine);
+    let environment = config.get_env("Env").unwrap();
+
+    actions::run(&docker, &Action::Build, false).await.unwrap();
+
+    assert_eq!(
+        engine.events()[0],
+        Event::Built {
+            image: environment.image.clone(),
+            dockerfile: "Dockerfile".to_string(),
+            context: vec![
+                ".dockerignore".to_string(),
+                "Dockerfile".to_string(),
+                "config.toml".to_string(),
+                "included.txt".to_string(),
+            ],
+        }
+    );
+
+    engine.clear_events();
+    actions::run(&docker, &Action::Rm { image: true }, false)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        engine.events(),
+        vec![
+            Event::Removed(environment.name),
+            Event::RemovedImage(environment.image),
+        ]
+    );
+    assert!(engine.images().is_empty());
+}
+
+#[tokio::test]
+async fn changed_configuration_creates_new_container() {
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+
+    let old_config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+    "#});
+    let old_name = old_config.get_env("Env").unwrap().name;
+    actions::run(&handler(&old_config, &engine), &Action::Build, false)
+        .await
+        .unwrap();
+
+    // The same config file with the environment changed
+    fs::write(
+        old_config.file_path(),
+        indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/bash"
+    "#},
+    )
+    .unwrap();
+    let docker = handler(&old_config, &engine);
+    let new_name = old_config.get_env("Env").unwrap().name;
+
+    assert_eq!(
+        docker.drift().await.unwrap(),
+        Drift::Changed {
+            container: old_name.clone(),
+            diff: Some(vec![
+                "- entry_cmd = \"/bin/ash\"".to_string(),
+                "+ entry_cmd = \"/bin/bash\"".to_string(),
+            ]),
+        }
+    );
+
+    engine.clear_events();
+    actions::run(&docker, &Action::Build, false).await.unwrap();
+
+    assert_eq!(engine.events()[0], Event::Created(new_name.clone()));
+    assert!(engine.container(&old_name).is_some());
+    assert_eq!(
+        docker.drift().await.unwrap(),
+        Drift::UpToDate {
+            container: new_name.clone()
+        }
+    );
+
+    let environments = old_config.get_all_envs().unwrap();
+    actions::gc(
+        &environments,
+        &[PathBuf::from(old_config.file_path())],
+        engine.clone(),
+        false,
+        false,
+        None,
+    )
+    .await
+    .unwrap();
+
+    assert!(engine.container(&old_name).is_none());
+    assert!(engine.container(&new_name).is_some());
+}
+
+#[tokio::test]
+async fn containers_of_other_workspaces_are_kept() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+        workspace = "/work"
+    "#});
+    let here = config.get_env("Env").unwrap();
+    let other_dir = TempDir::new().unwrap();
+    let there = here.with_workspace_source(other_dir.path());
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+
+    for environment in [&here, &there] {
+        let docker = DockerHandler::new(environment.clone(), engine.clone()).unwrap();
+        actions::run(&docker, &Action::Up, false).await.unwrap();
+    }
+
+    assert_ne!(here.name, there.name);
+    assert_eq!(
+        engine
+            .container(&there.name)
+            .unwrap()
+            .config
+            .labels
+            .unwrap()[labels::WORKSPACE_SOURCE],
+        other_dir.path().display().to_string()
+    );
+
+    // A third directory has no container rather than a stale one
+    let elsewhere = here.with_workspace_source(&other_dir.path().join("elsewhere"));
+    let docker = DockerHandler::new(elsewhere, engine.clone()).unwrap();
+    assert_eq!(docker.drift().await.unwrap(), Drift::Absent);
+    assert_eq!(
+        serde_json::to_value(docker.status().await.unwrap()).unwrap()["status"],
+        "absent"
+    );
+
+    let config_paths = [PathBuf::from(config.file_path())];
+    let gc = || {
+        actions::gc(
+            std::slice::from_ref(&here),
+            &config_paths,
+            engine.clone(),
+            false,
+            false,
+            None,
+        )
+    };
+
+    gc().await.unwrap();
+    assert!(engine.container(&here.name).is_some());
+    assert!(engine.container(&there.name).is_some());
+
+    // Once the other directory is deleted its container is left behind
+    drop(other_dir);
+    gc().await.unwrap();
+    assert!(engine.container(&here.name).is_some());
+    assert!(engine.container(&there.name).is_none());
+}
+
+#[tokio::test]
+async fn status_as_json() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+    "#});
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    let docker = handler(&config, &engine);
+    let name = config.get_env("Env").unwrap().name;
+
+    let status = serde_json::to_value(docker.status().await.unwrap()).unwrap();
+    assert_eq!(
+        status,
+        json!({
+            "environment": "Env",
+            "container": name,
+            "image": IMAGE,
+            "status": "absent",
+            "created": null,
+        })
+    );
+
+    actions::run(&docker, &Action::Build, false).await.unwrap();
+
+    let status = serde_json::to_value(docker.status().await.unwrap()).unwrap();
+    assert_eq!(status["status"], "stopped");
+    assert!(status["created"].is_i64());
+}
+
+#[tokio::test]
+async fn legacy_container_is_renamed() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+    "#});
+    let environment = config.get_env("Env").unwrap();
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    engine.add_container(&environment.legacy_name(), &[], 0);
+    let docker = handler(&config, &engine);
+
+    actions::run(&docker, &Action::Up, false).await.unwrap();
+
+    let events = engine.events();
+    assert_eq!(
+        events[..2],
+        [
+            Event::Renamed(environment.legacy_name(), environment.name.clone()),
+            Event::Started(environment.name.clone()),
+        ]
+    );
+    assert!(!events.contains(&Event::Created(environment.name)));
+}
+
+#[tokio::test]
+async fn legacy_container_is_only_renamed_when_used() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+    "#});
+    let environment = config.get_env("Env").unwrap();
+    let legacy_name = environment.legacy_name();
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    engine.add_container(&legacy_name, &[], 0);
+    let docker = handler(&config, &engine);
+
+    let status = Action::Status {
+        format: OutputFormat::Json,
+    };
+    for action in [&status, &Action::Diff] {
+        actions::run(&docker, action, false).await.unwrap();
+    }
+
+    assert!(engine.events().is_empty());
+    let status = serde_json::to_value(docker.status().await.unwrap()).unwrap();
+    assert_eq!(status["status"], "stopped");
+    assert_eq!(status["container"], legacy_name.as_str());
+    assert_eq!(
+        docker.drift().await.unwrap(),
+        Drift::UpToDate {
+            container: legacy_name
+        }
+    );
+}
+
+#[tokio::test]
+async fn unlabelled_containers_of_other_configs_are_kept() {
+    let config = ConfigTest::new(indoc! {r#"
+        [environment.Env]
+        image = "alpine:edge"
+        entry_cmd = "/bin/ash"
+    "#});
+    let environment = config.get_env("Env").unwrap();
+    let engine = Arc::new(FakeEngine::new().with_image(IMAGE));
+    engine.add_container(&environment.legacy_name(), &[], 0);
+    engine.add_container("berth-Other-0123456789abcdef", &[], 0);
+
+    let config_paths = [PathBuf::from(config.file_path())];
+    let gc = |all| {
+        actions::gc(
+            std::slice::from_ref(&environment),
+            &config_paths,
+            engine.clone(),
+            false,
+            all,
+            None,
+        )
+    };
+    let names = || -> Vec<String> { engine.containers().into_iter().map(|c| c.name).collect() };
+
+    gc(false).await.unwrap();
+    assert_eq!(names().len(), 2);
+
+    gc(true).await.unwrap();
+    assert_eq!(names(), vec!["berth-Other-0123456789abcdef"]);
+}
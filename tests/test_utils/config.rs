@@ -1,6 +1,7 @@
 use berth::{
-    cli::{Action, AppConfig},
-    configuration::{Configuration, Environment},
+    cli::{Action, AppConfig, OutputFormat},
+    configuration::{Configuration, Environment, Problems},
+    docker::Engine,
 };
 use miette::{GraphicalReportHandler, GraphicalTheme, Result};
 use std::path::PathBuf;
@@ -8,7 +9,7 @@ use std::{io::Write, path::Path};
 use tempfile::NamedTempFile;
 
 pub struct ConfigTest {
-    file_path: PathBuf,
+    file_paths: Vec<PathBuf>,
     _file: Option<NamedTempFile>,
 }
 
@@ -18,31 +19,80 @@ impl ConfigTest {
         write!(&config_file, "{}", config_content).expect("Failed to write config file");
 
         ConfigTest {
-            file_path: config_file.path().to_path_buf(),
+            file_paths: vec![config_file.path().to_path_buf()],
             _file: Some(config_file),
         }
     }
 
     pub fn from_file(config_path: &Path) -> Self {
+        Self::from_files(&[config_path])
+    }
+
+    /// Layers several config files, from lowest to highest priority
+    pub fn from_files(config_paths: &[&Path]) -> Self {
         ConfigTest {
-            file_path: config_path.to_path_buf(),
+            file_paths: config_paths.iter().map(|path| path.to_path_buf()).collect(),
             _file: None,
         }
     }
 
     pub fn get_env(&self, environment: &str) -> Result<Environment> {
         let app_config = AppConfig {
-            config_path: self.file_path.clone(),
+            config_paths: self.file_paths.clone(),
             action: Action::Up,
             cleanup: true,
             environment: environment.to_string(),
+            engine: None,
         };
 
         Configuration::new(&app_config)?.find_environment_from_configuration()
     }
 
+    pub fn get_all_envs(&self) -> Result<Vec<Environment>> {
+        let app_config = AppConfig {
+            config_paths: self.file_paths.clone(),
+            action: Action::List {
+                format: OutputFormat::Text,
+            },
+            cleanup: false,
+            environment: String::new(),
+            engine: None,
+        };
+
+        Configuration::new(&app_config)?.find_all_environments()
+    }
+
+    pub fn check_all_envs(&self) -> Result<(Vec<Environment>, Problems)> {
+        let app_config = AppConfig {
+            config_paths: self.file_paths.clone(),
+            action: Action::Check,
+            cleanup: false,
+            environment: String::new(),
+            engine: None,
+        };
+
+        Configuration::new(&app_config)?.check_all_environments()
+    }
+
+    pub fn get_engine(&self, engine: Option<Engine>) -> Result<Engine> {
+        let app_config = AppConfig {
+            config_paths: self.file_paths.clone(),
+            action: Action::List {
+                format: OutputFormat::Text,
+            },
+            cleanup: false,
+            environment: String::new(),
+            engine,
+        };
+
+        let mut configuration = Configuration::new(&app_config)?;
+        configuration.find_all_environments()?;
+        Ok(configuration.engine())
+    }
+
+    /// The highest priority config file
     pub fn file_path(&self) -> &str {
-        self.file_path.to_str().unwrap()
+        self.file_paths.last().unwrap().to_str().unwrap()
     }
 }
 
@@ -0,0 +1,611 @@
+use async_trait::async_trait;
+use berth::docker::{ContainerEngine, DockerError, ExecOutput};
+use bollard::{
+    container::Config,
+    errors::Error,
+    exec::CreateExecOptions,
+    image::BuildImageOptions,
+    secret::{ContainerSummary, ImageSummary},
+};
+use miette::Result;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    path::{Component, Path},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Something the engine was asked to do, recorded in the order it happened
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Pulled(String),
+    /// Image name, dockerfile and the files in the build context
+    Built {
+        image: String,
+        dockerfile: String,
+        context: Vec<String>,
+    },
+    RemovedImage(String),
+    Created(String),
+    Started(String),
+    Stopped(String),
+    Removed(String),
+    Renamed(String, String),
+    Exec {
+        container: String,
+        cmd: Vec<String>,
+        user: Option<String>,
+        workdir: Option<String>,
+    },
+    Entered {
+        container: String,
+        options: Vec<String>,
+        cmd: Vec<String>,
+    },
+    /// Container, directory the archive was extracted to and the files in it
+    Uploaded {
+        container: String,
+        path: String,
+        files: Vec<String>,
+    },
+    Downloaded {
+        container: String,
+        path: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct FakeContainer {
+    pub id: String,
+    pub name: String,
+    pub config: Config<String>,
+    pub platform: Option<String>,
+    pub running: bool,
+    pub created: i64,
+    /// Paths in the container, with `None` for directories
+    pub files: BTreeMap<String, Option<Vec<u8>>>,
+}
+
+impl FakeContainer {
+    pub fn labels(&self) -> HashMap<String, String> {
+        self.config.labels.clone().unwrap_or_default()
+    }
+
+    pub fn file(&self, path: &str) -> Option<String> {
+        self.files
+            .get(path)
+            .cloned()
+            .flatten()
+            .map(|content| String::from_utf8_lossy(&content).to_string())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FakeImage {
+    pub id: String,
+    pub name: String,
+    pub labels: HashMap<String, String>,
+    pub created: i64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    containers: Vec<FakeContainer>,
+    images: Vec<FakeImage>,
+    events: Vec<Event>,
+    next_id: u64,
+    image_files: BTreeMap<String, Option<Vec<u8>>>,
+    exec_failures: HashMap<String, String>,
+    connected: bool,
+    enter_exit_code: Option<i32>,
+}
+
+impl State {
+    fn id(&mut self) -> String {
+        self.next_id += 1;
+        format!("{:064x}", self.next_id)
+    }
+
+    fn container(&mut self, name: &str) -> Option<&mut FakeContainer> {
+        self.containers
+            .iter_mut()
+            .find(|container| container.name == name || container.id == name)
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+fn not_found(message: &str) -> Error {
+    Error::DockerResponseServerError {
+        status_code: 404,
+        message: message.to_string(),
+    }
+}
+
+fn conflict(message: &str) -> Error {
+    Error::DockerResponseServerError {
+        status_code: 409,
+        message: message.to_string(),
+    }
+}
+
+fn matches_labels(labels: &HashMap<String, String>, filters: &[&str]) -> bool {
+    filters.iter().all(|filter| match filter.split_once('=') {
+        Some((key, value)) => labels.get(key).is_some_and(|v| v == value),
+        None => labels.contains_key(*filter),
+    })
+}
+
+fn matches_reference(name: &str, reference: &str) -> bool {
+    let pattern = glob::Pattern::new(reference).unwrap();
+    let repository = name.strip_suffix(":latest").unwrap_or(name);
+    pattern.matches(name) || pattern.matches(repository)
+}
+
+fn join(directory: &str, path: &Path) -> String {
+    let mut joined = directory.trim_end_matches('/').to_string();
+    for component in path.components() {
+        if let Component::Normal(name) = component {
+            joined.push('/');
+            joined.push_str(&name.to_string_lossy());
+        }
+    }
+    if joined.is_empty() {
+        joined.push('/');
+    }
+    joined
+}
+
+/// A container engine that keeps everything in memory, recording what it
+/// was asked to do so tests can run without a container engine
+#[derive(Debug, Default)]
+pub struct FakeEngine {
+    state: Mutex<State>,
+}
+
+impl FakeEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an image as if it had already been pulled
+    pub fn with_image(self, name: &str) -> Self {
+        {
+            let mut state = self.state.lock().unwrap();
+            let id = state.id();
+            state.images.push(FakeImage {
+                id,
+                name: name.to_string(),
+                labels: HashMap::new(),
+                created: now(),
+            });
+        }
+        self
+    }
+
+    /// Adds a file to every container created from now on
+    pub fn with_file(self, path: &str, content: &str) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .image_files
+            .insert(path.to_string(), Some(content.as_bytes().to_vec()));
+        self
+    }
+
+    /// Makes running `cmd` in a container fail with `output`
+    pub fn fail_exec(self, cmd: &str, output: &str) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .exec_failures
+            .insert(cmd.to_string(), output.to_string());
+        self
+    }
+
+    /// Makes it look like someone else is connected to every container
+    pub fn connected(self) -> Self {
+        self.state.lock().unwrap().connected = true;
+        self
+    }
+
+    /// Exit code of entering a container, `None` for being killed by a signal
+    pub fn enter_exit_code(self, code: Option<i32>) -> Self {
+        self.state.lock().unwrap().enter_exit_code = code;
+        self
+    }
+
+    /// Adds a stopped container, as if created by an earlier run of berth
+    pub fn add_container(&self, name: &str, labels: &[(&str, &str)], created: i64) {
+        let mut state = self.state.lock().unwrap();
+        let id = state.id();
+        let labels = labels
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        state.containers.push(FakeContainer {
+            id,
+            name: name.to_string(),
+            config: Config {
+                labels: Some(labels),
+                ..Default::default()
+            },
+            platform: None,
+            running: false,
+            created,
+            files: BTreeMap::new(),
+        });
+    }
+
+    pub fn events(&self) -> Vec<Event> {
+        self.state.lock().unwrap().events.clone()
+    }
+
+    pub fn clear_events(&self) {
+        self.state.lock().unwrap().events.clear();
+    }
+
+    pub fn containers(&self) -> Vec<FakeContainer> {
+        self.state.lock().unwrap().containers.clone()
+    }
+
+    pub fn container(&self, name: &str) -> Option<FakeContainer> {
+        self.state.lock().unwrap().container(name).cloned()
+    }
+
+    pub fn images(&self) -> Vec<FakeImage> {
+        self.state.lock().unwrap().images.clone()
+    }
+}
+
+#[async_trait]
+impl ContainerEngine for FakeEngine {
+    async fn list_containers(
+        &self,
+        filters: HashMap<&str, Vec<&str>>,
+    ) -> Result<Vec<ContainerSummary>> {
+        let state = self.state.lock().unwrap();
+        let labels = filters.get("label").cloned().unwrap_or_default();
+        let names = filters.get("name").cloned().unwrap_or_default();
+
+        Ok(state
+            .containers
+            .iter()
+            .filter(|container| matches_labels(&container.labels(), &labels))
+            .filter(|container| names.iter().all(|name| container.name.contains(name)))
+            .map(|container| ContainerSummary {
+                id: Some(container.id.clone()),
+                names: Some(vec![format!("/{}", container.name)]),
+                image: container.config.image.clone(),
+                labels: Some(container.labels()),
+                created: Some(container.created),
+                state: Some(
+                    if container.running {
+                        "running"
+                    } else {
+                        "exited"
+                    }
+                    .to_string(),
+                ),
+                ..Default::default()
+            })
+            .collect())
+    }
+
+    async fn list_images(&self, filters: HashMap<&str, Vec<&str>>) -> Result<Vec<ImageSummary>> {
+        let state = self.state.lock().unwrap();
+        let labels = filters.get("label").cloned().unwrap_or_default();
+        let references = filters.get("reference").cloned().unwrap_or_default();
+
+        Ok(state
+            .images
+            .iter()
+            .filter(|image| matches_labels(&image.labels, &labels))
+            .filter(|image| {
+                references
+                    .iter()
+                    .all(|reference| matches_reference(&image.name, reference))
+            })
+            .map(|image| ImageSummary {
+                id: image.id.clone(),
+                repo_tags: vec![image.name.clone()],
+                labels: image.labels.clone(),
+                created: image.created,
+                ..Default::default()
+            })
+            .collect())
+    }
+
+    async fn pull_image(&self, image: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.id();
+        state.images.push(FakeImage {
+            id,
+            name: image.to_string(),
+            labels: HashMap::new(),
+            created: now(),
+        });
+        state.events.push(Event::Pulled(image.to_string()));
+        Ok(())
+    }
+
+    async fn build_image(
+        &self,
+        options: BuildImageOptions<String>,
+        context: Vec<u8>,
+    ) -> Result<()> {
+        let mut context_files = Vec::new();
+        let mut archive = tar::Archive::new(context.as_slice());
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            context_files.push(entry.path().unwrap().display().to_string());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let id = state.id();
+        state.images.retain(|image| image.name != options.t);
+        state.images.push(FakeImage {
+            id,
+            name: options.t.clone(),
+            labels: options.labels.clone(),
+            created: now(),
+        });
+        state.events.push(Event::Built {
+            image: options.t,
+            dockerfile: options.dockerfile,
+            context: context_files,
+        });
+        Ok(())
+    }
+
+    async fn remove_image(&self, image: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let count = state.images.len();
+        state
+            .images
+            .retain(|summary| summary.id != image && summary.name != image);
+        if state.images.len() == count {
+            return Err(DockerError::RemovingImage(not_found(image)).into());
+        }
+        state.events.push(Event::RemovedImage(image.to_string()));
+        Ok(())
+    }
+
+    async fn create_container(
+        &self,
+        name: &str,
+        platform: Option<String>,
+        config: Config<String>,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.container(name).is_some() {
+            return Err(DockerError::CreatingContainer(conflict(name)).into());
+        }
+
+        let id = state.id();
+        let files = state.image_files.clone();
+        state.containers.push(FakeContainer {
+            id,
+            name: name.to_string(),
+            config,
+            platform,
+            running: false,
+            created: now(),
+            files,
+        });
+        state.events.push(Event::Created(name.to_string()));
+        Ok(())
+    }
+
+    async fn start_container(&self, container: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .container(container)
+            .ok_or_else(|| DockerError::StartingContainer(not_found(container)))?
+            .running = true;
+        state.events.push(Event::Started(container.to_string()));
+        Ok(())
+    }
+
+    async fn stop_container(&self, container: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .container(container)
+            .ok_or_else(|| DockerError::StoppingContainer(not_found(container)))?
+            .running = false;
+        state.events.push(Event::Stopped(container.to_string()));
+        Ok(())
+    }
+
+    async fn remove_container(&self, container: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let count = state.containers.len();
+        state
+            .containers
+            .retain(|summary| summary.id != container && summary.name != container);
+        if state.containers.len() == count {
+            return Err(DockerError::RemovingContainer(not_found(container)).into());
+        }
+        state.events.push(Event::Removed(container.to_string()));
+        Ok(())
+    }
+
+    async fn rename_container(&self, container: &str, name: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .container(container)
+            .ok_or_else(|| DockerError::RenamingContainer(not_found(container)))?
+            .name = name.to_string();
+        state
+            .events
+            .push(Event::Renamed(container.to_string(), name.to_string()));
+        Ok(())
+    }
+
+    async fn exec(
+        &self,
+        container: &str,
+        options: CreateExecOptions<String>,
+    ) -> Result<ExecOutput> {
+        let mut state = self.state.lock().unwrap();
+        let running = state
+            .container(container)
+            .ok_or_else(|| DockerError::ExecutingCommand(not_found(container)))?
+            .running;
+        if !running {
+            return Err(DockerError::ExecutingCommand(conflict(container)).into());
+        }
+
+        let cmd = options.cmd.unwrap_or_default();
+        state.events.push(Event::Exec {
+            container: container.to_string(),
+            cmd: cmd.clone(),
+            user: options.user,
+            workdir: options.working_dir,
+        });
+
+        let joined = shell_words::join(&cmd);
+        if let Some(output) = state.exec_failures.get(&joined) {
+            return Ok(ExecOutput {
+                output: output.clone(),
+                exit_code: 1,
+            });
+        }
+
+        // Used to check whether anyone is still connected to the container
+        let output = if joined == "ls /dev/pts" {
+            let connections = if state.connected { "0\n1\n" } else { "0\n" };
+            format!("{connections}ptmx\n")
+        } else {
+            String::new()
+        };
+
+        Ok(ExecOutput {
+            output,
+            exit_code: 0,
+        })
+    }
+
+    async fn enter(
+        &self,
+        container: &str,
+        options: &[String],
+        cmd: &[String],
+    ) -> Result<Option<i32>> {
+        let mut state = self.state.lock().unwrap();
+        state.events.push(Event::Entered {
+            container: container.to_string(),
+            options: options.to_vec(),
+            cmd: cmd.to_vec(),
+        });
+        Ok(Some(state.enter_exit_code.unwrap_or_default()))
+    }
+
+    async fn upload(&self, container: &str, path: &str, archive: Vec<u8>) -> Result<()> {
+        let mut files = Vec::new();
+        let mut tar = tar::Archive::new(archive.as_slice());
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = join(path, &entry.path().unwrap());
+            let content = if entry.header().entry_type().is_dir() {
+                None
+            } else {
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content).unwrap();
+                Some(content)
+            };
+            files.push((name, content));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let fake = state
+            .container(container)
+            .ok_or_else(|| DockerError::CopyingArchive(not_found(container)))?;
+        for (name, content) in &files {
+            fake.files.insert(name.clone(), content.clone());
+        }
+        state.events.push(Event::Uploaded {
+            container: container.to_string(),
+            path: path.to_string(),
+            files: files.into_iter().map(|(name, _)| name).collect(),
+        });
+        Ok(())
+    }
+
+    async fn download(&self, container: &str, path: &str) -> Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let fake = state
+            .container(container)
+            .ok_or_else(|| DockerError::CopyingArchive(not_found(container)))?;
+
+        let path = path.trim_end_matches("/.").trim_end_matches('/');
+        let prefix = format!("{path}/");
+        if !fake
+            .files
+            .keys()
+            .any(|name| name == path || name.starts_with(&prefix))
+        {
+            return Err(DockerError::CopyingArchive(not_found(path)).into());
+        }
+
+        let base = Path::new(path).file_name().unwrap().to_string_lossy();
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, content) in fake.files.range(path.to_string()..) {
+            let Some(relative) = name.strip_prefix(path) else {
+                break;
+            };
+            if !relative.is_empty() && !relative.starts_with('/') {
+                continue;
+            }
+
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            let name = format!("{base}{relative}");
+            match content {
+                Some(content) => {
+                    header.set_size(content.len() as u64);
+                    header.set_cksum();
+                    builder
+                        .append_data(&mut header, name, content.as_slice())
+                        .unwrap();
+                }
+                None => {
+                    header.set_entry_type(tar::EntryType::Directory);
+                    header.set_mode(0o755);
+                    header.set_size(0);
+                    header.set_cksum();
+                    builder
+                        .append_data(&mut header, name, std::io::empty())
+                        .unwrap();
+                }
+            }
+        }
+
+        state.events.push(Event::Downloaded {
+            container: container.to_string(),
+            path: path.to_string(),
+        });
+        Ok(builder.into_inner().unwrap())
+    }
+
+    async fn is_dir(&self, container: &str, path: &str) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        let fake = state
+            .container(container)
+            .ok_or_else(|| DockerError::CopyingArchive(not_found(container)))?;
+
+        let path = path.trim_end_matches('/');
+        Ok(path.is_empty()
+            || fake.files.get(path).is_some_and(Option::is_none)
+            || fake
+                .files
+                .keys()
+                .any(|name| name.starts_with(&format!("{path}/"))))
+    }
+}
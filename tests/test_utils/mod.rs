@@ -1,11 +1,13 @@
 // pub mod test_utils;
 mod base;
 mod config;
+mod engine;
 mod harness;
 mod output;
 mod utils;
 
 pub use config::*;
+pub use engine::*;
 pub use harness::*;
 pub use output::*;
 pub use utils::*;